@@ -1 +1,3 @@
 pub mod plot_bam;
+mod svg;
+mod variants;
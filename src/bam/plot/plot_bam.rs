@@ -1,34 +1,312 @@
+use crate::bam::plot::svg::{render_svg, PileupPlot};
+use crate::bam::plot::variants::VariantTrack;
 use crate::bcf::report::table_report::create_report_table::create_report_data;
 use crate::bcf::report::table_report::create_report_table::manipulate_json;
+use crate::bcf::report::table_report::fasta_reader::get_fasta_lengths;
+use crate::bcf::report::table_report::feature_reader::{FeatureReader, FeatureTrack};
+use crate::bcf::report::table_report::static_reader::{ReadLayout, Variant};
 use crate::common::Region;
-use anyhow::Result;
+use anyhow::{anyhow, Context as AnyhowContext, Result};
+use bio::io::bed;
 use chrono::{DateTime, Local};
 use itertools::Itertools;
+use log::{info, warn};
+use rust_htslib::bam;
+use rust_htslib::bcf::{self, Read};
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::fs;
 use std::io;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use tera::{Context, Tera};
 
+/// Output format of plot-bam.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlotFormat {
+    /// Interactive HTML page with a Vega visualization.
+    Html,
+    /// Static SVG image.
+    Svg,
+}
+
+impl FromStr for PlotFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "html" => Ok(PlotFormat::Html),
+            "svg" => Ok(PlotFormat::Svg),
+            _ => Err(anyhow!("invalid plot format {}", s)),
+        }
+    }
+}
+
+impl PlotFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            PlotFormat::Html => "html",
+            PlotFormat::Svg => "svg",
+        }
+    }
+}
+
+/// A plotted region as listed on the index page of a batch of plots.
+#[derive(Serialize, Debug, Clone)]
+struct PlotEntry {
+    name: String,
+    region: String,
+    file: String,
+}
+
+/// A region of a batch of plots that could not be plotted, with the reason.
+#[derive(Serialize, Debug, Clone)]
+struct SkippedEntry {
+    name: String,
+    region: String,
+    reason: String,
+}
+
+/// Links between the pages of a batch of plots.
+#[derive(Serialize, Debug, Clone)]
+struct Navigation {
+    index: String,
+    previous: Option<String>,
+    next: Option<String>,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn plot_bam<P: AsRef<Path> + std::fmt::Debug>(
     bam_paths: &[P],
     fasta_path: P,
+    vcf_paths: &[PathBuf],
+    gff_path: Option<&Path>,
     region: &Region,
     max_read_depth: u32,
+    layout: &ReadLayout,
+    format: PlotFormat,
 ) -> Result<()> {
-    let mut plots = Vec::new();
+    let bams = bam_names(bam_paths);
+    let mut readers = open_bams(bam_paths)?;
+    let mut tracks = Tracks::open(vcf_paths, gff_path)?;
+    let plot = render_plot(
+        &mut readers,
+        &bams,
+        &fasta_path,
+        &mut tracks,
+        region,
+        max_read_depth,
+        layout,
+        format,
+        None,
+    )?;
+    io::stdout().write_all(plot.as_bytes())?;
 
-    let Region { target, start, end } = region.clone();
-    for bam_path in bam_paths {
-        let content = create_report_data(&fasta_path, None, bam_path, region, max_read_depth)?;
-        let visualization = manipulate_json(content, start, end)?;
+    Ok(())
+}
 
-        plots.push(visualization);
+/// Plot each interval of a BED file or each record of a VCF/BCF file, extended by the given
+/// flank, into the output directory. The plots are linked from `index.html` and the BAM files are
+/// opened only once for all regions. Regions on contigs missing from the reference or any of the
+/// BAM files are skipped and listed as such.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn plot_bam_regions<P: AsRef<Path> + std::fmt::Debug>(
+    bam_paths: &[P],
+    fasta_path: P,
+    vcf_paths: &[PathBuf],
+    gff_path: Option<&Path>,
+    regions_path: &Path,
+    flank: u64,
+    max_read_depth: u32,
+    layout: &ReadLayout,
+    format: PlotFormat,
+    output_path: &Path,
+) -> Result<()> {
+    let contig_lengths = get_fasta_lengths(fasta_path.as_ref())?;
+    let (regions, mut skipped) = read_regions(regions_path, flank, &contig_lengths)?;
+    let plot_path = output_path.join("regions");
+    fs::create_dir_all(&plot_path).with_context(|| {
+        format!(
+            "couldn't create output directory {}, please make sure the path exists",
+            plot_path.display()
+        )
+    })?;
+
+    let bams = bam_names(bam_paths);
+    let mut readers = open_bams(bam_paths)?;
+    let regions = regions
+        .into_iter()
+        .filter(|(name, region)| {
+            let missing = readers
+                .iter()
+                .zip(&bams)
+                .find(|(reader, _)| reader.header().tid(region.target.as_bytes()).is_none());
+            match missing {
+                Some((_, bam)) => {
+                    let reason = format!("contig {} not found in {}", region.target, bam);
+                    warn!("Skipping region {}: {}.", name, reason);
+                    skipped.push(SkippedEntry {
+                        name: name.to_owned(),
+                        region: format!("{}:{}-{}", region.target, region.start, region.end),
+                        reason,
+                    });
+                    false
+                }
+                None => true,
+            }
+        })
+        .collect_vec();
+    let mut tracks = Tracks::open(vcf_paths, gff_path)?;
+    let file_name = |i: usize| format!("{}.{}", i + 1, format.extension());
+    let mut entries = Vec::new();
+    for (i, (name, region)) in regions.iter().enumerate() {
+        let navigation = Navigation {
+            index: "../index.html".to_owned(),
+            previous: if i > 0 { Some(file_name(i - 1)) } else { None },
+            next: if i + 1 < regions.len() {
+                Some(file_name(i + 1))
+            } else {
+                None
+            },
+        };
+        let plot = render_plot(
+            &mut readers,
+            &bams,
+            &fasta_path,
+            &mut tracks,
+            region,
+            max_read_depth,
+            layout,
+            format,
+            Some(&navigation),
+        )?;
+        fs::write(plot_path.join(file_name(i)), plot)?;
+        entries.push(PlotEntry {
+            name: name.to_owned(),
+            region: format!("{}:{}-{}", region.target, region.start, region.end),
+            file: format!("regions/{}", file_name(i)),
+        });
     }
+    info!(
+        "Plotted {} regions, skipped {} regions.",
+        entries.len(),
+        skipped.len()
+    );
+
+    let mut templates = Tera::default();
+    templates.add_raw_template(
+        "bam_plot_index.html.tera",
+        include_str!("bam_plot_index.html.tera"),
+    )?;
+    let mut context = Context::new();
+    let local: DateTime<Local> = Local::now();
+    context.insert("time", &local.format("%a %b %e %T %Y").to_string());
+    context.insert("version", &env!("CARGO_PKG_VERSION"));
+    context.insert("bams", &bams);
+    context.insert("regions", &entries);
+    context.insert("skipped", &skipped);
+    let html = templates.render("bam_plot_index.html.tera", &context)?;
+    fs::write(output_path.join("index.html"), html)?;
+
+    Ok(())
+}
 
-    let bams = bam_paths
+fn bam_names<P: AsRef<Path>>(bam_paths: &[P]) -> Vec<&str> {
+    bam_paths
         .iter()
         .map(|b| b.as_ref().iter().last().unwrap().to_str().unwrap())
-        .collect_vec();
+        .collect_vec()
+}
+
+fn open_bams<P: AsRef<Path> + std::fmt::Debug>(bam_paths: &[P]) -> Result<Vec<bam::IndexedReader>> {
+    bam_paths
+        .iter()
+        .map(|path| {
+            bam::IndexedReader::from_path(path)
+                .with_context(|| format!("error reading indexed BAM file {:?}", path))
+        })
+        .collect()
+}
+
+/// Annotation tracks drawn along with the reads: variants of VCF/BCF files above the reference,
+/// one row above the other, and transcripts of a GFF3/GTF file below it.
+struct Tracks {
+    variants: Vec<VariantTrack>,
+    features: Option<FeatureReader>,
+}
+
+impl Tracks {
+    fn open(vcf_paths: &[PathBuf], gff_path: Option<&Path>) -> Result<Self> {
+        Ok(Tracks {
+            variants: vcf_paths
+                .iter()
+                .enumerate()
+                .map(|(i, path)| VariantTrack::from_path(path, -(i as i8) - 1))
+                .collect::<Result<_>>()?,
+            features: gff_path.map(FeatureReader::from_path).transpose()?,
+        })
+    }
+}
+
+/// Render the plots of all BAM files for the given region into a single HTML page or SVG image.
+#[allow(clippy::too_many_arguments)]
+fn render_plot<P: AsRef<Path> + std::fmt::Debug>(
+    readers: &mut [bam::IndexedReader],
+    bams: &[&str],
+    fasta_path: P,
+    tracks: &mut Tracks,
+    region: &Region,
+    max_read_depth: u32,
+    layout: &ReadLayout,
+    format: PlotFormat,
+    navigation: Option<&Navigation>,
+) -> Result<String> {
+    let Region { target, start, end } = region.clone();
+    let mut overlay: Vec<Variant> = Vec::new();
+    for track in tracks.variants.iter_mut() {
+        overlay.extend(track.variants(region)?);
+    }
+    let features = match tracks.features.as_mut() {
+        Some(reader) => reader.track(region)?,
+        None => FeatureTrack::default(),
+    };
+    if format == PlotFormat::Svg {
+        let mut plots = Vec::new();
+        for (reader, bam) in readers.iter_mut().zip(bams) {
+            let content = create_report_data(
+                &fasta_path,
+                None,
+                &overlay,
+                &features,
+                reader,
+                region,
+                max_read_depth,
+                layout,
+            )?;
+            let data: serde_json::Value = serde_json::from_str(&content.to_string())?;
+            plots.push(PileupPlot::new(bam, &data));
+        }
+        let title = format!("{}:{}-{}", target, start, end);
+        return render_svg(&plots, &title, start, end);
+    }
+
+    let mut plots = Vec::new();
+    for reader in readers.iter_mut() {
+        let content = create_report_data(
+            &fasta_path,
+            None,
+            &overlay,
+            &features,
+            reader,
+            region,
+            max_read_depth,
+            layout,
+        )?;
+        let visualization = manipulate_json(content, start, end)?;
+
+        plots.push(visualization);
+    }
 
     let mut templates = Tera::default();
     templates.add_raw_template("bam_plot.html.tera", include_str!("bam_plot.html.tera"))?;
@@ -41,9 +319,84 @@ pub(crate) fn plot_bam<P: AsRef<Path> + std::fmt::Debug>(
     context.insert("chrom", &target);
     context.insert("start", &start);
     context.insert("end", &end);
+    context.insert("navigation", &navigation);
 
-    let html = templates.render("bam_plot.html.tera", &context)?;
-    io::stdout().write_all(html.as_bytes())?;
+    Ok(templates.render("bam_plot.html.tera", &context)?)
+}
 
-    Ok(())
+/// Read the regions to be plotted from a BED file (by extension) or a VCF/BCF file, named by
+/// the name column of the BED file or the ID or alleles of the VCF record. Regions on contigs
+/// missing from the reference are returned separately.
+fn read_regions(
+    path: &Path,
+    flank: u64,
+    contig_lengths: &HashMap<String, u64>,
+) -> Result<(Vec<(String, Region)>, Vec<SkippedEntry>)> {
+    let mut regions = Vec::new();
+    let mut skipped = Vec::new();
+    let mut add =
+        |name: String, target: &str, start: u64, end: u64| match contig_lengths.get(target) {
+            Some(length) => regions.push((
+                name,
+                Region {
+                    target: target.to_owned(),
+                    start: start.saturating_sub(flank),
+                    end: (end + flank).min(*length),
+                },
+            )),
+            None => {
+                let reason = format!("contig {} not found in reference", target);
+                warn!("Skipping region {}: {}.", name, reason);
+                skipped.push(SkippedEntry {
+                    name,
+                    region: format!("{}:{}-{}", target, start, end),
+                    reason,
+                });
+            }
+        };
+
+    if path.extension().map_or(false, |ext| ext == "bed") {
+        let mut reader = bed::Reader::from_file(path)
+            .with_context(|| format!("error reading BED file {}", path.display()))?;
+        for result in reader.records() {
+            let record = result?;
+            let name = match record.name() {
+                Some(name) if !name.is_empty() && name != "." => name.to_owned(),
+                _ => format!("{}:{}-{}", record.chrom(), record.start(), record.end()),
+            };
+            add(name, record.chrom(), record.start(), record.end());
+        }
+    } else {
+        let mut reader = bcf::Reader::from_path(path)
+            .with_context(|| format!("error reading VCF/BCF file {}", path.display()))?;
+        let header = reader.header().clone();
+        for result in reader.records() {
+            let record = result?;
+            let chrom = String::from_utf8(
+                header
+                    .rid2name(record.rid().context("record without CHROM")?)?
+                    .to_owned(),
+            )?;
+            let pos = record.pos() as u64;
+            let id = String::from_utf8(record.id())?;
+            let name = if id == "." {
+                let alleles = record
+                    .alleles()
+                    .iter()
+                    .map(|a| String::from_utf8_lossy(a).into_owned())
+                    .collect_vec();
+                format!(
+                    "{}:{} {}>{}",
+                    chrom,
+                    pos + 1,
+                    alleles[0],
+                    alleles[1..].join(",")
+                )
+            } else {
+                id
+            };
+            add(name, &chrom, pos, (record.end() as u64).max(pos + 1));
+        }
+    }
+    Ok((regions, skipped))
 }
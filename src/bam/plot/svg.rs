@@ -0,0 +1,507 @@
+//! Rendering of read pileups as standalone SVG, mirroring the Vega specification used for the
+//! HTML output (see `vegaSpecs.json`) without the need for a browser.
+use anyhow::Result;
+use itertools::Itertools;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+const WIDTH: f64 = 700.0;
+const MARGIN: f64 = 10.0;
+const ROW_HEIGHT: f64 = 10.0;
+const AXIS_HEIGHT: f64 = 30.0;
+const TITLE_HEIGHT: f64 = 20.0;
+const LEGEND_HEIGHT: f64 = 30.0;
+const COVERAGE_HEIGHT: f64 = 50.0;
+const FEATURE_COLOR: &str = "#1f3d7a";
+
+/// Colors of bases and markers, as in the `color` scale of the Vega specification.
+fn marker_color(marker_type: &str) -> &'static str {
+    match marker_type {
+        "A" => "#CADB69",
+        "C" => "#F2B671",
+        "G" => "#F28CC2",
+        "T" => "#7284A8",
+        "N" => "#A23E11",
+        "Insertion" => "#047C0A",
+        "Deletion" => "#CC1414",
+        "Match" | "Pairing" => "#BBBBBB",
+        "Inversion" => "#984aff",
+        "Duplicate" => "#ffa600",
+        "SoftClip" => "#a6cee3",
+        "Split" => "#e377c2",
+        _ => "#000000",
+    }
+}
+
+/// Colors of read borders by mapping quality, in bins of 10.
+fn mapq_color(mapq: u64) -> &'static str {
+    const COLORS: [&str; 7] = [
+        "#910000", "#c70002", "#ff0000", "#ff7500", "#ffb200", "#ffe921", "#bbbbbb",
+    ];
+    COLORS[(mapq / 10).min(COLORS.len() as u64 - 1) as usize]
+}
+
+/// Drawing order of markers, such that mismatches and indels are shown on top of matches.
+fn zindex(marker_type: &str) -> u8 {
+    match marker_type {
+        "Match" | "Pairing" | "Split" | "SoftClip" | "Variant" => 1,
+        "Insertion" => 4,
+        _ => 2,
+    }
+}
+
+fn stroke_width(marker_type: &str) -> f64 {
+    match marker_type {
+        "Insertion" => 7.5,
+        "Pairing" | "Split" => 1.0,
+        _ => 5.0,
+    }
+}
+
+/// Tooltip of a variant with its alleles and, if drawn from a VCF file, genotypes.
+fn variant_title(datum: &Value) -> String {
+    let mut title = format!(
+        "{} {}>{}",
+        datum["var_type"].as_str().unwrap_or_default(),
+        datum["reference"].as_str().unwrap_or_default(),
+        datum["alternatives"].as_str().unwrap_or_default()
+    );
+    if let Some(genotypes) = datum["genotypes"].as_str() {
+        title += &format!(" ({})", genotypes);
+    }
+    title
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A horizontal line of the pileup, spanning the given reference positions.
+struct Line {
+    start: f64,
+    end: f64,
+    row: i64,
+    zindex: u8,
+    color: String,
+    width: f64,
+    opacity: f64,
+    title: Option<String>,
+}
+
+/// A segment of the coverage bar of a reference position, see `CoverageBar`.
+struct CoverageSegment {
+    position: f64,
+    coverage_base: String,
+    start: f64,
+    end: f64,
+    depth: u64,
+}
+
+/// A plot of a single BAM file, given the data of `create_report_data`.
+pub(crate) struct PileupPlot {
+    title: String,
+    lines: Vec<Line>,
+    rows: Vec<i64>,
+    /// Labels of read groups, at the first row of each group.
+    groups: Vec<(i64, String)>,
+    /// Strand arrows of transcripts, given by position, row and whether on the reverse strand.
+    arrows: Vec<(f64, i64, bool)>,
+    coverage: Vec<CoverageSegment>,
+}
+
+impl PileupPlot {
+    pub(crate) fn new(title: &str, data: &Value) -> Self {
+        let mut background = Vec::new();
+        let mut markers = Vec::new();
+        let mut coverage = Vec::new();
+        let mut groups: HashMap<String, i64> = HashMap::new();
+        let mut arrows = Vec::new();
+        let empty = vec![];
+        for datum in data.as_array().unwrap_or(&empty) {
+            let marker_type = datum["marker_type"].as_str().unwrap_or_default();
+            if marker_type == "Coverage" {
+                coverage.push(CoverageSegment {
+                    position: datum["position"].as_f64().unwrap_or_default(),
+                    coverage_base: datum["coverage_base"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_owned(),
+                    start: datum["start"].as_f64().unwrap_or_default(),
+                    end: datum["end"].as_f64().unwrap_or_default(),
+                    depth: datum["depth"].as_u64().unwrap_or_default(),
+                });
+                continue;
+            }
+            let row = datum["row"].as_i64().unwrap_or_default();
+            if marker_type == "Feature" {
+                let feature_type = datum["feature_type"].as_str().unwrap_or_default();
+                let start = datum["start_position"].as_f64().unwrap_or_default();
+                let reverse = datum["strand"].as_str() == Some("-");
+                if feature_type == "Arrow" {
+                    arrows.push((start, row, reverse));
+                    continue;
+                }
+                background.push(Line {
+                    start,
+                    end: datum["end_position"].as_f64().unwrap_or_default(),
+                    row,
+                    zindex: 0,
+                    color: FEATURE_COLOR.to_owned(),
+                    width: match feature_type {
+                        "CDS" => 8.0,
+                        "Exon" => 5.0,
+                        _ => 1.0,
+                    },
+                    opacity: 1.0,
+                    title: Some(format!(
+                        "{} {} ({})",
+                        feature_type,
+                        datum["name"].as_str().unwrap_or_default(),
+                        datum["strand"].as_str().unwrap_or_default()
+                    )),
+                });
+                continue;
+            }
+            if let Some(group) = datum["group"].as_str() {
+                let first_row = groups.entry(group.to_owned()).or_insert(row);
+                *first_row = (*first_row).min(row);
+            }
+            let opacity = if datum["repeat"].as_bool().unwrap_or(false) {
+                0.4
+            } else {
+                0.9
+            };
+            if row == 0 && marker_type != "Pairing" && marker_type != "Split" {
+                // reference bases
+                let position = datum["position"].as_f64().unwrap_or_default();
+                markers.push(Line {
+                    start: position - 0.5,
+                    end: position + 0.5,
+                    row,
+                    zindex: zindex(marker_type),
+                    color: marker_color(marker_type).to_owned(),
+                    width: stroke_width(marker_type),
+                    opacity,
+                    title: Some(format!("{} {}", marker_type, position)),
+                });
+                continue;
+            }
+            let start = datum["start_position"].as_f64().unwrap_or_default();
+            let end = datum["end_position"].as_f64().unwrap_or_default();
+            if row < 0 {
+                // variants
+                let var_type = datum["var_type"].as_str().unwrap_or_default();
+                markers.push(Line {
+                    start,
+                    end,
+                    row,
+                    zindex: zindex("Variant"),
+                    color: marker_color(var_type).to_owned(),
+                    width: stroke_width(marker_type),
+                    opacity: 0.8,
+                    title: Some(variant_title(datum)),
+                });
+            } else if marker_type == "Pairing" || marker_type == "Split" {
+                background.push(Line {
+                    start,
+                    end,
+                    row,
+                    zindex: zindex(marker_type),
+                    color: marker_color(marker_type).to_owned(),
+                    width: stroke_width(marker_type),
+                    opacity,
+                    title: datum["supplementary"].as_str().map(|sa| {
+                        format!(
+                            "Split read {}: {}",
+                            datum["name"].as_str().unwrap_or_default(),
+                            sa
+                        )
+                    }),
+                });
+            } else {
+                background.push(Line {
+                    start: start - 0.2,
+                    end: end + 0.2,
+                    row,
+                    zindex: 0,
+                    color: mapq_color(datum["mapq"].as_u64().unwrap_or_default()).to_owned(),
+                    width: if marker_type == "Insertion" { 9.0 } else { 6.5 },
+                    opacity: 1.0,
+                    title: None,
+                });
+                let title = match marker_type {
+                    "Insertion" => format!(
+                        "Insertion {} in {}",
+                        datum["bases"].as_str().unwrap_or_default(),
+                        datum["name"].as_str().unwrap_or_default()
+                    ),
+                    _ => {
+                        let mut title = format!(
+                            "{} in {} (MAPQ {}, CIGAR {})",
+                            marker_type,
+                            datum["name"].as_str().unwrap_or_default(),
+                            datum["mapq"],
+                            datum["cigar"].as_str().unwrap_or_default()
+                        );
+                        if let Some(discordance) = datum["discordance"].as_str() {
+                            title += &format!(", discordant pair: {}", discordance);
+                        }
+                        if let Some(sa) = datum["supplementary"].as_str() {
+                            title += &format!(", split segments: {}", sa);
+                        }
+                        title
+                    }
+                };
+                let color = match datum["read_color"].as_str() {
+                    Some(color) if marker_type == "Match" => color,
+                    _ => marker_color(marker_type),
+                };
+                markers.push(Line {
+                    start,
+                    end,
+                    row,
+                    zindex: zindex(marker_type),
+                    color: color.to_owned(),
+                    width: stroke_width(marker_type),
+                    opacity,
+                    title: Some(title),
+                });
+            }
+        }
+        // stable sort keeps the order of the data within the same layer
+        markers.sort_by_key(|line| line.zindex);
+        let rows = background
+            .iter()
+            .chain(&markers)
+            .map(|line| line.row)
+            .chain(arrows.iter().map(|(_, row, _)| *row))
+            .sorted()
+            .dedup()
+            .collect();
+        background.extend(markers);
+        PileupPlot {
+            title: title.to_owned(),
+            lines: background,
+            rows,
+            groups: groups
+                .into_iter()
+                .map(|(group, row)| (row, group))
+                .sorted()
+                .collect(),
+            arrows,
+            coverage,
+        }
+    }
+
+    fn height(&self) -> f64 {
+        TITLE_HEIGHT
+            + AXIS_HEIGHT
+            + COVERAGE_HEIGHT
+            + ROW_HEIGHT * self.rows.len().max(1) as f64
+            + MARGIN
+    }
+
+    fn render(&self, svg: &mut String, offset: f64, from: u64, to: u64) -> Result<()> {
+        let span = to.saturating_sub(from).max(1) as f64;
+        let x = |position: f64| MARGIN + (position - from as f64) / span * WIDTH;
+        let axis = offset + TITLE_HEIGHT + AXIS_HEIGHT - 5.0;
+        let top = axis + 5.0 + COVERAGE_HEIGHT;
+        let bottom = top + ROW_HEIGHT * self.rows.len().max(1) as f64;
+
+        writeln!(
+            svg,
+            r#"<text x="{}" y="{}" font-weight="bold">{}</text>"#,
+            MARGIN,
+            offset + TITLE_HEIGHT - 5.0,
+            escape(&self.title)
+        )?;
+        // axis with grid, ticks roughly every 40 pixels
+        let step = tick_step(span / (WIDTH / 40.0).ceil());
+        let mut tick = (from as f64 / step).ceil() * step;
+        while tick <= to as f64 {
+            writeln!(
+                svg,
+                r##"<line x1="{0:.2}" y1="{1:.2}" x2="{0:.2}" y2="{2:.2}" stroke="#dddddd" stroke-width="0.5"/>"##,
+                x(tick),
+                axis,
+                bottom
+            )?;
+            writeln!(
+                svg,
+                r#"<text x="{:.2}" y="{:.2}" font-size="10" text-anchor="middle">{}</text>"#,
+                x(tick),
+                axis - 3.0,
+                tick
+            )?;
+            tick += step;
+        }
+        writeln!(
+            svg,
+            r##"<line x1="{}" y1="{:.2}" x2="{}" y2="{:.2}" stroke="#888888" stroke-width="1"/>"##,
+            MARGIN,
+            axis,
+            MARGIN + WIDTH,
+            axis
+        )?;
+
+        // coverage track, with bars scaled to the maximum depth
+        let max_depth = self.coverage.iter().map(|c| c.depth).max().unwrap_or(0);
+        if max_depth > 0 {
+            let scale = (COVERAGE_HEIGHT - 10.0) / max_depth as f64;
+            let baseline = top - 5.0;
+            writeln!(
+                svg,
+                r#"<text x="{:.2}" y="{:.2}" font-size="10">{}</text>"#,
+                MARGIN + 2.0,
+                axis + 14.0,
+                max_depth
+            )?;
+            writeln!(svg, r#"<g clip-path="url(#clip)">"#)?;
+            for segment in &self.coverage {
+                writeln!(
+                    svg,
+                    r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}" fill="{}"><title>{} {}: {} of {}</title></rect>"#,
+                    x(segment.position - 0.5),
+                    baseline - segment.end * scale,
+                    WIDTH / span,
+                    (segment.end - segment.start) * scale,
+                    marker_color(&segment.coverage_base),
+                    segment.position,
+                    escape(&segment.coverage_base),
+                    segment.end - segment.start,
+                    segment.depth
+                )?;
+            }
+            writeln!(svg, "</g>")?;
+        }
+
+        writeln!(
+            svg,
+            r#"<g clip-path="url(#clip)" transform="translate(0,{:.2})">"#,
+            top
+        )?;
+        for line in &self.lines {
+            let y = match self.rows.binary_search(&line.row) {
+                Ok(i) => (i as f64 + 0.5) * ROW_HEIGHT,
+                Err(_) => continue,
+            };
+            write!(
+                svg,
+                r#"<line x1="{:.2}" y1="{:.2}" x2="{:.2}" y2="{:.2}" stroke="{}" stroke-width="{}" stroke-opacity="{}">"#,
+                x(line.start),
+                y,
+                x(line.end),
+                y,
+                line.color,
+                line.width,
+                line.opacity
+            )?;
+            if let Some(title) = &line.title {
+                write!(svg, "<title>{}</title>", escape(title))?;
+            }
+            writeln!(svg, "</line>")?;
+        }
+        for (position, row, reverse) in &self.arrows {
+            if let Ok(i) = self.rows.binary_search(row) {
+                let y = (i as f64 + 0.5) * ROW_HEIGHT;
+                let tip = if *reverse { -2.0 } else { 2.0 };
+                writeln!(
+                    svg,
+                    r#"<path d="M{:.2},{:.2} L{:.2},{:.2} L{:.2},{:.2}" fill="none" stroke="{}" stroke-width="1"/>"#,
+                    x(*position) - tip,
+                    y - 3.0,
+                    x(*position) + tip,
+                    y,
+                    x(*position) - tip,
+                    y + 3.0,
+                    FEATURE_COLOR
+                )?;
+            }
+        }
+        for (row, group) in &self.groups {
+            if let Ok(i) = self.rows.binary_search(row) {
+                writeln!(
+                    svg,
+                    r##"<text x="{:.2}" y="{:.2}" font-size="9" fill="#444444">{}</text>"##,
+                    MARGIN + 2.0,
+                    (i + 1) as f64 * ROW_HEIGHT - 1.0,
+                    escape(group)
+                )?;
+            }
+        }
+        writeln!(svg, "</g>")?;
+        Ok(())
+    }
+}
+
+/// Round the given step between axis ticks up to 1, 2 or 5 times a power of ten.
+fn tick_step(raw: f64) -> f64 {
+    let magnitude = 10f64.powf(raw.max(1.0).log10().floor());
+    [1.0, 2.0, 5.0, 10.0]
+        .iter()
+        .map(|f| f * magnitude)
+        .find(|step| *step >= raw)
+        .unwrap_or(10.0 * magnitude)
+}
+
+/// Render the given plots of the region `from..to` stacked into a single SVG document.
+pub(crate) fn render_svg(plots: &[PileupPlot], region: &str, from: u64, to: u64) -> Result<String> {
+    let height = plots.iter().map(|p| p.height()).sum::<f64>() + TITLE_HEIGHT + LEGEND_HEIGHT;
+    let width = WIDTH + 2.0 * MARGIN;
+    let mut svg = String::new();
+    writeln!(svg, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" version="1.1" width="{0}" height="{1}" viewBox="0 0 {0} {1}" font-family="sans-serif" font-size="12">"#,
+        width, height
+    )?;
+    writeln!(
+        svg,
+        r#"<defs><clipPath id="clip"><rect x="{}" y="0" width="{}" height="100%"/></clipPath></defs>"#,
+        MARGIN, WIDTH
+    )?;
+    writeln!(
+        svg,
+        r#"<rect width="100%" height="100%" fill="white"/><text x="{}" y="{}" font-size="14" font-weight="bold">{}</text>"#,
+        MARGIN,
+        TITLE_HEIGHT - 5.0,
+        escape(region)
+    )?;
+    let mut offset = TITLE_HEIGHT;
+    for plot in plots {
+        plot.render(&mut svg, offset, from, to)?;
+        offset += plot.height();
+    }
+    // legend of base and marker colors
+    let mut legend_x = MARGIN;
+    for marker_type in &[
+        "A",
+        "C",
+        "G",
+        "T",
+        "N",
+        "Insertion",
+        "Deletion",
+        "Match",
+        "SoftClip",
+        "Split",
+    ] {
+        writeln!(
+            svg,
+            r#"<rect x="{:.2}" y="{:.2}" width="10" height="10" fill="{}"/><text x="{:.2}" y="{:.2}" font-size="10">{}</text>"#,
+            legend_x,
+            offset + 10.0,
+            marker_color(marker_type),
+            legend_x + 14.0,
+            offset + 19.0,
+            marker_type
+        )?;
+        legend_x += 24.0 + 6.0 * marker_type.len() as f64;
+    }
+    writeln!(svg, "</svg>")?;
+    Ok(svg)
+}
@@ -0,0 +1,108 @@
+//! Records of VCF/BCF files drawn as variant tracks above the reads of plot-bam.
+use crate::bcf::report::table_report::create_report_table::make_variant;
+use crate::bcf::report::table_report::static_reader::Variant;
+use crate::common::Region;
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use rust_htslib::bcf::{self, header::HeaderView, Read};
+use std::path::Path;
+
+/// Records fetched via the index of a VCF/BCF file, or all records of an unindexed file kept in
+/// memory together with their contig, start and end.
+enum Records {
+    Indexed(bcf::IndexedReader),
+    Loaded(Vec<(String, i64, i64, Vec<Variant>)>),
+}
+
+/// A VCF/BCF file drawn as a track in the given (negative) row.
+pub(crate) struct VariantTrack {
+    records: Records,
+    row: i8,
+}
+
+impl VariantTrack {
+    pub(crate) fn from_path(path: &Path, row: i8) -> Result<Self> {
+        let records = match bcf::IndexedReader::from_path(path) {
+            Ok(reader) => Records::Indexed(reader),
+            Err(_) => {
+                let mut reader = bcf::Reader::from_path(path)
+                    .with_context(|| format!("error reading VCF/BCF file {}", path.display()))?;
+                let header = reader.header().clone();
+                let mut records = Vec::new();
+                for result in reader.records() {
+                    let record = result?;
+                    let chrom = String::from_utf8(
+                        header
+                            .rid2name(record.rid().context("record without CHROM")?)?
+                            .to_owned(),
+                    )?;
+                    let variants = record_variants(&record, &header, row)?;
+                    records.push((chrom, record.pos(), record.end(), variants));
+                }
+                Records::Loaded(records)
+            }
+        };
+        Ok(VariantTrack { records, row })
+    }
+
+    /// The variants of all records overlapping the given region.
+    pub(crate) fn variants(&mut self, region: &Region) -> Result<Vec<Variant>> {
+        match &mut self.records {
+            Records::Indexed(reader) => {
+                let header = reader.header().clone();
+                let rid = match header.name2rid(region.target.as_bytes()) {
+                    Ok(rid) => rid,
+                    // contig not present in this file
+                    Err(_) => return Ok(Vec::new()),
+                };
+                reader.fetch(rid, region.start, Some(region.end))?;
+                let mut variants = Vec::new();
+                for result in reader.records() {
+                    variants.extend(record_variants(&result?, &header, self.row)?);
+                }
+                Ok(variants)
+            }
+            Records::Loaded(records) => Ok(records
+                .iter()
+                .filter(|(chrom, start, end, _)| {
+                    *chrom == region.target
+                        && *start < region.end as i64
+                        && *end > region.start as i64
+                })
+                .flat_map(|(_, _, _, variants)| variants.iter().cloned())
+                .collect()),
+        }
+    }
+}
+
+/// The plotted variants of all alternative alleles of the record, with the genotypes of all
+/// samples.
+fn record_variants(record: &bcf::Record, header: &HeaderView, row: i8) -> Result<Vec<Variant>> {
+    let alleles = record.alleles();
+    if alleles.is_empty() {
+        return Ok(Vec::new());
+    }
+    let ref_allele = String::from_utf8(alleles[0].to_owned())?;
+    // end of symbolic alleles, as the END tag in vcf-report
+    let end_pos = Some(record.end() as f64 - 0.5);
+    let genotypes = match record.genotypes() {
+        Ok(genotypes) if header.sample_count() > 0 => Some(
+            header
+                .samples()
+                .iter()
+                .enumerate()
+                .map(|(i, sample)| {
+                    format!("{}: {}", String::from_utf8_lossy(sample), genotypes.get(i))
+                })
+                .join(", "),
+        ),
+        _ => None,
+    };
+    Ok(alleles[1..]
+        .iter()
+        .map(|alt| Variant {
+            genotypes: genotypes.clone(),
+            ..make_variant(record.pos(), &ref_allele, alt, end_pos, row)
+        })
+        .collect())
+}
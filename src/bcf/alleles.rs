@@ -0,0 +1,344 @@
+//! Rewrite the alleles of BCF records together with their per-allele fields.
+//!
+//! New alleles are described by their origin, i.e. the index of the original allele each new
+//! allele stems from. INFO and FORMAT fields with `Number=A`, `Number=R` or `Number=G` as well as
+//! genotypes are rewritten accordingly. Genotype alleles without a new counterpart are set to
+//! the reference allele. An original allele split into several new ones leaves their
+//! `Number=A`, `Number=R` and `Number=G` values as well as genotype alleles pointing to it
+//! missing, as e.g. its allele depth or a call of it cannot be attributed to any of them.
+use anyhow::Result;
+use itertools::Itertools;
+use rust_htslib::bcf;
+use rust_htslib::bcf::header::{HeaderRecord, HeaderView, TagLength, TagType};
+use rust_htslib::bcf::record::Numeric;
+
+const VECTOR_END_INTEGER: i32 = i32::MIN + 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FieldKind {
+    Info,
+    Format,
+}
+
+#[derive(Debug, Clone)]
+struct AlleleField {
+    kind: FieldKind,
+    tag: Vec<u8>,
+    tag_type: TagType,
+    tag_length: TagLength,
+}
+
+/// Values of a field, per sample for FORMAT fields and a single entry for INFO fields.
+enum Values {
+    Integer(Vec<Vec<i32>>),
+    Float(Vec<Vec<f32>>),
+    String(Vec<Vec<Vec<u8>>>),
+}
+
+/// Per-allele INFO and FORMAT fields defined in a header.
+#[derive(Debug, Clone)]
+pub struct AlleleFields {
+    fields: Vec<AlleleField>,
+}
+
+impl AlleleFields {
+    pub fn new(header: &HeaderView) -> Self {
+        let fields = header
+            .header_records()
+            .into_iter()
+            .filter_map(|record| {
+                let (kind, values) = match record {
+                    HeaderRecord::Info { values, .. } => (FieldKind::Info, values),
+                    HeaderRecord::Format { values, .. } => (FieldKind::Format, values),
+                    _ => return None,
+                };
+                let tag = values.get("ID")?.as_bytes().to_vec();
+                let (tag_type, tag_length) = match kind {
+                    FieldKind::Info => header.info_type(&tag),
+                    FieldKind::Format => header.format_type(&tag),
+                }
+                .ok()?;
+                match tag_length {
+                    TagLength::AltAlleles | TagLength::Alleles | TagLength::Genotypes => {
+                        Some(AlleleField {
+                            kind,
+                            tag,
+                            tag_type,
+                            tag_length,
+                        })
+                    }
+                    _ => None,
+                }
+            })
+            .collect_vec();
+        AlleleFields { fields }
+    }
+
+    /// Set the given alleles, with `origin[i]` being the index of the original allele that
+    /// the new allele `i` stems from, and rewrite per-allele fields and genotypes accordingly.
+    pub fn set_alleles(
+        &self,
+        record: &mut bcf::Record,
+        alleles: &[&[u8]],
+        origin: &[usize],
+    ) -> Result<()> {
+        let old_allele_count = record.allele_count() as usize;
+
+        let values = self
+            .fields
+            .iter()
+            .map(|field| read_values(record, field))
+            .collect_vec();
+        let genotypes = if record.header().format_type(b"GT").is_ok() {
+            record
+                .format(b"GT")
+                .integer()
+                .ok()
+                .map(|gts| gts.iter().map(|gt| gt.to_vec()).collect_vec())
+        } else {
+            None
+        };
+
+        record.set_alleles(alleles)?;
+
+        for (field, values) in self.fields.iter().zip(values) {
+            let values = match values {
+                Some(values) => values,
+                None => continue,
+            };
+            match (values, field.kind) {
+                (Values::Integer(values), FieldKind::Info) => record.push_info_integer(
+                    &field.tag,
+                    &remap_values(
+                        &values[0],
+                        &field.tag_length,
+                        origin,
+                        old_allele_count,
+                        i32::missing(),
+                    )
+                    .unwrap_or_default(),
+                )?,
+                (Values::Float(values), FieldKind::Info) => record.push_info_float(
+                    &field.tag,
+                    &remap_values(
+                        &values[0],
+                        &field.tag_length,
+                        origin,
+                        old_allele_count,
+                        f32::missing(),
+                    )
+                    .unwrap_or_default(),
+                )?,
+                (Values::String(values), FieldKind::Info) => {
+                    let remapped = remap_values(
+                        &values[0],
+                        &field.tag_length,
+                        origin,
+                        old_allele_count,
+                        b".".to_vec(),
+                    )
+                    .unwrap_or_default()
+                    .join(&b',');
+                    record.push_info_string(&field.tag, &[remapped.as_slice()])?
+                }
+                (Values::Integer(values), FieldKind::Format) => {
+                    let remapped = pad(
+                        values
+                            .iter()
+                            .map(|v| {
+                                remap_values(
+                                    v,
+                                    &field.tag_length,
+                                    origin,
+                                    old_allele_count,
+                                    i32::missing(),
+                                )
+                            })
+                            .collect_vec(),
+                        i32::missing(),
+                        VECTOR_END_INTEGER,
+                    );
+                    record.push_format_integer(&field.tag, &remapped)?
+                }
+                (Values::Float(values), FieldKind::Format) => {
+                    let remapped = pad(
+                        values
+                            .iter()
+                            .map(|v| {
+                                remap_values(
+                                    v,
+                                    &field.tag_length,
+                                    origin,
+                                    old_allele_count,
+                                    f32::missing(),
+                                )
+                            })
+                            .collect_vec(),
+                        f32::missing(),
+                        f32::missing(),
+                    );
+                    record.push_format_float(&field.tag, &remapped)?
+                }
+                (Values::String(values), FieldKind::Format) => {
+                    let remapped = values
+                        .iter()
+                        .map(|v| {
+                            remap_values(
+                                v,
+                                &field.tag_length,
+                                origin,
+                                old_allele_count,
+                                b".".to_vec(),
+                            )
+                            .map_or_else(|| b".".to_vec(), |v| v.join(&b','))
+                        })
+                        .collect_vec();
+                    record.push_format_string(
+                        &field.tag,
+                        &remapped.iter().map(|v| v.as_slice()).collect_vec(),
+                    )?
+                }
+            }
+        }
+
+        if let Some(genotypes) = genotypes {
+            let new_index = |old: usize| origin.iter().position(|o| *o == old).unwrap_or(0) as i32;
+            let remapped = genotypes
+                .into_iter()
+                .flatten()
+                .map(|gt| {
+                    // GT values are encoded as (allele + 1) << 1 | phased, with 0 being missing
+                    if gt == VECTOR_END_INTEGER || gt >> 1 == 0 {
+                        gt
+                    } else {
+                        let old = ((gt >> 1) - 1) as usize;
+                        if is_split(origin, old) {
+                            // missing allele, keeping the phasing
+                            gt & 1
+                        } else {
+                            ((new_index(old) + 1) << 1) | (gt & 1)
+                        }
+                    }
+                })
+                .collect_vec();
+            record.push_format_integer(b"GT", &remapped)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn read_values(record: &mut bcf::Record, field: &AlleleField) -> Option<Values> {
+    let split = |value: &[u8]| {
+        value
+            .split(|c| *c == b',')
+            .map(|v| v.to_vec())
+            .collect_vec()
+    };
+    match (field.kind, &field.tag_type) {
+        (FieldKind::Info, TagType::Integer) => record
+            .info(&field.tag)
+            .integer()
+            .ok()?
+            .map(|v| Values::Integer(vec![v.to_vec()])),
+        (FieldKind::Info, TagType::Float) => record
+            .info(&field.tag)
+            .float()
+            .ok()?
+            .map(|v| Values::Float(vec![v.to_vec()])),
+        (FieldKind::Info, TagType::String) => record
+            .info(&field.tag)
+            .string()
+            .ok()?
+            .map(|v| Values::String(vec![v.iter().flat_map(|v| split(v)).collect_vec()])),
+        (FieldKind::Format, TagType::Integer) => record
+            .format(&field.tag)
+            .integer()
+            .ok()
+            .map(|v| Values::Integer(v.iter().map(|v| v.to_vec()).collect_vec())),
+        (FieldKind::Format, TagType::Float) => record
+            .format(&field.tag)
+            .float()
+            .ok()
+            .map(|v| Values::Float(v.iter().map(|v| v.to_vec()).collect_vec())),
+        (FieldKind::Format, TagType::String) => record
+            .format(&field.tag)
+            .string()
+            .ok()
+            .map(|v| Values::String(v.iter().map(|v| split(v)).collect_vec())),
+        _ => None,
+    }
+}
+
+/// Remap the values of a single sample (or of an INFO field) to the new alleles.
+/// Returns `None` if the number of values does not fit the original alleles.
+fn remap_values<T: Clone>(
+    values: &[T],
+    tag_length: &TagLength,
+    origin: &[usize],
+    old_allele_count: usize,
+    missing: T,
+) -> Option<Vec<T>> {
+    let per_allele = |o: usize, index: Option<usize>| {
+        let value = values.get(index?).cloned()?;
+        if is_split(origin, o) {
+            Some(missing.clone())
+        } else {
+            Some(value)
+        }
+    };
+    match tag_length {
+        TagLength::AltAlleles => origin[1..]
+            .iter()
+            .map(|o| per_allele(*o, o.checked_sub(1)))
+            .collect(),
+        TagLength::Alleles => origin.iter().map(|o| per_allele(*o, Some(*o))).collect(),
+        TagLength::Genotypes => {
+            if values.len() == old_allele_count {
+                // haploid
+                origin.iter().map(|o| per_allele(*o, Some(*o))).collect()
+            } else {
+                // diploid, with genotype (j, k), j <= k at index k * (k + 1) / 2 + j
+                let index = |j: usize, k: usize| {
+                    let (j, k) = if j <= k { (j, k) } else { (k, j) };
+                    k * (k + 1) / 2 + j
+                };
+                (0..origin.len())
+                    .flat_map(|k| (0..=k).map(move |j| (j, k)))
+                    .map(|(j, k)| {
+                        let value = values.get(index(origin[j], origin[k])).cloned()?;
+                        if is_split(origin, origin[j]) || is_split(origin, origin[k]) {
+                            Some(missing.clone())
+                        } else {
+                            Some(value)
+                        }
+                    })
+                    .collect()
+            }
+        }
+        _ => Some(values.to_vec()),
+    }
+}
+
+/// Whether the original allele was split into several new ones.
+fn is_split(origin: &[usize], old: usize) -> bool {
+    origin.iter().filter(|o| **o == old).count() > 1
+}
+
+/// Flatten per-sample values, replacing unavailable samples by a missing value and padding
+/// shorter vectors.
+fn pad<T: Clone>(values: Vec<Option<Vec<T>>>, missing: T, vector_end: T) -> Vec<T> {
+    let width = values
+        .iter()
+        .map(|v| v.as_ref().map_or(1, |v| v.len()))
+        .max()
+        .unwrap_or(1);
+    values
+        .into_iter()
+        .flat_map(|v| {
+            let mut v = v.unwrap_or_else(|| vec![missing.clone()]);
+            v.resize(width, vector_end.clone());
+            v
+        })
+        .collect_vec()
+}
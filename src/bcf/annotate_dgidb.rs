@@ -1,12 +1,17 @@
+use crate::bcf::genes::{extract_genes, GeneField, GeneSource};
 use anyhow::Result;
 use itertools::Itertools;
+use log::warn;
 use regex::Regex;
 use rust_htslib::bcf;
-use rust_htslib::bcf::{Format, Read};
+use rust_htslib::bcf::Read;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
-use std::str;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Dgidb {
@@ -29,28 +34,113 @@ struct Interaction {
     interaction_types: Vec<String>,
 }
 
+/// Source of drug-gene interactions.
+#[derive(Debug, Clone)]
+pub enum InteractionSource {
+    /// The DGIdb REST API.
+    Api(ApiOptions),
+    /// A local TSV file with one interaction per row, e.g. the DGIdb `interactions.tsv` dump.
+    Local {
+        path: PathBuf,
+        columns: InteractionColumns,
+    },
+}
+
+/// Options for requesting the DGIdb API.
+#[derive(Debug, Clone)]
+pub struct ApiOptions {
+    /// Url prefix to which chunks of comma-separated genes are appended.
+    pub api_path: String,
+    pub genes_per_request: usize,
+    /// Directory in which responses are cached per gene.
+    pub cache_dir: Option<PathBuf>,
+    /// Number of retries of a failed request, with exponentially increasing delays of at most
+    /// five minutes.
+    pub retries: u32,
+    /// Delay before the first retry.
+    pub retry_delay: Duration,
+    pub max_requests_per_second: Option<f64>,
+}
+
+/// Column names of a local interaction TSV file.
+#[derive(Debug, Clone)]
+pub struct InteractionColumns {
+    pub gene: String,
+    pub drug: String,
+    pub interaction_types: String,
+    pub source: String,
+}
+
+/// Annotate drug-gene interactions. Genes are collected in a first pass over the file, such that
+/// only their interactions are requested from the API or kept from a local interaction file.
+/// Requesting the API requires a path, while with a local interaction file records can also be
+/// streamed from STDIN in a single pass, keeping the interactions of all genes.
 pub fn annotate_dgidb<P: AsRef<Path>, T: AsRef<str>>(
-    vcf_path: P,
-    api_path: String,
+    vcf_path: Option<P>,
+    interaction_source: InteractionSource,
+    gene_field: &GeneField,
     field_name: &str,
     datasources: Option<&[T]>,
-    genes_per_request: usize,
+    output: Option<P>,
+    output_type: &str,
 ) -> Result<()> {
     let datasources = datasources.map(|d| d.iter().map(|s| s.as_ref()).collect());
-    let gene_drug_interactions =
-        request_interaction_drugs(vcf_path.as_ref(), api_path, datasources, genes_per_request)?;
-    modify_vcf_entries(vcf_path.as_ref(), gene_drug_interactions, field_name)
+    let reader = match &vcf_path {
+        Some(path) => bcf::Reader::from_path(path)?,
+        None => bcf::Reader::from_stdin()?,
+    };
+    let gene_source = GeneSource::new(reader.header(), gene_field)?;
+    let gene_drug_interactions = match interaction_source {
+        InteractionSource::Api(options) => {
+            let vcf_path = vcf_path.ok_or(DgidbError::ApiRequiresPath)?;
+            let genes = collect_genes(vcf_path.as_ref(), &gene_source)?;
+            if genes.is_empty() {
+                None
+            } else {
+                Some(request_interaction_drugs(genes, &options, datasources)?)
+            }
+        }
+        InteractionSource::Local { path, columns } => {
+            let genes = vcf_path
+                .as_ref()
+                .map(|vcf_path| collect_genes(vcf_path.as_ref(), &gene_source))
+                .transpose()?;
+            Some(read_interaction_drugs(
+                &path,
+                &columns,
+                datasources,
+                genes.as_ref(),
+            )?)
+        }
+    };
+    modify_vcf_entries(
+        reader,
+        gene_drug_interactions,
+        &gene_source,
+        field_name,
+        output,
+        output_type,
+    )
 }
 
 type Interactions = HashMap<String, Vec<(String, Vec<String>)>>;
 
-fn request_interaction_drugs<P: AsRef<Path>>(
-    vcf_path: P,
-    api_path: String,
+fn request_interaction_drugs(
+    genes: HashSet<String>,
+    options: &ApiOptions,
     datasources_opt: Option<Vec<&str>>,
-    genes_per_request: usize,
-) -> Result<Option<Interactions>> {
-    let mut genes = collect_genes(vcf_path)?;
+) -> Result<Interactions> {
+    // responses depend on the selected data sources, hence they are cached separately
+    let cache_dir = options.cache_dir.as_ref().map(|dir| {
+        dir.join(
+            datasources_opt
+                .as_ref()
+                .map_or_else(|| "all".to_owned(), |entries| entries.join("+")),
+        )
+    });
+    if let Some(cache_dir) = &cache_dir {
+        fs::create_dir_all(cache_dir)?;
+    }
     let datasources = if let Some(entries) = datasources_opt {
         let mut b = String::from("&interaction_sources=");
         b.push_str(entries.join(",").as_str());
@@ -58,72 +148,222 @@ fn request_interaction_drugs<P: AsRef<Path>>(
     } else {
         String::new()
     };
-    if genes.is_empty() {
-        return Ok(None);
-    }
+
     let mut gene_drug_interactions: HashMap<String, Vec<(String, Vec<String>)>> = HashMap::new();
-    for gene_slice in genes.drain().collect_vec().chunks(genes_per_request) {
-        let mut slice_api_path = api_path.clone();
+    let mut uncached_genes = Vec::new();
+    for gene in genes.into_iter().sorted() {
+        match cache_dir
+            .as_ref()
+            .map(|dir| cache_path(dir, &gene))
+            .filter(|path| path.exists())
+        {
+            Some(path) => {
+                let interactions: Vec<Interaction> =
+                    serde_json::from_reader(fs::File::open(path)?)?;
+                insert_interactions(&mut gene_drug_interactions, gene, interactions);
+            }
+            None => uncached_genes.push(gene),
+        }
+    }
+
+    let mut rate_limiter = RateLimiter::new(options.max_requests_per_second);
+    for gene_slice in uncached_genes.chunks(options.genes_per_request) {
+        let mut slice_api_path = options.api_path.clone();
         slice_api_path.push_str(gene_slice.join(",").as_str());
         slice_api_path.push_str(datasources.as_str());
-        let res: Dgidb = reqwest::get(&slice_api_path)?.json()?;
-
-        for term in res.matched_terms {
-            if !term.interactions.is_empty() {
-                gene_drug_interactions.insert(
-                    term.gene_name,
-                    term.interactions
-                        .iter()
-                        .map(|interaction| {
-                            (
-                                interaction.drug_name.clone(),
-                                interaction.interaction_types.clone(),
-                            )
-                        })
-                        .collect(),
-                );
+        let res = request_with_retries(&slice_api_path, options, &mut rate_limiter)?;
+
+        let mut matched_terms: HashMap<_, _> = res
+            .matched_terms
+            .into_iter()
+            .map(|term| (term.gene_name, term.interactions))
+            .collect();
+        for gene in gene_slice {
+            // genes without matches are cached as well, in order to not request them again
+            let interactions = matched_terms.remove(gene).unwrap_or_default();
+            if let Some(cache_dir) = &cache_dir {
+                serde_json::to_writer(
+                    fs::File::create(cache_path(cache_dir, gene))?,
+                    &interactions,
+                )?;
             }
+            insert_interactions(&mut gene_drug_interactions, gene.to_owned(), interactions);
+        }
+        for (gene, interactions) in matched_terms {
+            insert_interactions(&mut gene_drug_interactions, gene, interactions);
         }
     }
-    Ok(Some(gene_drug_interactions))
+    Ok(gene_drug_interactions)
 }
 
-fn collect_genes<P: AsRef<Path>>(vcf_path: P) -> Result<HashSet<String>> {
-    let mut total_genes = HashSet::new();
-    let mut reader = bcf::Reader::from_path(vcf_path)?;
+fn insert_interactions(
+    gene_drug_interactions: &mut Interactions,
+    gene: String,
+    interactions: Vec<Interaction>,
+) {
+    if !interactions.is_empty() {
+        gene_drug_interactions.insert(
+            gene,
+            interactions
+                .into_iter()
+                .map(|interaction| (interaction.drug_name, interaction.interaction_types))
+                .collect(),
+        );
+    }
+}
+
+fn cache_path(cache_dir: &Path, gene: &str) -> PathBuf {
+    cache_dir.join(format!("{}.json", gene.replace('/', "_")))
+}
+
+/// Upper bound of the delay between retries of a failed request.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Request the given url, retrying failed requests with exponential backoff of at most
+/// [`MAX_RETRY_DELAY`].
+fn request_with_retries(
+    url: &str,
+    options: &ApiOptions,
+    rate_limiter: &mut RateLimiter,
+) -> Result<Dgidb> {
+    let mut attempt = 0;
+    loop {
+        rate_limiter.wait();
+        let result = reqwest::get(url)
+            .and_then(|response| response.error_for_status())
+            .and_then(|mut response| response.json());
+        match result {
+            Ok(res) => return Ok(res),
+            Err(e) if attempt < options.retries => {
+                let delay = 2u32
+                    .checked_pow(attempt)
+                    .and_then(|factor| options.retry_delay.checked_mul(factor))
+                    .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY));
+                warn!("DGIdb request failed ({}), retrying in {:?}.", e, delay);
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Enforces a minimum interval between consecutive requests.
+struct RateLimiter {
+    min_interval: Option<Duration>,
+    last_request: Option<Instant>,
+}
+
+impl RateLimiter {
+    fn new(max_requests_per_second: Option<f64>) -> Self {
+        RateLimiter {
+            min_interval: max_requests_per_second
+                .filter(|rate| *rate > 0.0)
+                .map(|rate| Duration::from_secs_f64(1.0 / rate)),
+            last_request: None,
+        }
+    }
+
+    fn wait(&mut self) {
+        if let (Some(min_interval), Some(last_request)) = (self.min_interval, self.last_request) {
+            let elapsed = last_request.elapsed();
+            if elapsed < min_interval {
+                thread::sleep(min_interval - elapsed);
+            }
+        }
+        self.last_request = Some(Instant::now());
+    }
+}
+
+/// Read interactions of the given genes (or all genes) from a local TSV file. Rows of the same gene and drug
+/// are merged into a single interaction, mirroring the aggregated results of the DGIdb API.
+fn read_interaction_drugs(
+    path: &Path,
+    columns: &InteractionColumns,
+    datasources_opt: Option<Vec<&str>>,
+    genes: Option<&HashSet<String>>,
+) -> Result<Interactions> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .flexible(true)
+        .from_path(path)?;
+    let header = reader.headers()?.clone();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|h| h == name)
+            .ok_or_else(|| DgidbError::MissingColumn {
+                column: name.to_owned(),
+                path: path.display().to_string(),
+            })
+    };
+    let gene_idx = column(&columns.gene)?;
+    let drug_idx = column(&columns.drug)?;
+    let types_idx = column(&columns.interaction_types)?;
+    let source_idx = datasources_opt
+        .as_ref()
+        .map(|_| column(&columns.source))
+        .transpose()?;
+
+    let mut gene_drug_interactions: Interactions = HashMap::new();
     for result in reader.records() {
-        let mut rec = result?;
-        let genes_opt = extract_genes(&mut rec)?;
-        if let Some(genes) = genes_opt {
-            for gene in genes {
-                total_genes.insert(gene);
+        let row = result?;
+        let gene = row.get(gene_idx).unwrap_or_default();
+        let drug = row.get(drug_idx).unwrap_or_default();
+        if genes.map_or(false, |genes| !genes.contains(gene)) || drug.is_empty() {
+            continue;
+        }
+        if let (Some(datasources), Some(source_idx)) = (&datasources_opt, source_idx) {
+            if !datasources.contains(&row.get(source_idx).unwrap_or_default()) {
+                continue;
+            }
+        }
+        let drugs = gene_drug_interactions
+            .entry(gene.to_owned())
+            .or_insert_with(Vec::new);
+        let (_, interaction_types) = match drugs.iter().position(|(d, _)| d == drug) {
+            Some(i) => &mut drugs[i],
+            None => {
+                drugs.push((drug.to_owned(), Vec::new()));
+                drugs.last_mut().unwrap()
+            }
+        };
+        for interaction_type in row
+            .get(types_idx)
+            .unwrap_or_default()
+            .split(',')
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+        {
+            if !interaction_types.iter().any(|t| t == interaction_type) {
+                interaction_types.push(interaction_type.to_owned());
             }
         }
     }
-    Ok(total_genes)
+    Ok(gene_drug_interactions)
 }
 
-fn extract_genes(rec: &mut bcf::Record) -> Result<Option<impl Iterator<Item = String> + '_>> {
-    let annotation = rec.info(b"ANN").string()?;
-    match annotation {
-        Some(transcripts) => Ok(Some(transcripts.clone().into_iter().map(|transcript| {
-            str::from_utf8(transcript.split(|c| *c == b'|').nth(3).unwrap())
-                .unwrap()
-                .to_owned()
-        }))),
-        None => Ok(None),
+fn collect_genes<P: AsRef<Path>>(vcf_path: P, gene_source: &GeneSource) -> Result<HashSet<String>> {
+    let mut total_genes = HashSet::new();
+    let mut reader = bcf::Reader::from_path(vcf_path)?;
+    for result in reader.records() {
+        let mut rec = result?;
+        total_genes.extend(extract_genes(&mut rec, gene_source)?);
     }
+    Ok(total_genes)
 }
 
 fn modify_vcf_entries<P: AsRef<Path>>(
-    vcf_path: P,
+    mut reader: bcf::Reader,
     gene_drug_interactions_opt: Option<Interactions>,
+    gene_source: &GeneSource,
     field_name: &str,
+    output: Option<P>,
+    output_type: &str,
 ) -> Result<()> {
-    let mut reader = bcf::Reader::from_path(vcf_path)?;
     let mut header = bcf::header::Header::from_template(reader.header());
     header.push_record(format!("##INFO=<ID={},Number=.,Type=String,Description=\"Combination of gene, drug, interaction types extracted from dgiDB. Each combination is pipe-seperated annotated as GENE|DRUG|TYPE\">", field_name).as_bytes());
-    let mut writer = bcf::Writer::from_stdout(&header, true, Format::Bcf)?;
+    let mut writer = crate::bcf::create_writer(&header, output, output_type)?;
     match gene_drug_interactions_opt {
         None => {
             for result in reader.records() {
@@ -136,8 +376,8 @@ fn modify_vcf_entries<P: AsRef<Path>>(
             for result in reader.records() {
                 let mut rec = result?;
                 writer.translate(&mut rec);
-                let genes = extract_genes(&mut rec)?.map(|genes| genes.collect_vec());
-                if let Some(mut genes) = genes {
+                let mut genes = extract_genes(&mut rec, gene_source)?;
+                if !genes.is_empty() {
                     genes.sort();
                     genes.dedup();
                     let field_entries = build_dgidb_field(&gene_drug_interactions, genes);
@@ -191,3 +431,11 @@ fn build_dgidb_field(
     }
     field_entries
 }
+
+#[derive(Error, Debug)]
+pub enum DgidbError {
+    #[error("reading from STDIN requires a local interaction file (--interactions), since genes have to be collected before requesting the DGIdb API")]
+    ApiRequiresPath,
+    #[error("missing column {column} in interaction file {path}")]
+    MissingColumn { column: String, path: String },
+}
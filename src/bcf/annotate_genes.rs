@@ -0,0 +1,293 @@
+//! Annotate the genes of VCF/BCF records with columns of a local TSV table keyed by gene.
+//!
+//! Genes are taken from functional annotations (e.g. ANN from SnpEff or CSQ from VEP) or from a
+//! plain INFO tag and looked up in the key column of the table, which may contain gene symbols or
+//! Ensembl gene IDs. Each mapped column becomes an INFO field, holding one value per matching
+//! table row (in order of gene name). Empty cells and `.` are written as missing values.
+//!
+//! ## Usage:
+//! ```bash
+//! $ rbt vcf-annotate-genes --table cancer_gene_census.tsv --key-column "Gene Symbol" \
+//!     --fields Tier=CGC_TIER:Integer "Role in Cancer=CGC_ROLE" Hallmark=CGC_HALLMARK:Flag \
+//!     -- input.vcf > output.bcf
+//! ```
+//!
+use crate::bcf::genes::{extract_genes, GeneField, GeneSource};
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+use log::info;
+use rust_htslib::bcf::record::Numeric;
+use rust_htslib::bcf::{self, Read};
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Type of an INFO field filled from a table column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    String,
+    Integer,
+    Float,
+    Flag,
+}
+
+impl FromStr for FieldType {
+    type Err = GeneTableError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "string" => Ok(FieldType::String),
+            "integer" => Ok(FieldType::Integer),
+            "float" => Ok(FieldType::Float),
+            "flag" => Ok(FieldType::Flag),
+            _ => Err(GeneTableError::InvalidFieldType {
+                field_type: s.to_owned(),
+            }),
+        }
+    }
+}
+
+impl FieldType {
+    fn header_definition(self) -> (&'static str, &'static str) {
+        match self {
+            FieldType::String => (".", "String"),
+            FieldType::Integer => (".", "Integer"),
+            FieldType::Float => (".", "Float"),
+            FieldType::Flag => ("0", "Flag"),
+        }
+    }
+}
+
+/// Mapping of a table column onto an INFO field, given as `COLUMN=TAG[:TYPE]`. If the tag is
+/// omitted, the column name is used. The type defaults to `String`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldMapping {
+    pub column: String,
+    pub tag: String,
+    pub field_type: FieldType,
+}
+
+impl FromStr for FieldMapping {
+    type Err = GeneTableError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (column, target) = match s.rfind('=') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, s),
+        };
+        let (tag, field_type) = match target.rfind(':') {
+            Some(i) => (&target[..i], target[i + 1..].parse()?),
+            None => (target, FieldType::String),
+        };
+        let valid_tag = tag
+            .chars()
+            .enumerate()
+            .all(|(i, c)| c.is_ascii_alphanumeric() || c == '_' || (i > 0 && c == '.'));
+        if column.is_empty() || tag.is_empty() || !valid_tag {
+            return Err(GeneTableError::InvalidFieldMapping {
+                mapping: s.to_owned(),
+            });
+        }
+        Ok(FieldMapping {
+            column: column.to_owned(),
+            tag: tag.to_owned(),
+            field_type,
+        })
+    }
+}
+
+/// Values of the mapped columns for each row, by gene.
+type GeneTable = HashMap<String, Vec<Vec<String>>>;
+
+pub fn annotate_genes<P: AsRef<Path>>(
+    vcf_path: Option<P>,
+    table_path: P,
+    key_column: &str,
+    fields: &[FieldMapping],
+    gene_field: &GeneField,
+    output: Option<P>,
+    output_type: &str,
+) -> Result<()> {
+    if fields.is_empty() {
+        bail!(GeneTableError::NoFields);
+    }
+    let table = read_gene_table(table_path.as_ref(), key_column, fields)?;
+
+    let mut reader = match vcf_path {
+        Some(path) => bcf::Reader::from_path(path)?,
+        None => bcf::Reader::from_stdin()?,
+    };
+    let gene_source = GeneSource::new(reader.header(), gene_field)?;
+    let table_name = table_path.as_ref().file_name().map_or_else(
+        || table_path.as_ref().display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    );
+    let mut header = bcf::Header::from_template(reader.header());
+    for field in fields {
+        let (number, field_type) = field.field_type.header_definition();
+        header.push_record(
+            format!(
+                "##INFO=<ID={},Number={},Type={},Description=\"Column {} of {}, one value per matching table row\">",
+                field.tag,
+                number,
+                field_type,
+                field.column.replace('"', "'"),
+                table_name
+            )
+            .as_bytes(),
+        );
+    }
+    let mut writer = crate::bcf::create_writer(&header, output, output_type)?;
+
+    let mut annotated = 0;
+    for result in reader.records() {
+        let mut rec = result?;
+        writer.translate(&mut rec);
+        let rows = extract_genes(&mut rec, &gene_source)?
+            .into_iter()
+            .sorted()
+            .dedup()
+            .filter_map(|gene| table.get(&gene))
+            .flatten()
+            .collect_vec();
+        if !rows.is_empty() {
+            annotated += 1;
+            for (i, field) in fields.iter().enumerate() {
+                let values = rows.iter().map(|row| row[i].as_str()).collect_vec();
+                push_values(&mut rec, field, &values)?;
+            }
+        }
+        writer.write(&rec)?;
+    }
+    info!(
+        "Annotated {} records with genes found in the table.",
+        annotated
+    );
+
+    Ok(())
+}
+
+/// Read the mapped columns of a TSV table, grouping rows by the value of the key column.
+fn read_gene_table(path: &Path, key_column: &str, fields: &[FieldMapping]) -> Result<GeneTable> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .flexible(true)
+        .from_path(path)
+        .with_context(|| format!("error reading gene table {}", path.display()))?;
+    let header = reader.headers()?.clone();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|h| h == name)
+            .ok_or_else(|| GeneTableError::MissingColumn {
+                column: name.to_owned(),
+                path: path.display().to_string(),
+            })
+    };
+    let key_idx = column(key_column)?;
+    let field_idx = fields
+        .iter()
+        .map(|field| column(&field.column))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut table = GeneTable::new();
+    for result in reader.records() {
+        let row = result?;
+        let gene = row.get(key_idx).unwrap_or_default().trim();
+        if gene.is_empty() {
+            continue;
+        }
+        let values = field_idx
+            .iter()
+            .map(|i| row.get(*i).unwrap_or_default().trim().to_owned())
+            .collect_vec();
+        table
+            .entry(gene.to_owned())
+            .or_insert_with(Vec::new)
+            .push(values);
+    }
+    Ok(table)
+}
+
+fn is_missing(value: &str) -> bool {
+    value.is_empty() || value == "."
+}
+
+fn push_values(rec: &mut bcf::Record, field: &FieldMapping, values: &[&str]) -> Result<()> {
+    let tag = field.tag.as_bytes();
+    let invalid = |value: &str| GeneTableError::InvalidValue {
+        value: value.to_owned(),
+        column: field.column.clone(),
+        field_type: field.field_type,
+    };
+    match field.field_type {
+        FieldType::String => {
+            // commas and semicolons would break the INFO column
+            let values = values
+                .iter()
+                .map(|v| {
+                    if is_missing(v) {
+                        ".".to_owned()
+                    } else {
+                        v.replace(|c| c == ',' || c == ';', "|").replace(' ', "_")
+                    }
+                })
+                .collect_vec();
+            rec.push_info_string(tag, &values.iter().map(|v| v.as_bytes()).collect_vec())?;
+        }
+        FieldType::Integer => {
+            let values = values
+                .iter()
+                .map(|v| {
+                    if is_missing(v) {
+                        Ok(i32::missing())
+                    } else {
+                        v.parse().map_err(|_| invalid(v))
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            rec.push_info_integer(tag, &values)?;
+        }
+        FieldType::Float => {
+            let values = values
+                .iter()
+                .map(|v| {
+                    if is_missing(v) {
+                        Ok(f32::missing())
+                    } else {
+                        v.parse().map_err(|_| invalid(v))
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            rec.push_info_float(tag, &values)?;
+        }
+        FieldType::Flag => {
+            let set = values.iter().any(|v| {
+                !is_missing(v) && !["0", "false", "no"].contains(&v.to_ascii_lowercase().as_str())
+            });
+            if set {
+                rec.push_info_flag(tag)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum GeneTableError {
+    #[error("no fields to annotate, please specify at least one column mapping with --fields")]
+    NoFields,
+    #[error("invalid field mapping {mapping}, expected COLUMN=TAG[:TYPE] with TAG being a valid INFO tag")]
+    InvalidFieldMapping { mapping: String },
+    #[error("invalid field type {field_type}, expected one of String, Integer, Float or Flag")]
+    InvalidFieldType { field_type: String },
+    #[error("missing column {column} in gene table {path}")]
+    MissingColumn { column: String, path: String },
+    #[error("invalid value {value} in column {column} for field type {field_type:?}")]
+    InvalidValue {
+        value: String,
+        column: String,
+        field_type: FieldType,
+    },
+}
@@ -0,0 +1,251 @@
+//! Annotate VCF/BCF records with the names or scores of overlapping BED intervals.
+//!
+//! Each BED file is mapped onto its own INFO tag, e.g. for target regions, repeat masks,
+//! blacklist regions or exon numbers. Intervals are kept in an interval tree per contig and a
+//! record overlaps an interval if any of its reference bases (up to `END` for structural
+//! variants) lies within it. Names and scores are annotated once per overlapping interval, such
+//! that equal values of different intervals are kept, and a score of `.` is annotated as missing.
+//!
+//! ## Usage:
+//! ```bash
+//! $ rbt vcf-annotate-intervals input.vcf --bed targets.bed=TARGET exons.bed=EXON \
+//!     blacklist.bed=BLACKLIST:flag > output.bcf
+//! ```
+//!
+use anyhow::{Context, Result};
+use bio::data_structures::interval_tree::IntervalTree;
+use bio::io::bed;
+use itertools::Itertools;
+use log::info;
+use rust_htslib::bcf::record::Numeric;
+use rust_htslib::bcf::{self, Read};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::{self, FromStr};
+use thiserror::Error;
+
+/// Column of a BED file to be annotated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntervalValue {
+    /// The name column, annotated as a string.
+    Name,
+    /// The score column, annotated as a float.
+    Score,
+    /// Only whether there is an overlapping interval.
+    Flag,
+}
+
+impl FromStr for IntervalValue {
+    type Err = IntervalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "name" => Ok(IntervalValue::Name),
+            "score" => Ok(IntervalValue::Score),
+            "flag" => Ok(IntervalValue::Flag),
+            _ => Err(IntervalError::InvalidValue {
+                value: s.to_owned(),
+            }),
+        }
+    }
+}
+
+/// A BED file mapped onto an INFO tag, given as `BED=TAG[:VALUE]` with VALUE being one of
+/// `name` (default), `score` or `flag`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BedAnnotation {
+    pub path: PathBuf,
+    pub tag: String,
+    pub value: IntervalValue,
+}
+
+impl FromStr for BedAnnotation {
+    type Err = IntervalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || IntervalError::InvalidMapping {
+            mapping: s.to_owned(),
+        };
+        let i = s.rfind('=').ok_or_else(invalid)?;
+        let (path, target) = (&s[..i], &s[i + 1..]);
+        let (tag, value) = match target.rfind(':') {
+            Some(i) => (&target[..i], target[i + 1..].parse()?),
+            None => (target, IntervalValue::Name),
+        };
+        let valid_tag = tag
+            .chars()
+            .enumerate()
+            .all(|(i, c)| c.is_ascii_alphanumeric() || c == '_' || (i > 0 && c == '.'));
+        if path.is_empty() || tag.is_empty() || !valid_tag {
+            return Err(invalid());
+        }
+        Ok(BedAnnotation {
+            path: PathBuf::from(path),
+            tag: tag.to_owned(),
+            value,
+        })
+    }
+}
+
+/// Score of BED intervals without a score.
+const MISSING_SCORE: &str = ".";
+
+/// Intervals of a BED file per contig, with the value to be annotated.
+type Intervals = HashMap<String, IntervalTree<u64, String>>;
+
+pub fn annotate_intervals<P: AsRef<Path>>(
+    vcf_path: Option<P>,
+    annotations: &[BedAnnotation],
+    output: Option<P>,
+    output_type: &str,
+) -> Result<()> {
+    let intervals = annotations
+        .iter()
+        .map(read_intervals)
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut reader = match vcf_path {
+        Some(path) => bcf::Reader::from_path(path)?,
+        None => bcf::Reader::from_stdin()?,
+    };
+    let mut header = bcf::Header::from_template(reader.header());
+    for annotation in annotations {
+        let (number, tag_type, description) = match annotation.value {
+            IntervalValue::Name => (".", "String", "Names of overlapping intervals"),
+            IntervalValue::Score => (".", "Float", "Scores of overlapping intervals"),
+            IntervalValue::Flag => ("0", "Flag", "Overlap with an interval"),
+        };
+        header.push_record(
+            format!(
+                "##INFO=<ID={},Number={},Type={},Description=\"{} in {}\">",
+                annotation.tag,
+                number,
+                tag_type,
+                description,
+                annotation.path.display()
+            )
+            .as_bytes(),
+        );
+    }
+    let mut writer = crate::bcf::create_writer(&header, output, output_type)?;
+
+    let mut annotated = vec![0; annotations.len()];
+    for result in reader.records() {
+        let mut rec = result?;
+        writer.translate(&mut rec);
+        let chrom = str::from_utf8(
+            writer
+                .header()
+                .rid2name(rec.rid().context("record without CHROM")?)?,
+        )?
+        .to_owned();
+        let start = rec.pos() as u64;
+        let end = (rec.end() as u64).max(start + 1);
+
+        for ((annotation, intervals), count) in
+            annotations.iter().zip(&intervals).zip(annotated.iter_mut())
+        {
+            let tree = match intervals.get(&chrom) {
+                Some(tree) => tree,
+                None => continue,
+            };
+            let values = tree
+                .find(start..end)
+                .sorted_by_key(|entry| (entry.interval().start, entry.interval().end))
+                .map(|entry| entry.data().as_str())
+                .collect_vec();
+            if values.is_empty() {
+                continue;
+            }
+            *count += 1;
+            let tag = annotation.tag.as_bytes();
+            match annotation.value {
+                IntervalValue::Name => {
+                    rec.push_info_string(tag, &values.iter().map(|v| v.as_bytes()).collect_vec())?
+                }
+                IntervalValue::Score => rec.push_info_float(
+                    tag,
+                    &values
+                        .iter()
+                        .map(|v| match *v {
+                            MISSING_SCORE => Ok(f32::missing()),
+                            v => v.parse::<f32>(),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?,
+                )?,
+                IntervalValue::Flag => rec.push_info_flag(tag)?,
+            }
+        }
+        writer.write(&rec)?;
+    }
+    for (annotation, count) in annotations.iter().zip(annotated) {
+        info!(
+            "Annotated {} records with intervals of {}.",
+            count,
+            annotation.path.display()
+        );
+    }
+
+    Ok(())
+}
+
+fn read_intervals(annotation: &BedAnnotation) -> Result<Intervals> {
+    let path = &annotation.path;
+    let mut reader = bed::Reader::from_file(path)
+        .with_context(|| format!("error reading BED file {}", path.display()))?;
+    let mut intervals = Intervals::new();
+    for (i, result) in reader.records().enumerate() {
+        let record = result?;
+        let value = match annotation.value {
+            IntervalValue::Name => record.name(),
+            IntervalValue::Score => record.score(),
+            IntervalValue::Flag => Some(""),
+        }
+        .ok_or_else(|| IntervalError::MissingColumn {
+            path: path.display().to_string(),
+            line: i + 1,
+            value: annotation.value,
+        })?;
+        if annotation.value == IntervalValue::Score
+            && value != MISSING_SCORE
+            && value.parse::<f32>().is_err()
+        {
+            return Err(IntervalError::InvalidScore {
+                score: value.to_owned(),
+                path: path.display().to_string(),
+                line: i + 1,
+            }
+            .into());
+        }
+        // empty intervals cannot overlap any record
+        if record.end() > record.start() {
+            intervals
+                .entry(record.chrom().to_owned())
+                .or_insert_with(IntervalTree::new)
+                .insert(record.start()..record.end(), value.to_owned());
+        }
+    }
+    Ok(intervals)
+}
+
+#[derive(Error, Debug)]
+pub enum IntervalError {
+    #[error(
+        "invalid BED mapping {mapping}, expected BED=TAG[:VALUE] with TAG being a valid INFO tag"
+    )]
+    InvalidMapping { mapping: String },
+    #[error("invalid interval value {value}, expected one of name, score or flag")]
+    InvalidValue { value: String },
+    #[error("missing {value:?} column in record {line} of BED file {path}")]
+    MissingColumn {
+        path: String,
+        line: usize,
+        value: IntervalValue,
+    },
+    #[error("invalid score {score} in record {line} of BED file {path}")]
+    InvalidScore {
+        score: String,
+        path: String,
+        line: usize,
+    },
+}
@@ -1,20 +1,201 @@
 //! Compute the B-allele frequencies for a given VCF file.
 //!
+//! Allele depths are taken from the FORMAT tags written by the respective variant caller:
+//! `RO`/`AO` (FreeBayes), `AD` (GATK, DeepVariant) or `AU`/`CU`/`GU`/`TU` and `TAR`/`TIR` (Strelka).
+//! By default, the tags are detected from the header.
+//!
 //! ## Usage:
 //! ```bash
-//! $ rbt vcf-baf < tests/test-freebayes.vcf > tests/baf.
+//! $ rbt vcf-baf < tests/test-freebayes.vcf > tests/baf.bcf
+//! $ rbt vcf-baf --caller gatk --indels < tests/test-gatk-baf.vcf > tests/baf-gatk.bcf
 //! ```
 //!
-use anyhow::Result;
+use anyhow::{bail, Result};
 use itertools::repeat_n;
 use itertools::Itertools;
 use rust_htslib::bcf;
+use rust_htslib::bcf::header::HeaderView;
 use rust_htslib::bcf::record::Numeric;
 use rust_htslib::bcf::{Format, Read};
 use std::f32;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Variant callers with known FORMAT tags for allele depths.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Caller {
+    Auto,
+    FreeBayes,
+    Gatk,
+    DeepVariant,
+    Strelka,
+}
+
+impl FromStr for Caller {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto" => Caller::Auto,
+            "freebayes" => Caller::FreeBayes,
+            "gatk" => Caller::Gatk,
+            "deepvariant" => Caller::DeepVariant,
+            "strelka" => Caller::Strelka,
+            _ => bail!(BafError::UnknownCaller {
+                caller: s.to_owned()
+            }),
+        })
+    }
+}
+
+/// FORMAT tags from which allele depths are obtained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DepthFields {
+    /// Reference and alternative observation counts in separate tags (FreeBayes).
+    RefAlt,
+    /// Depths of all alleles, including the reference allele (GATK, DeepVariant).
+    AlleleDepth,
+    /// Tier 1 counts per base for SNVs and per allele for indels (Strelka).
+    StrelkaTiers,
+}
+
+impl DepthFields {
+    fn new(caller: Caller, header: &HeaderView) -> Result<Self> {
+        let has_tags = |tags: &[&str]| {
+            tags.iter()
+                .all(|tag| header.format_type(tag.as_bytes()).is_ok())
+        };
+        let require = |tags: &[&str], fields| {
+            if has_tags(tags) {
+                Ok(fields)
+            } else {
+                Err(BafError::MissingTags {
+                    tags: tags.iter().join(", "),
+                })
+            }
+        };
+        Ok(match caller {
+            Caller::FreeBayes => require(&["RO", "AO"], DepthFields::RefAlt)?,
+            Caller::Gatk | Caller::DeepVariant => require(&["AD"], DepthFields::AlleleDepth)?,
+            // SNV and indel calls of Strelka are written to separate files with different tags
+            Caller::Strelka if has_tags(&["TAR", "TIR"]) => DepthFields::StrelkaTiers,
+            Caller::Strelka => require(&["AU", "CU", "GU", "TU"], DepthFields::StrelkaTiers)?,
+            Caller::Auto => {
+                if has_tags(&["RO", "AO"]) {
+                    DepthFields::RefAlt
+                } else if has_tags(&["AD"]) {
+                    DepthFields::AlleleDepth
+                } else if has_tags(&["AU", "CU", "GU", "TU"]) || has_tags(&["TAR", "TIR"]) {
+                    DepthFields::StrelkaTiers
+                } else {
+                    bail!(BafError::UndetectableCaller)
+                }
+            }
+        })
+    }
 
-pub fn calculate_baf() -> Result<()> {
+    /// Depths of all alleles (reference first) for each sample.
+    /// Samples without (complete) depth information yield `None`.
+    fn allele_depths(
+        &self,
+        record: &mut bcf::Record,
+        alleles: &[Vec<u8>],
+    ) -> Vec<Option<Vec<i32>>> {
+        let sample_count = record.sample_count() as usize;
+        let depths = match self {
+            DepthFields::RefAlt => {
+                match (
+                    format_integers(record, b"RO"),
+                    format_integers(record, b"AO"),
+                ) {
+                    (Some(ref_depths), Some(alt_depths)) => ref_depths
+                        .into_iter()
+                        .zip(alt_depths)
+                        .map(|(ref_depth, alt_depth)| {
+                            ref_depth
+                                .first()
+                                .map(|d| [&[*d], alt_depth.as_slice()].concat())
+                        })
+                        .collect_vec(),
+                    _ => vec![],
+                }
+            }
+            DepthFields::AlleleDepth => format_integers(record, b"AD")
+                .map_or_else(Vec::new, |depths| {
+                    depths.into_iter().map(Some).collect_vec()
+                }),
+            DepthFields::StrelkaTiers => {
+                if alleles.iter().all(|allele| allele.len() == 1) {
+                    let base_counts = [b'A', b'C', b'G', b'T']
+                        .iter()
+                        .map(|base| {
+                            (
+                                *base,
+                                format_integers(record, &[*base, b'U']).unwrap_or_default(),
+                            )
+                        })
+                        .collect_vec();
+                    (0..sample_count)
+                        .map(|s| {
+                            alleles
+                                .iter()
+                                .map(|allele| {
+                                    base_counts
+                                        .iter()
+                                        .find(|(base, _)| *base == allele[0].to_ascii_uppercase())
+                                        .and_then(|(_, counts)| counts.get(s))
+                                        .and_then(|tiers| tiers.first().cloned())
+                                })
+                                .collect::<Option<Vec<_>>>()
+                        })
+                        .collect_vec()
+                } else {
+                    match (
+                        format_integers(record, b"TAR"),
+                        format_integers(record, b"TIR"),
+                    ) {
+                        (Some(ref_tiers), Some(alt_tiers)) => ref_tiers
+                            .into_iter()
+                            .zip(alt_tiers)
+                            .map(|(ref_tier, alt_tier)| {
+                                match (ref_tier.first(), alt_tier.first()) {
+                                    (Some(r), Some(a)) => Some(vec![*r, *a]),
+                                    _ => None,
+                                }
+                            })
+                            .collect_vec(),
+                        _ => vec![],
+                    }
+                }
+            }
+        };
+        if depths.len() != sample_count {
+            return vec![None; sample_count];
+        }
+        depths
+            .into_iter()
+            .map(|depths| {
+                // missing values and vector ends are encoded as negative integers
+                depths.filter(|depths| {
+                    depths.len() == alleles.len() && depths.iter().all(|d| *d >= 0)
+                })
+            })
+            .collect_vec()
+    }
+}
+
+/// Integer values of the given FORMAT tag for each sample, or `None` if the tag is not present.
+fn format_integers(record: &mut bcf::Record, tag: &[u8]) -> Option<Vec<Vec<i32>>> {
+    record
+        .format(tag)
+        .integer()
+        .ok()
+        .map(|values| values.iter().map(|v| v.to_vec()).collect_vec())
+}
+
+pub fn calculate_baf(caller: Caller, indels: bool) -> Result<()> {
     let mut reader = bcf::Reader::from_stdin()?;
+    let depth_fields = DepthFields::new(caller, reader.header())?;
 
     let mut header = bcf::Header::from_template(reader.header());
     header.push_record(b"##FORMAT=<ID=BAF,Number=A,Type=Float,Description=\"b-allele frequency\">");
@@ -24,33 +205,21 @@ pub fn calculate_baf() -> Result<()> {
     for record in reader.records() {
         let mut record = record?;
 
-        let allele_lens = record.alleles().iter().map(|a| a.len()).collect_vec();
+        let alleles = record.alleles().iter().map(|a| a.to_vec()).collect_vec();
         let mut bafs = Vec::new();
-        {
-            let ref_depths = record
-                .format(b"RO")
-                .integer()?
-                .clone()
-                .into_iter()
-                .map(|d| d.to_owned())
-                .collect_vec();
-            let alt_depths = record.format(b"AO").integer()?;
-
-            for (sample_ref_depth, sample_alt_depth) in ref_depths.iter().zip(alt_depths.iter()) {
-                if allele_lens[0] != 1 || sample_ref_depth[0].is_missing() {
-                    bafs.extend(repeat_n(f32::missing(), allele_lens.len() - 1));
-                } else {
-                    let total_depth = sample_ref_depth[0] + sample_alt_depth.iter().sum::<i32>();
-                    bafs.extend(allele_lens[1..].iter().zip(sample_alt_depth.iter()).map(
-                        |(alen, d)| {
-                            if *alen == 1 {
-                                *d as f32 / total_depth as f32
-                            } else {
-                                f32::missing()
-                            }
-                        },
-                    ));
-                };
+        for sample_depths in depth_fields.allele_depths(&mut record, &alleles) {
+            match sample_depths {
+                Some(depths) if indels || alleles[0].len() == 1 => {
+                    let total_depth = depths.iter().sum::<i32>();
+                    bafs.extend(alleles[1..].iter().zip(depths[1..].iter()).map(|(alt, d)| {
+                        if indels || alt.len() == 1 {
+                            *d as f32 / total_depth as f32
+                        } else {
+                            f32::missing()
+                        }
+                    }));
+                }
+                _ => bafs.extend(repeat_n(f32::missing(), alleles.len() - 1)),
             }
         }
         writer.translate(&mut record);
@@ -60,3 +229,15 @@ pub fn calculate_baf() -> Result<()> {
 
     Ok(())
 }
+
+#[derive(Error, Debug)]
+pub enum BafError {
+    #[error(
+        "unknown variant caller {caller}, use one of auto, freebayes, gatk, deepvariant or strelka"
+    )]
+    UnknownCaller { caller: String },
+    #[error("missing FORMAT tag(s) {tags} in VCF/BCF header")]
+    MissingTags { tags: String },
+    #[error("unable to detect allele depth FORMAT tags (RO/AO, AD, AU/CU/GU/TU or TAR/TIR), please specify --caller")]
+    UndetectableCaller,
+}
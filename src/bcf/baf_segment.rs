@@ -0,0 +1,419 @@
+//! Segment b-allele frequencies and depth ratios into copy number and LOH states.
+//!
+//! Input is a VCF/BCF annotated with `rbt vcf-baf` (FORMAT tags `BAF` and `DP`). Along each
+//! chromosome, a hidden Markov model with copy number states (total and minor copy number) is
+//! decoded with the Viterbi algorithm. Emissions are the mirrored BAF of the tumor sample and,
+//! if a matched normal sample is given, the median-centered log2 tumor/normal depth ratio.
+//! With a normal sample, only sites that are heterozygous in the normal sample are used.
+//! Without, homozygous germline sites are indistinguishable from LOH.
+//!
+//! ## Usage:
+//! ```bash
+//! $ rbt vcf-baf-segment --tumor tumor --normal normal < tests/test-baf-segment.vcf > tests/baf-segments.bed
+//! ```
+//!
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+use rust_htslib::bcf;
+use rust_htslib::bcf::record::Numeric;
+use rust_htslib::bcf::{Format, Read};
+use std::io;
+use std::io::Write;
+use std::str;
+use thiserror::Error;
+
+/// Hidden states of the segmentation model.
+const STATES: [CopyNumberState; 5] = [
+    CopyNumberState { total: 1, minor: 0 },
+    CopyNumberState { total: 2, minor: 1 },
+    CopyNumberState { total: 2, minor: 0 },
+    CopyNumberState { total: 3, minor: 1 },
+    CopyNumberState { total: 4, minor: 2 },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CopyNumberState {
+    total: u32,
+    minor: u32,
+}
+
+impl CopyNumberState {
+    fn is_loh(&self) -> bool {
+        self.minor == 0
+    }
+
+    fn name(&self) -> String {
+        if self.is_loh() {
+            format!("CN{}_LOH", self.total)
+        } else {
+            format!("CN{}", self.total)
+        }
+    }
+
+    /// Expected mirrored BAF of a germline heterozygous site.
+    fn expected_baf(&self) -> f64 {
+        (self.total - self.minor).max(self.minor) as f64 / self.total as f64
+    }
+
+    /// Expected log2 depth ratio compared to a diploid normal.
+    fn expected_log2_ratio(&self) -> f64 {
+        (self.total as f64 / 2.0).log2()
+    }
+}
+
+/// Parameters of the segmentation model.
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentationParams {
+    /// Probability to switch into another state between two consecutive sites.
+    pub switch_prob: f64,
+    /// Standard deviation of the mirrored BAF around its expected value.
+    pub baf_sd: f64,
+    /// Standard deviation of the log2 depth ratio around its expected value.
+    pub ratio_sd: f64,
+    /// Minimum BAF (and maximum 1 - BAF) for a site to count as heterozygous in the normal sample.
+    pub min_het_baf: f64,
+}
+
+#[derive(Debug, Clone)]
+struct Marker {
+    pos: u64,
+    baf: f64,
+    log2_ratio: Option<f64>,
+}
+
+impl Marker {
+    fn log_likelihood(&self, state: &CopyNumberState, params: &SegmentationParams) -> f64 {
+        let gauss = |x: f64, mu: f64, sd: f64| -(x - mu).powi(2) / (2.0 * sd.powi(2));
+        gauss(self.baf, state.expected_baf(), params.baf_sd)
+            + self.log2_ratio.map_or(0.0, |r| {
+                gauss(r, state.expected_log2_ratio(), params.ratio_sd)
+            })
+    }
+}
+
+#[derive(Debug)]
+struct Segment {
+    chrom: String,
+    start: u64,
+    end: u64,
+    state: CopyNumberState,
+    markers: usize,
+    mean_baf: f64,
+    mean_log2_ratio: Option<f64>,
+}
+
+impl Segment {
+    fn new(chrom: &str, markers: &[Marker], state: CopyNumberState) -> Self {
+        let ratios = markers.iter().filter_map(|m| m.log2_ratio).collect_vec();
+        Segment {
+            chrom: chrom.to_owned(),
+            start: markers[0].pos,
+            end: markers[markers.len() - 1].pos + 1,
+            state,
+            markers: markers.len(),
+            mean_baf: markers.iter().map(|m| m.baf).sum::<f64>() / markers.len() as f64,
+            mean_log2_ratio: if ratios.is_empty() {
+                None
+            } else {
+                Some(ratios.iter().sum::<f64>() / ratios.len() as f64)
+            },
+        }
+    }
+}
+
+pub fn segment(
+    tumor: &str,
+    normal: Option<&str>,
+    output_format: &str,
+    params: SegmentationParams,
+) -> Result<()> {
+    let mut reader = bcf::Reader::from_stdin()?;
+    let header = reader.header().clone();
+    let sample_index = |name: &str| {
+        header
+            .sample_id(name.as_bytes())
+            .ok_or_else(|| SegmentError::UnknownSample {
+                name: name.to_owned(),
+            })
+    };
+    let tumor_idx = sample_index(tumor)?;
+    let normal_idx = normal.map(sample_index).transpose()?;
+    let required_tags: &[&str] = if normal.is_some() {
+        &["BAF", "DP"]
+    } else {
+        &["BAF"]
+    };
+    for tag in required_tags {
+        if header.format_type(tag.as_bytes()).is_err() {
+            bail!(SegmentError::MissingTag {
+                tag: (*tag).to_owned()
+            });
+        }
+    }
+
+    let mut chroms: Vec<(String, Vec<Marker>)> = Vec::new();
+    for record in reader.records() {
+        let mut record = record?;
+        let rid = record.rid().context("record without CHROM")?;
+        let chrom = str::from_utf8(header.rid2name(rid)?)?.to_owned();
+
+        let bafs = format_values(&mut record, b"BAF")?;
+        let depths = if normal_idx.is_some() {
+            format_values(&mut record, b"DP")?
+        } else {
+            vec![]
+        };
+
+        let baf = match bafs[tumor_idx] {
+            Some(baf) => baf,
+            None => continue,
+        };
+        let log2_ratio = if let Some(normal_idx) = normal_idx {
+            match bafs[normal_idx] {
+                Some(normal_baf)
+                    if normal_baf >= params.min_het_baf
+                        && normal_baf <= 1.0 - params.min_het_baf => {}
+                _ => continue,
+            }
+            match (depths[tumor_idx], depths[normal_idx]) {
+                (Some(t), Some(n)) if t > 0.0 && n > 0.0 => Some((t / n).log2()),
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        let marker = Marker {
+            pos: record.pos() as u64,
+            baf: baf.max(1.0 - baf),
+            log2_ratio,
+        };
+        match chroms.last_mut() {
+            Some((last, markers)) if *last == chrom => markers.push(marker),
+            _ => chroms.push((chrom, vec![marker])),
+        }
+    }
+
+    // center depth ratios at the genome wide median, assuming a mostly diploid genome
+    let median = median(
+        chroms
+            .iter()
+            .flat_map(|(_, markers)| markers.iter().filter_map(|m| m.log2_ratio))
+            .collect_vec(),
+    );
+    if let Some(median) = median {
+        for (_, markers) in &mut chroms {
+            for marker in markers {
+                marker.log2_ratio = marker.log2_ratio.map(|r| r - median);
+            }
+        }
+    }
+
+    let segments = chroms
+        .iter()
+        .flat_map(|(chrom, markers)| {
+            let path = viterbi(markers, &params);
+            let mut segments = Vec::new();
+            let mut start = 0;
+            for i in 1..=markers.len() {
+                if i == markers.len() || path[i] != path[start] {
+                    segments.push(Segment::new(chrom, &markers[start..i], STATES[path[start]]));
+                    start = i;
+                }
+            }
+            segments
+        })
+        .collect_vec();
+
+    match output_format {
+        "bed" => write_bed(&segments),
+        "vcf" => write_vcf(&segments, &header),
+        _ => bail!(SegmentError::UnknownOutputFormat {
+            format: output_format.to_owned()
+        }),
+    }
+}
+
+/// First value of the given FORMAT tag for each sample, `None` if missing or not finite
+/// (e.g. the BAF 0/0 of a site without coverage).
+fn format_values(record: &mut bcf::Record, tag: &[u8]) -> Result<Vec<Option<f64>>> {
+    Ok(match record.header().format_type(tag)?.0 {
+        bcf::header::TagType::Float => record
+            .format(tag)
+            .float()?
+            .iter()
+            .map(|v| {
+                v.first()
+                    .filter(|v| !v.is_missing())
+                    .map(|v| *v as f64)
+                    .filter(|v| v.is_finite())
+            })
+            .collect_vec(),
+        _ => record
+            .format(tag)
+            .integer()?
+            .iter()
+            .map(|v| v.first().filter(|v| !v.is_missing()).map(|v| *v as f64))
+            .collect_vec(),
+    })
+}
+
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    values.retain(|v| v.is_finite());
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mid = values.len() / 2;
+    Some(if values.len() % 2 == 0 {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    })
+}
+
+/// Most likely sequence of state indices for the given markers.
+fn viterbi(markers: &[Marker], params: &SegmentationParams) -> Vec<usize> {
+    if markers.is_empty() {
+        return vec![];
+    }
+    let stay = (1.0 - params.switch_prob).ln();
+    let switch = (params.switch_prob / (STATES.len() - 1) as f64).ln();
+    let emissions = |marker: &Marker| {
+        STATES
+            .iter()
+            .map(|state| marker.log_likelihood(state, params))
+            .collect_vec()
+    };
+
+    let mut scores = emissions(&markers[0]);
+    let mut backtrack: Vec<Vec<usize>> = Vec::with_capacity(markers.len());
+    for marker in &markers[1..] {
+        let (best, pointers): (Vec<f64>, Vec<usize>) = emissions(marker)
+            .into_iter()
+            .enumerate()
+            .map(|(j, emission)| {
+                let (from, score) = scores
+                    .iter()
+                    .enumerate()
+                    .map(|(i, score)| (i, score + if i == j { stay } else { switch }))
+                    .fold((0, f64::NEG_INFINITY), |best, (i, score)| {
+                        if score > best.1 {
+                            (i, score)
+                        } else {
+                            best
+                        }
+                    });
+                (score + emission, from)
+            })
+            .unzip();
+        scores = best;
+        backtrack.push(pointers);
+    }
+
+    let mut state = scores
+        .iter()
+        .enumerate()
+        .fold((0, f64::NEG_INFINITY), |best, (i, score)| {
+            if *score > best.1 {
+                (i, *score)
+            } else {
+                best
+            }
+        })
+        .0;
+    let mut path = vec![state];
+    for pointers in backtrack.iter().rev() {
+        state = pointers[state];
+        path.push(state);
+    }
+    path.reverse();
+    path
+}
+
+fn write_bed(segments: &[Segment]) -> Result<()> {
+    let mut out = io::BufWriter::new(io::stdout());
+    writeln!(
+        out,
+        "#chrom\tstart\tend\tstate\tmarkers\tmean_baf\tmean_log2_ratio"
+    )?;
+    for segment in segments {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{:.3}\t{}",
+            segment.chrom,
+            segment.start,
+            segment.end,
+            segment.state.name(),
+            segment.markers,
+            segment.mean_baf,
+            segment
+                .mean_log2_ratio
+                .map_or_else(|| ".".to_owned(), |r| format!("{:.3}", r))
+        )?;
+    }
+    Ok(())
+}
+
+fn write_vcf(segments: &[Segment], template: &bcf::header::HeaderView) -> Result<()> {
+    let mut header = bcf::Header::from_template_subset(template, &[])?;
+    header.push_record(b"##ALT=<ID=CNV,Description=\"Copy number variable region\">");
+    header.push_record(
+        b"##INFO=<ID=END,Number=1,Type=Integer,Description=\"End position of the segment\">",
+    );
+    header.push_record(
+        b"##INFO=<ID=SVTYPE,Number=1,Type=String,Description=\"Type of structural variant\">",
+    );
+    header.push_record(b"##INFO=<ID=CN,Number=1,Type=Integer,Description=\"Total copy number\">");
+    header.push_record(
+        b"##INFO=<ID=MCN,Number=1,Type=Integer,Description=\"Minor allele copy number\">",
+    );
+    header
+        .push_record(b"##INFO=<ID=LOH,Number=0,Type=Flag,Description=\"Loss of heterozygosity\">");
+    header.push_record(
+        b"##INFO=<ID=MARKERS,Number=1,Type=Integer,Description=\"Number of sites in the segment\">",
+    );
+    header.push_record(
+        b"##INFO=<ID=BAF,Number=1,Type=Float,Description=\"Mean mirrored b-allele frequency of the segment\">",
+    );
+    header.push_record(
+        b"##INFO=<ID=LOG2R,Number=1,Type=Float,Description=\"Mean median-centered log2 tumor/normal depth ratio of the segment\">",
+    );
+
+    let mut writer = bcf::Writer::from_stdout(&header, true, Format::Vcf)?;
+    for segment in segments {
+        let mut record = writer.empty_record();
+        let rid = writer.header().name2rid(segment.chrom.as_bytes())?;
+        record.set_rid(Some(rid));
+        record.set_pos(segment.start as i64);
+        if segment.state == (CopyNumberState { total: 2, minor: 1 }) {
+            record.set_alleles(&[b"N"])?;
+        } else {
+            record.set_alleles(&[b"N", b"<CNV>"])?;
+            record.push_info_string(b"SVTYPE", &[b"CNV"])?;
+        }
+        record.push_info_integer(b"END", &[segment.end as i32])?;
+        record.push_info_integer(b"CN", &[segment.state.total as i32])?;
+        record.push_info_integer(b"MCN", &[segment.state.minor as i32])?;
+        if segment.state.is_loh() {
+            record.push_info_flag(b"LOH")?;
+        }
+        record.push_info_integer(b"MARKERS", &[segment.markers as i32])?;
+        record.push_info_float(b"BAF", &[segment.mean_baf as f32])?;
+        if let Some(ratio) = segment.mean_log2_ratio {
+            record.push_info_float(b"LOG2R", &[ratio as f32])?;
+        }
+        writer.write(&record)?;
+    }
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum SegmentError {
+    #[error("sample {name} not found in VCF/BCF")]
+    UnknownSample { name: String },
+    #[error("missing FORMAT tag {tag}, annotate b-allele frequencies with rbt vcf-baf first")]
+    MissingTag { tag: String },
+    #[error("unknown output format {format}, use bed or vcf")]
+    UnknownOutputFormat { format: String },
+}
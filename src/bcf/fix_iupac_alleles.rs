@@ -1,43 +1,182 @@
-use anyhow::Result;
+//! Replace IUPAC codes in alleles in order to comply with the VCF specification.
+//!
+//! By default, each ambiguous base is converted into an N. Optionally, REF alleles are resolved
+//! from a reference genome and ALT alleles are expanded into the concrete alleles they represent.
+//! Changes can be annotated with the INFO tag `IUPAC_FIXED` and are reported at debug level.
+//!
+//! ## Usage:
+//! ```bash
+//! $ rbt vcf-fix-iupac-alleles --reference tests/ref.fa --expand-alts --annotate < tests/test-iupac-resolve.vcf > tests/iupac-resolved.bcf
+//! ```
+//!
+use crate::bcf::alleles::AlleleFields;
+use anyhow::{Context, Result};
 use bio::alphabets::dna::n_alphabet;
+use bio::io::fasta;
 use itertools::Itertools;
+use log::{debug, info};
 use rust_htslib::bcf::{self, Format, Read};
+use std::path::Path;
+use std::str;
 
-pub fn fix_iupac_alleles() -> Result<()> {
+/// Concrete bases represented by an IUPAC code, or `None` for non-ambiguous or invalid bases.
+fn iupac_bases(base: u8) -> Option<&'static [u8]> {
+    Some(match base.to_ascii_uppercase() {
+        b'R' => b"AG",
+        b'Y' => b"CT",
+        b'S' => b"CG",
+        b'W' => b"AT",
+        b'K' => b"GT",
+        b'M' => b"AC",
+        b'B' => b"CGT",
+        b'D' => b"AGT",
+        b'H' => b"ACT",
+        b'V' => b"ACG",
+        _ => return None,
+    })
+}
+
+pub fn fix_iupac_alleles<P: AsRef<Path> + std::fmt::Debug>(
+    reference: Option<P>,
+    expand_alts: bool,
+    annotate: bool,
+) -> Result<()> {
     let mut inbcf = bcf::Reader::from_stdin()?;
-    let mut outbcf = bcf::Writer::from_stdout(
-        &bcf::Header::from_template(inbcf.header()),
-        false,
-        Format::Bcf,
-    )?;
+    let mut header = bcf::Header::from_template(inbcf.header());
+    if annotate {
+        header.push_record(
+            b"##INFO=<ID=IUPAC_FIXED,Number=.,Type=String,Description=\"Alleles containing IUPAC codes that have been fixed (allele index:original allele>fixed allele(s), separated by |)\">",
+        );
+    }
+    let mut outbcf = bcf::Writer::from_stdout(&header, false, Format::Bcf)?;
+    let mut reference = reference
+        .map(|path| fasta::IndexedReader::from_file(&path))
+        .transpose()
+        .context("error reading reference genome, make sure it is indexed with samtools faidx")?;
+    let allele_fields = AlleleFields::new(inbcf.header());
     let valid_alphabet = n_alphabet();
+    let to_n = |allele: &[u8]| {
+        allele
+            .iter()
+            .map(|base| {
+                if valid_alphabet.is_word(&[*base]) {
+                    *base
+                } else {
+                    b'N'
+                }
+            })
+            .collect_vec()
+    };
+    let mut fixed_records = 0;
 
     for res in inbcf.records() {
         let mut rec = res?;
 
-        let alleles = rec.alleles();
-        if !alleles.iter().all(|allele| valid_alphabet.is_word(*allele)) {
-            let fixed = alleles
-                .into_iter()
-                .map(|allele| {
-                    allele
+        let alleles = rec.alleles().iter().map(|a| a.to_vec()).collect_vec();
+        if !alleles.iter().all(|allele| valid_alphabet.is_word(allele)) {
+            let mut changes = Vec::new();
+            let chrom = str::from_utf8(
+                outbcf
+                    .header()
+                    .rid2name(rec.rid().context("record without CHROM")?)?,
+            )?
+            .to_owned();
+
+            // REF allele
+            let ref_allele = &alleles[0];
+            let mut fixed_ref = to_n(ref_allele);
+            if let Some(reference) = reference.as_mut() {
+                if !valid_alphabet.is_word(ref_allele) {
+                    let start = rec.pos() as u64;
+                    let mut seq = Vec::new();
+                    reference.fetch(&chrom, start, start + ref_allele.len() as u64)?;
+                    reference.read(&mut seq)?;
+                    for (i, base) in ref_allele.iter().enumerate() {
+                        if !valid_alphabet.is_word(&[*base]) {
+                            fixed_ref[i] = match seq.get(i).map(|b| b.to_ascii_uppercase()) {
+                                Some(b) if b"ACGT".contains(&b) => b,
+                                _ => b'N',
+                            };
+                        }
+                    }
+                }
+            }
+            if fixed_ref != *ref_allele {
+                changes.push(format_change(0, ref_allele, &[fixed_ref.as_slice()]));
+            }
+
+            // ALT alleles
+            let mut fixed_alleles = vec![fixed_ref];
+            let mut origin = vec![0];
+            for (i, alt) in alleles.iter().enumerate().skip(1) {
+                let mut fixed = vec![];
+                // symbolic alleles and breakends are not expanded
+                if expand_alts
+                    && alt.iter().all(|base| base.is_ascii_alphabetic())
+                    && alt.iter().any(|base| iupac_bases(*base).is_some())
+                {
+                    fixed = alt
                         .iter()
                         .map(|base| {
-                            if valid_alphabet.is_word(&[*base]) {
-                                *base
-                            } else {
-                                b'N'
-                            }
+                            iupac_bases(*base).map_or_else(|| to_n(&[*base]), |b| b.to_vec())
                         })
-                        .collect_vec()
-                })
-                .collect_vec();
+                        .multi_cartesian_product()
+                        .filter(|allele| !fixed_alleles.contains(allele))
+                        .unique()
+                        .collect_vec();
+                }
+                if fixed.is_empty() {
+                    fixed.push(to_n(alt));
+                }
+                if fixed.len() > 1 || fixed[0] != *alt {
+                    changes.push(format_change(
+                        i,
+                        alt,
+                        &fixed.iter().map(|a| a.as_slice()).collect_vec(),
+                    ));
+                }
+                origin.extend(std::iter::repeat(i).take(fixed.len()));
+                fixed_alleles.extend(fixed);
+            }
+
+            for change in &changes {
+                debug!("{}:{} {}", chrom, rec.pos() + 1, str::from_utf8(change)?);
+            }
+            fixed_records += 1;
 
-            rec.set_alleles(&fixed.iter().map(|allele| allele.as_slice()).collect_vec())?;
+            outbcf.translate(&mut rec);
+            let fixed_alleles = fixed_alleles
+                .iter()
+                .map(|allele| allele.as_slice())
+                .collect_vec();
+            if origin.len() == alleles.len() {
+                rec.set_alleles(&fixed_alleles)?;
+            } else {
+                allele_fields.set_alleles(&mut rec, &fixed_alleles, &origin)?;
+            }
+            if annotate {
+                rec.push_info_string(
+                    b"IUPAC_FIXED",
+                    &changes.iter().map(|c| c.as_slice()).collect_vec(),
+                )?;
+            }
+        } else {
+            outbcf.translate(&mut rec);
         }
 
         outbcf.write(&rec)?;
     }
+    info!("Fixed IUPAC codes in {} records.", fixed_records);
 
     Ok(())
 }
+
+fn format_change(index: usize, original: &[u8], fixed: &[&[u8]]) -> Vec<u8> {
+    format!(
+        "{}:{}>{}",
+        index,
+        String::from_utf8_lossy(original),
+        fixed.iter().map(|a| String::from_utf8_lossy(a)).join("|")
+    )
+    .into_bytes()
+}
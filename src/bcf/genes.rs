@@ -0,0 +1,114 @@
+//! Genes of VCF/BCF records, taken from functional annotations or a plain INFO tag.
+use crate::bcf::report::annotation::parse_fields;
+use anyhow::{bail, Result};
+use rust_htslib::bcf;
+use std::str;
+use thiserror::Error;
+
+/// INFO field from which the genes of a record are taken.
+#[derive(Debug, Clone)]
+pub enum GeneField {
+    /// Functional annotations (e.g. ANN from SnpEff or CSQ from VEP) with the gene in the given
+    /// subfield. If no subfield is given, `SYMBOL` or `Gene_Name` is used.
+    Annotation {
+        field: String,
+        subfield: Option<String>,
+    },
+    /// A plain INFO tag containing gene names.
+    Tag(String),
+}
+
+/// Gene field resolved against a VCF/BCF header.
+#[derive(Debug, Clone)]
+pub(crate) enum GeneSource {
+    Annotation { field: Vec<u8>, index: usize },
+    Tag(Vec<u8>),
+}
+
+impl GeneSource {
+    pub(crate) fn new(header: &bcf::header::HeaderView, gene_field: &GeneField) -> Result<Self> {
+        match gene_field {
+            GeneField::Tag(tag) => {
+                if header.info_type(tag.as_bytes()).is_err() {
+                    bail!(GeneError::MissingInfoField { field: tag.clone() });
+                }
+                Ok(GeneSource::Tag(tag.as_bytes().to_vec()))
+            }
+            GeneField::Annotation { field, subfield } => {
+                let subfields = get_annotation_subfields(header, field)?;
+                let candidates = match subfield {
+                    Some(subfield) => vec![subfield.as_str()],
+                    None => vec!["SYMBOL", "Gene_Name"],
+                };
+                let index = subfields
+                    .iter()
+                    .position(|s| candidates.contains(&s.as_str()))
+                    .ok_or_else(|| GeneError::MissingGeneSubfield {
+                        field: field.clone(),
+                        subfields: candidates.join(" or "),
+                    })?;
+                Ok(GeneSource::Annotation {
+                    field: field.as_bytes().to_vec(),
+                    index,
+                })
+            }
+        }
+    }
+}
+
+/// Names of the pipe-separated subfields of an annotation field, as given by its header
+/// description (e.g. `Format: Allele|Consequence|...` for VEP or `'Allele | Annotation | ...'` for SnpEff).
+fn get_annotation_subfields(
+    header: &bcf::header::HeaderView,
+    annotation_field: &str,
+) -> Result<Vec<String>> {
+    for rec in header.header_records() {
+        if let bcf::HeaderRecord::Info { values, .. } = rec {
+            if values.get("ID").map(|id| id.as_str()) == Some(annotation_field) {
+                let description = values
+                    .get("Description")
+                    .map_or("", |d| d.as_str())
+                    .trim()
+                    .trim_matches('"')
+                    .trim();
+                return Ok(parse_fields(description));
+            }
+        }
+    }
+    Err(GeneError::MissingInfoField {
+        field: annotation_field.to_owned(),
+    }
+    .into())
+}
+
+/// Genes of the given record. Records without annotation yield no genes.
+pub(crate) fn extract_genes(
+    rec: &mut bcf::Record,
+    gene_source: &GeneSource,
+) -> Result<Vec<String>> {
+    let (field, index) = match gene_source {
+        GeneSource::Annotation { field, index } => (field, Some(*index)),
+        GeneSource::Tag(tag) => (tag, None),
+    };
+    let genes = match rec.info(field).string()? {
+        Some(entries) => entries
+            .iter()
+            .filter_map(|entry| match index {
+                Some(index) => entry.split(|c| *c == b'|').nth(index),
+                None => Some(*entry),
+            })
+            .filter(|gene| !gene.is_empty() && *gene != b".")
+            .map(|gene| Ok(str::from_utf8(gene)?.to_owned()))
+            .collect::<Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    Ok(genes)
+}
+
+#[derive(Error, Debug)]
+pub enum GeneError {
+    #[error("missing INFO field {field} in VCF/BCF header")]
+    MissingInfoField { field: String },
+    #[error("could not find subfield {subfields} in the header description of annotation field {field}, please specify --gene-subfield")]
+    MissingGeneSubfield { field: String, subfields: String },
+}
@@ -4,17 +4,24 @@
 //! Results are printed as BCF to STDOUT, with an additional INFO tag MATCHING.
 //! The two vcfs do not have to be sorted.
 //!
+//! With `--genotypes`, the genotypes of all samples contained in both files are compared
+//! as well. The result is written into the FORMAT tag GTMATCH and a per-sample
+//! concordance summary is logged to STDERR.
+//!
 //! ## Usage:
 //! ```bash
 //! rbt vcf-match -d 50 -l 20 tests/test3.vcf < tests/test2.vcf > tests/matching.bcf
+//! rbt vcf-match --genotypes tests/test-gt-truth.vcf < tests/test-gt-query.vcf > tests/gt-matching.bcf
 //! ```
 //!
 use anyhow::{bail, Result};
 use itertools::Itertools;
 use log::{info, warn};
 use rust_htslib::bcf;
+use rust_htslib::bcf::record::GenotypeAllele;
 use rust_htslib::bcf::{Format, Read};
 use std::collections::{btree_map, BTreeMap, HashMap};
+use std::fmt;
 use std::path::Path;
 use std::str;
 use thiserror::Error;
@@ -22,11 +29,18 @@ use thiserror::Error;
 pub struct VarIndex {
     inner: HashMap<Vec<u8>, BTreeMap<u64, Vec<Variant>>>,
     max_dist: u64,
+    samples: Vec<Vec<u8>>,
 }
 
 impl VarIndex {
-    pub fn new(mut reader: bcf::Reader, max_dist: u64) -> Result<Self> {
+    pub fn new(mut reader: bcf::Reader, max_dist: u64, genotypes: bool) -> Result<Self> {
         let mut inner: HashMap<Vec<u8>, BTreeMap<u64, Vec<Variant>>> = HashMap::new();
+        let samples = reader
+            .header()
+            .samples()
+            .into_iter()
+            .map(|s| s.to_owned())
+            .collect_vec();
         let mut i = 0;
         let mut rec = reader.empty_record();
         loop {
@@ -37,11 +51,14 @@ impl VarIndex {
             };
             if let Some(rid) = rec.rid() {
                 let chrom = reader.header().rid2name(rid)?;
+                let mut var = Variant::new(&mut rec, &mut i)?;
+                if genotypes {
+                    var.genotypes = Some(read_genotypes(&mut rec)?);
+                }
                 let recs = inner.entry(chrom.to_owned()).or_insert_with(BTreeMap::new);
                 recs.entry(rec.pos() as u64)
                     .or_insert_with(Vec::new)
-                    .push(Variant::new(&mut rec, &mut i)?);
-            //recs.insert(rec.pos(), Variant::new(&mut rec, &mut i)?);
+                    .push(var);
             } else {
                 // skip records without rid
                 let alt_count = rec.alleles().len() as u32 - 1;
@@ -49,7 +66,16 @@ impl VarIndex {
             }
         }
 
-        Ok(VarIndex { inner, max_dist })
+        Ok(VarIndex {
+            inner,
+            max_dist,
+            samples,
+        })
+    }
+
+    /// Index of the given sample in the indexed VCF/BCF file.
+    pub fn sample_index(&self, sample: &[u8]) -> Option<usize> {
+        self.samples.iter().position(|s| s == sample)
     }
 
     pub fn range(&self, chrom: &[u8], pos: u64) -> Option<btree_map::Range<'_, u64, Vec<Variant>>> {
@@ -59,7 +85,12 @@ impl VarIndex {
     }
 }
 
-pub fn match_variants<P: AsRef<Path>>(matchbcf: P, max_dist: u32, max_len_diff: u32) -> Result<()> {
+pub fn match_variants<P: AsRef<Path>>(
+    matchbcf: P,
+    max_dist: u32,
+    max_len_diff: u32,
+    genotypes: bool,
+) -> Result<()> {
     let mut inbcf = bcf::Reader::from_stdin()?;
     let mut header = bcf::Header::from_template(inbcf.header());
 
@@ -70,8 +101,32 @@ pub fn match_variants<P: AsRef<Path>>(matchbcf: P, max_dist: u32, max_len_diff:
         alternative allele separately). For indels, matching is fuzzy: distance of centres <= {}, difference of \
         lengths <= {}\">", max_dist, max_len_diff).as_bytes()
     );
+    if genotypes {
+        header.push_record(
+            b"##FORMAT=<ID=GTMATCH,Number=1,Type=String,\
+            Description=\"Comparison of the genotype of this sample with the genotype of the same sample \
+            in another VCF/BCF (concordant, phase_discordant, zygosity_discordant, allele_discordant or missing). \
+            Variants that are not contained in the other VCF/BCF are treated as homozygous reference there.\">",
+        );
+    }
     let mut outbcf = bcf::Writer::from_path(&"-", &header, false, Format::Bcf)?;
-    let index = VarIndex::new(bcf::Reader::from_path(matchbcf)?, max_dist as u64)?;
+    let index = VarIndex::new(
+        bcf::Reader::from_path(matchbcf)?,
+        max_dist as u64,
+        genotypes,
+    )?;
+
+    let samples = inbcf
+        .header()
+        .samples()
+        .into_iter()
+        .map(|s| s.to_owned())
+        .collect_vec();
+    let sample_indices = samples.iter().map(|s| index.sample_index(s)).collect_vec();
+    if genotypes && sample_indices.iter().all(|idx| idx.is_none()) {
+        warn!("None of the samples is contained in both VCF/BCF files, GTMATCH will be empty.");
+    }
+    let mut concordance = vec![GenotypeConcordance::default(); samples.len()];
 
     let mut rec = inbcf.empty_record();
     let mut i = 0;
@@ -88,20 +143,41 @@ pub fn match_variants<P: AsRef<Path>>(matchbcf: P, max_dist: u32, max_len_diff:
             let pos = rec.pos();
 
             let var = Variant::new(&mut rec, &mut i)?;
-            let matching = var
+            let matches = var
                 .alleles
                 .iter()
                 .map(|a| {
                     if let Some(range) = index.range(chrom, pos as u64) {
                         for v in range.map(|(_, idx_vars)| idx_vars).flatten() {
-                            if let Some(id) = var.matches(v, a, max_dist, max_len_diff) {
-                                return id as i32;
+                            if let Some(j) = var.matching_allele(v, a, max_dist, max_len_diff) {
+                                return Some((v, j));
                             }
                         }
                     }
-                    -1
+                    None
                 })
                 .collect_vec();
+            let matching = matches
+                .iter()
+                .map(|m| m.map_or(-1, |(v, j)| v.id(j) as i32))
+                .collect_vec();
+
+            if genotypes {
+                let gt_matches = compare_genotypes(&mut rec, &matches, &sample_indices)?;
+                for (sample_concordance, gt_match) in concordance.iter_mut().zip(&gt_matches) {
+                    if let Some(gt_match) = gt_match {
+                        sample_concordance.count(*gt_match);
+                    }
+                }
+                let values = gt_matches
+                    .iter()
+                    .map(|gt_match| gt_match.map_or(".".to_owned(), |m| m.to_string()))
+                    .collect_vec();
+                rec.push_format_string(
+                    b"GTMATCH",
+                    &values.iter().map(|v| v.as_bytes()).collect_vec(),
+                )?;
+            }
 
             rec.push_info_integer(b"MATCHING", &matching)?;
         }
@@ -113,15 +189,90 @@ pub fn match_variants<P: AsRef<Path>>(matchbcf: P, max_dist: u32, max_len_diff:
     }
     info!("{} variants written.", i);
 
+    if genotypes {
+        info!("sample\tconcordant\tphase_discordant\tzygosity_discordant\tallele_discordant\tmissing\tconcordance");
+        for ((sample, sample_concordance), idx) in
+            samples.iter().zip(&concordance).zip(&sample_indices)
+        {
+            if idx.is_some() {
+                info!("{}\t{}", str::from_utf8(sample)?, sample_concordance);
+            }
+        }
+    }
+
     Ok(())
 }
 
+/// Read all genotypes of the given record, one vector of alleles per sample.
+fn read_genotypes(rec: &mut bcf::Record) -> Result<Vec<Vec<GenotypeAllele>>> {
+    let sample_count = rec.sample_count() as usize;
+    let genotypes = rec.genotypes()?;
+    Ok((0..sample_count)
+        .map(|s| {
+            genotypes
+                .get(s)
+                .iter()
+                .cloned()
+                // Skip vector end markers of samples with lower ploidy.
+                .filter(|allele| match allele {
+                    GenotypeAllele::Unphased(a) | GenotypeAllele::Phased(a) => *a >= 0,
+                    _ => true,
+                })
+                .collect_vec()
+        })
+        .collect_vec())
+}
+
+/// Compare the genotype of each sample in the given record with the genotype of the same sample
+/// in the matching variant. Alleles of the record are translated into the allele indices of the
+/// matching variant. If no variant matches, the sample is assumed to be homozygous reference there.
+/// Samples that are not contained in the other VCF/BCF yield `None`.
+fn compare_genotypes(
+    rec: &mut bcf::Record,
+    matches: &[Option<(&Variant, usize)>],
+    sample_indices: &[Option<usize>],
+) -> Result<Vec<Option<GenotypeMatch>>> {
+    let genotypes = read_genotypes(rec)?;
+    // The variant the genotypes are compared against is the one matched by the first allele.
+    let other = matches.iter().flatten().next().map(|(v, _)| *v);
+    // Translate allele indices of this record into the allele indices of the other variant.
+    // Alternative alleles without a match get a distinct negative index.
+    let translate = |allele: i32| -> i32 {
+        if allele == 0 {
+            return 0;
+        }
+        match (other, matches.get(allele as usize - 1)) {
+            (Some(other), Some(Some((v, j)))) if std::ptr::eq(other, *v) => *j as i32 + 1,
+            _ => -allele,
+        }
+    };
+
+    Ok(genotypes
+        .iter()
+        .zip(sample_indices)
+        .map(|(gt, idx)| {
+            idx.map(|idx| {
+                let other_gt = match other {
+                    Some(other) => other
+                        .genotypes
+                        .as_ref()
+                        .and_then(|gts| gts.get(idx).cloned())
+                        .unwrap_or_default(),
+                    None => vec![GenotypeAllele::Unphased(0); gt.len()],
+                };
+                GenotypeMatch::new(gt, &other_gt, &translate)
+            })
+        })
+        .collect_vec())
+}
+
 #[derive(Debug)]
 pub struct Variant {
     id: u32,
     rid: u32,
     pos: u64,
     alleles: Vec<VariantType>,
+    genotypes: Option<Vec<Vec<GenotypeAllele>>>,
 }
 
 impl Variant {
@@ -209,6 +360,7 @@ impl Variant {
             rid: rec.rid().unwrap(),
             pos: pos as u64,
             alleles: _alleles,
+            genotypes: None,
         };
         *id += alleles.len() as u32 - 1;
         Ok(var)
@@ -230,6 +382,18 @@ impl Variant {
         max_dist: u32,
         max_len_diff: u32,
     ) -> Option<u32> {
+        self.matching_allele(other, allele, max_dist, max_len_diff)
+            .map(|j| other.id(j))
+    }
+
+    /// Return the index of the first alternative allele of other that matches the given allele.
+    pub fn matching_allele(
+        &self,
+        other: &Variant,
+        allele: &VariantType,
+        max_dist: u32,
+        max_len_diff: u32,
+    ) -> Option<usize> {
         if allele.is_unsupported() {
             return None;
         }
@@ -241,13 +405,13 @@ impl Variant {
             match (allele, b) {
                 (&VariantType::Snv(a), &VariantType::Snv(b)) => {
                     if a == b && dist == 0 {
-                        return Some(other.id(j));
+                        return Some(j);
                     }
                 }
                 (&VariantType::Insertion(l1), &VariantType::Insertion(l2))
                 | (&VariantType::Deletion(l1), &VariantType::Deletion(l2)) => {
                     if (l1 as i32 - l2 as i32).abs() as u32 <= max_len_diff && dist <= max_dist {
-                        return Some(other.id(j));
+                        return Some(j);
                     }
                 }
                 // TODO: for now, ignore complex variants
@@ -276,6 +440,131 @@ impl VariantType {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenotypeMatch {
+    Concordant,
+    PhaseDiscordant,
+    ZygosityDiscordant,
+    AlleleDiscordant,
+    Missing,
+}
+
+impl GenotypeMatch {
+    /// Compare two genotypes. Alleles of the first genotype are translated into the allele
+    /// indices of the second genotype via the given function.
+    pub fn new<F: Fn(i32) -> i32>(
+        genotype: &[GenotypeAllele],
+        other: &[GenotypeAllele],
+        translate: F,
+    ) -> Self {
+        let alleles: Option<Vec<i32>> = genotype
+            .iter()
+            .map(|a| a.index().map(|a| translate(a as i32)))
+            .collect();
+        let other_alleles: Option<Vec<i32>> =
+            other.iter().map(|a| a.index().map(|a| a as i32)).collect();
+        let (alleles, other_alleles) = match (alleles, other_alleles) {
+            (Some(a), Some(b)) if !a.is_empty() && !b.is_empty() => (a, b),
+            _ => return GenotypeMatch::Missing,
+        };
+
+        let sorted = alleles.iter().sorted().collect_vec();
+        let other_sorted = other_alleles.iter().sorted().collect_vec();
+        if sorted == other_sorted {
+            if is_phased(genotype) && is_phased(other) && alleles != other_alleles {
+                GenotypeMatch::PhaseDiscordant
+            } else {
+                GenotypeMatch::Concordant
+            }
+        } else {
+            let alt = |alleles: Vec<&i32>| {
+                alleles
+                    .into_iter()
+                    .filter(|a| **a != 0)
+                    .unique()
+                    .collect_vec()
+            };
+            if alt(sorted) == alt(other_sorted) {
+                GenotypeMatch::ZygosityDiscordant
+            } else {
+                GenotypeMatch::AlleleDiscordant
+            }
+        }
+    }
+}
+
+impl fmt::Display for GenotypeMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            GenotypeMatch::Concordant => "concordant",
+            GenotypeMatch::PhaseDiscordant => "phase_discordant",
+            GenotypeMatch::ZygosityDiscordant => "zygosity_discordant",
+            GenotypeMatch::AlleleDiscordant => "allele_discordant",
+            GenotypeMatch::Missing => "missing",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// A genotype is phased if all alleles except the first are marked as phased
+/// (the first allele is always unphased in BCF).
+fn is_phased(genotype: &[GenotypeAllele]) -> bool {
+    genotype.len() > 1
+        && genotype[1..]
+            .iter()
+            .all(|a| matches!(a, GenotypeAllele::Phased(_) | GenotypeAllele::PhasedMissing))
+}
+
+/// Per-sample counts of genotype match states.
+#[derive(Debug, Clone, Default)]
+pub struct GenotypeConcordance {
+    concordant: u64,
+    phase_discordant: u64,
+    zygosity_discordant: u64,
+    allele_discordant: u64,
+    missing: u64,
+}
+
+impl GenotypeConcordance {
+    pub fn count(&mut self, gt_match: GenotypeMatch) {
+        match gt_match {
+            GenotypeMatch::Concordant => self.concordant += 1,
+            GenotypeMatch::PhaseDiscordant => self.phase_discordant += 1,
+            GenotypeMatch::ZygosityDiscordant => self.zygosity_discordant += 1,
+            GenotypeMatch::AlleleDiscordant => self.allele_discordant += 1,
+            GenotypeMatch::Missing => self.missing += 1,
+        }
+    }
+
+    /// Fraction of concordant genotypes among all non-missing comparisons.
+    pub fn concordance(&self) -> f64 {
+        let called = self.concordant
+            + self.phase_discordant
+            + self.zygosity_discordant
+            + self.allele_discordant;
+        if called == 0 {
+            f64::NAN
+        } else {
+            self.concordant as f64 / called as f64
+        }
+    }
+}
+
+impl fmt::Display for GenotypeConcordance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{:.4}",
+            self.concordant,
+            self.phase_discordant,
+            self.zygosity_discordant,
+            self.allele_discordant,
+            self.missing,
+            self.concordance()
+        )
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum MatchError {
     #[error("missing tag {tag}")]
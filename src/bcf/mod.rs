@@ -1,8 +1,41 @@
 //! Tools that work on VCF and BCF files.
+pub mod alleles;
 pub mod annotate_dgidb;
+pub mod annotate_genes;
+pub mod annotate_intervals;
 pub mod baf;
+pub mod baf_segment;
 pub mod fix_iupac_alleles;
+pub mod genes;
 pub mod match_variants;
+pub mod normalize;
 pub mod report;
 pub mod split;
 pub mod to_txt;
+
+use anyhow::{bail, Result};
+use rust_htslib::bcf::{Format, Header, Writer};
+use std::path::Path;
+
+/// Create a writer for the given path (or STDOUT) and output type, which is one of compressed
+/// BCF (`b`), uncompressed BCF (`u`), compressed VCF (`z`) or uncompressed VCF (`v`).
+pub(crate) fn create_writer<P: AsRef<Path>>(
+    header: &Header,
+    path: Option<P>,
+    output_type: &str,
+) -> Result<Writer> {
+    let (uncompressed, format) = match output_type {
+        "b" => (false, Format::Bcf),
+        "u" => (true, Format::Bcf),
+        "z" => (false, Format::Vcf),
+        "v" => (true, Format::Vcf),
+        _ => bail!(
+            "unknown output type {}, use one of b, u, z or v",
+            output_type
+        ),
+    };
+    Ok(match path {
+        Some(path) => Writer::from_path(path, header, uncompressed, format)?,
+        None => Writer::from_stdout(header, uncompressed, format)?,
+    })
+}
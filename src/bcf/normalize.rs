@@ -0,0 +1,193 @@
+//! Normalize variants by left-aligning and trimming their alleles against a reference genome.
+//!
+//! Optionally, multi-allelic records are split into biallelic ones (with `Number=A`, `Number=R`
+//! and `Number=G` fields as well as genotypes adjusted accordingly) and MNPs are decomposed into
+//! SNVs. Since left-alignment moves variants upstream, records are buffered within a window in
+//! order to keep the output sorted.
+//!
+//! ## Usage:
+//! ```bash
+//! $ rbt vcf-normalize --split-multiallelics --decompose-mnps tests/ref.fa < tests/test-normalize.vcf > tests/normalized.bcf
+//! ```
+//!
+use crate::bcf::alleles::AlleleFields;
+use anyhow::{Context, Result};
+use bio::io::fasta;
+use itertools::Itertools;
+use log::info;
+use rust_htslib::bcf::{self, Format, Read};
+use std::fs;
+use std::path::Path;
+use std::str;
+
+pub fn normalize<P: AsRef<Path> + std::fmt::Debug>(
+    reference: P,
+    split_multiallelics: bool,
+    decompose_mnps: bool,
+    site_window: u64,
+) -> Result<()> {
+    let mut inbcf = bcf::Reader::from_stdin()?;
+    let mut outbcf = bcf::Writer::from_stdout(
+        &bcf::Header::from_template(inbcf.header()),
+        false,
+        Format::Bcf,
+    )?;
+    let mut reference = fasta::IndexedReader::from_file(&reference)
+        .context("error reading reference genome, make sure it is indexed with samtools faidx")?;
+    let allele_fields = AlleleFields::new(inbcf.header());
+
+    let mut buffer: Vec<bcf::Record> = Vec::new();
+    let (mut realigned, mut split, mut decomposed) = (0, 0, 0);
+
+    for res in inbcf.records() {
+        let rec = res?;
+        let rid = rec.rid().context("record without CHROM")?;
+        let chrom = str::from_utf8(outbcf.header().rid2name(rid)?)?.to_owned();
+
+        // flush records that cannot be overtaken by left-aligned upcoming records
+        if buffer
+            .first()
+            .map_or(false, |first| first.rid() != Some(rid))
+        {
+            flush(&mut outbcf, &mut buffer, None)?;
+        }
+        flush(
+            &mut outbcf,
+            &mut buffer,
+            Some((rec.pos() as u64).saturating_sub(site_window)),
+        )?;
+
+        let records = if split_multiallelics && rec.allele_count() > 2 {
+            let alleles = rec.alleles().iter().map(|a| a.to_vec()).collect_vec();
+            split += 1;
+            (1..alleles.len())
+                .map(|i| {
+                    let mut biallelic = rec.clone();
+                    allele_fields.set_alleles(
+                        &mut biallelic,
+                        &[alleles[0].as_slice(), alleles[i].as_slice()],
+                        &[0, i],
+                    )?;
+                    Ok(biallelic)
+                })
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            vec![rec]
+        };
+
+        for mut rec in records {
+            let pos = rec.pos() as u64;
+            let alleles = rec.alleles().iter().map(|a| a.to_vec()).collect_vec();
+            let (normalized_pos, normalized) =
+                normalize_alleles(&mut reference, &chrom, pos, &alleles)?;
+            if normalized_pos != pos || normalized != alleles {
+                realigned += 1;
+                rec.set_pos(normalized_pos as i64);
+                rec.set_alleles(&normalized.iter().map(|a| a.as_slice()).collect_vec())?;
+            }
+
+            if decompose_mnps
+                && normalized.len() == 2
+                && normalized[0].len() > 1
+                && normalized[0].len() == normalized[1].len()
+            {
+                decomposed += 1;
+                for (i, (ref_base, alt_base)) in
+                    normalized[0].iter().zip(normalized[1].iter()).enumerate()
+                {
+                    if ref_base != alt_base {
+                        let mut snv = rec.clone();
+                        snv.set_pos((normalized_pos + i as u64) as i64);
+                        snv.set_alleles(&[&[*ref_base][..], &[*alt_base][..]])?;
+                        buffer.push(snv);
+                    }
+                }
+            } else {
+                buffer.push(rec);
+            }
+        }
+    }
+    flush(&mut outbcf, &mut buffer, None)?;
+
+    info!(
+        "Left-aligned or trimmed {} records, split {} multi-allelic records and decomposed {} MNPs.",
+        realigned, split, decomposed
+    );
+
+    Ok(())
+}
+
+/// Write all buffered records (sorted by position) that start before the given position.
+fn flush(
+    writer: &mut bcf::Writer,
+    buffer: &mut Vec<bcf::Record>,
+    until: Option<u64>,
+) -> Result<()> {
+    buffer.sort_by_key(|rec| rec.pos());
+    let n = until.map_or(buffer.len(), |until| {
+        buffer
+            .iter()
+            .take_while(|rec| (rec.pos() as u64) < until)
+            .count()
+    });
+    for rec in buffer.drain(..n) {
+        writer.write(&rec)?;
+    }
+    Ok(())
+}
+
+/// Trim bases shared by all alleles and left-align them against the reference.
+/// Symbolic alleles, breakends and records with a single allele are left unchanged.
+fn normalize_alleles(
+    reference: &mut fasta::IndexedReader<fs::File>,
+    chrom: &str,
+    pos: u64,
+    alleles: &[Vec<u8>],
+) -> Result<(u64, Vec<Vec<u8>>)> {
+    let is_sequence = |allele: &Vec<u8>| {
+        !allele.is_empty() && allele.iter().all(|base| b"ACGTNacgtn".contains(base))
+    };
+    if alleles.len() < 2
+        || !alleles.iter().all(is_sequence)
+        || alleles.iter().all(|allele| *allele == alleles[0])
+    {
+        return Ok((pos, alleles.to_vec()));
+    }
+
+    let mut pos = pos;
+    let mut alleles = alleles.to_vec();
+    let mut seq = Vec::new();
+    // trim the common last base, extending alleles to the left where they would become empty
+    while alleles
+        .iter()
+        .all(|allele| allele.last() == alleles[0].last())
+    {
+        if alleles.iter().any(|allele| allele.len() == 1) {
+            if pos == 0 {
+                break;
+            }
+            pos -= 1;
+            reference.fetch(chrom, pos, pos + 1)?;
+            reference.read(&mut seq)?;
+            let base = seq.first().context("position beyond end of reference")?;
+            for allele in &mut alleles {
+                allele.insert(0, base.to_ascii_uppercase());
+            }
+        }
+        for allele in &mut alleles {
+            allele.pop();
+        }
+    }
+    // trim the common first base
+    while alleles
+        .iter()
+        .all(|allele| allele.len() >= 2 && allele[0] == alleles[0][0])
+    {
+        for allele in &mut alleles {
+            allele.remove(0);
+        }
+        pos += 1;
+    }
+
+    Ok((pos, alleles))
+}
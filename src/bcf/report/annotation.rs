@@ -0,0 +1,318 @@
+//! Layouts of the functional annotations of a VCF file, e.g. the `CSQ`/`ANN` field of VEP or the
+//! `ANN` field of SnpEff. vcf-report uses a few of their fields by role (gene symbol, impact,
+//! HGVS notations, ...), which are mapped to the field names of the respective tool by an
+//! [`AnnotationSchema`].
+//!
+//! Other annotation tools can be used by a YAML mapping file like this, with all but `allele`
+//! being optional:
+//! ```yaml
+//! allele: Allele
+//! consequence: Annotation
+//! impact: Annotation_Impact
+//! symbol: Gene_Name
+//! gene: Gene_ID
+//! feature: Feature_ID
+//! hgvsp: HGVS.p
+//! existing_variation: Existing_variation
+//! clin_sig: CLIN_SIG
+//! ```
+//! Without an `hgvsg` field, the HGVS genomic notation is derived from the VCF record.
+use anyhow::{anyhow, Context, Result};
+use rust_htslib::bcf::HeaderRecord;
+use serde_derive::Deserialize;
+use std::fs::File;
+use std::str;
+use std::str::FromStr;
+
+/// Field names of the annotation fields used by vcf-report.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AnnotationSchema {
+    pub allele: String,
+    pub consequence: Option<String>,
+    pub impact: Option<String>,
+    pub symbol: Option<String>,
+    pub gene: Option<String>,
+    pub feature: Option<String>,
+    pub hgvsg: Option<String>,
+    pub hgvsp: Option<String>,
+    pub canonical: Option<String>,
+    pub existing_variation: Option<String>,
+    pub clin_sig: Option<String>,
+}
+
+impl AnnotationSchema {
+    /// Schema of VEP run with `--hgvs --hgvsg`.
+    pub fn vep() -> Self {
+        AnnotationSchema {
+            allele: "Allele".to_owned(),
+            consequence: Some("Consequence".to_owned()),
+            impact: Some("IMPACT".to_owned()),
+            symbol: Some("SYMBOL".to_owned()),
+            gene: Some("Gene".to_owned()),
+            feature: Some("Feature".to_owned()),
+            hgvsg: Some("HGVSg".to_owned()),
+            hgvsp: Some("HGVSp".to_owned()),
+            canonical: Some("CANONICAL".to_owned()),
+            existing_variation: Some("Existing_variation".to_owned()),
+            clin_sig: Some("CLIN_SIG".to_owned()),
+        }
+    }
+
+    /// Schema of the `ANN` field of SnpEff, which has no HGVSg.
+    pub fn snpeff() -> Self {
+        AnnotationSchema {
+            allele: "Allele".to_owned(),
+            consequence: Some("Annotation".to_owned()),
+            impact: Some("Annotation_Impact".to_owned()),
+            symbol: Some("Gene_Name".to_owned()),
+            gene: Some("Gene_ID".to_owned()),
+            feature: Some("Feature_ID".to_owned()),
+            hgvsg: None,
+            hgvsp: Some("HGVS.p".to_owned()),
+            canonical: None,
+            existing_variation: None,
+            clin_sig: None,
+        }
+    }
+
+    /// Guess the schema from the description of the annotation field in the VCF header.
+    fn detect(description: &str) -> Self {
+        if description.starts_with("Functional annotations") {
+            Self::snpeff()
+        } else {
+            Self::vep()
+        }
+    }
+}
+
+/// Parse the value of `--annotation-schema`, which is either `vep`, `snpeff` or the path to a
+/// YAML mapping file.
+impl FromStr for AnnotationSchema {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "vep" => Ok(Self::vep()),
+            "snpeff" => Ok(Self::snpeff()),
+            path => serde_yaml::from_reader(
+                File::open(path)
+                    .with_context(|| format!("unable to open annotation schema file {}", path))?,
+            )
+            .with_context(|| format!("invalid annotation schema file {}", path)),
+        }
+    }
+}
+
+/// Positions of the fields of an [`AnnotationSchema`] within the annotation entries of a VCF file.
+#[derive(Debug, Clone)]
+pub(crate) struct AnnotationLayout {
+    /// Names of all fields of an annotation entry.
+    pub(crate) fields: Vec<String>,
+    allele: usize,
+    consequence: Option<usize>,
+    impact: Option<usize>,
+    symbol: Option<usize>,
+    gene: Option<usize>,
+    feature: Option<usize>,
+    hgvsg: Option<usize>,
+    hgvsp: Option<usize>,
+    canonical: Option<usize>,
+    existing_variation: Option<usize>,
+    clin_sig: Option<usize>,
+}
+
+impl AnnotationLayout {
+    /// Read the fields of the annotation INFO field from the VCF header and locate those of the
+    /// given schema, or of the schema detected from the description of the field.
+    pub(crate) fn from_header(
+        header_records: Vec<HeaderRecord>,
+        annotation_field: &str,
+        schema: Option<&AnnotationSchema>,
+    ) -> Result<Self> {
+        let description = header_records
+            .into_iter()
+            .find_map(|rec| match rec {
+                HeaderRecord::Info { values, .. }
+                    if values.get("ID").map(|id| id.as_str()) == Some(annotation_field) =>
+                {
+                    values.get("Description").cloned()
+                }
+                _ => None,
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "Could not find the annotation field {} in the VCF header. Please only use VCF-files annotated by VEP or SnpEff or provide the field via --annotation-field.",
+                    annotation_field
+                )
+            })?;
+        let description = description.trim().trim_matches('"').trim();
+        let fields = parse_fields(description);
+        let schema = schema
+            .cloned()
+            .unwrap_or_else(|| AnnotationSchema::detect(description));
+
+        let index = |name: &str| {
+            fields.iter().position(|f| f == name).with_context(|| {
+                format!(
+                    "No field named {} found in the annotation field {}.",
+                    name, annotation_field
+                )
+            })
+        };
+        let required = |name: &Option<String>| name.as_deref().map(index).transpose();
+        // missing optional VEP fields are left empty, as VEP only adds them on request
+        let optional = |name: &Option<String>| fields.iter().position(|f| Some(f) == name.as_ref());
+        Ok(AnnotationLayout {
+            allele: index(schema.allele.as_str())?,
+            consequence: required(&schema.consequence)?,
+            impact: required(&schema.impact)?,
+            symbol: required(&schema.symbol)?,
+            gene: required(&schema.gene)?,
+            feature: required(&schema.feature)?,
+            hgvsg: required(&schema.hgvsg)?,
+            hgvsp: required(&schema.hgvsp)?,
+            canonical: optional(&schema.canonical),
+            existing_variation: optional(&schema.existing_variation),
+            clin_sig: optional(&schema.clin_sig),
+            fields,
+        })
+    }
+
+    fn get<'a>(entry: &[&'a [u8]], index: Option<usize>) -> Result<&'a str> {
+        match index.and_then(|i| entry.get(i)) {
+            Some(value) => Ok(str::from_utf8(value)?),
+            None => Ok(""),
+        }
+    }
+
+    pub(crate) fn allele<'a>(&self, entry: &[&'a [u8]]) -> Result<&'a str> {
+        Self::get(entry, Some(self.allele))
+    }
+
+    pub(crate) fn consequence<'a>(&self, entry: &[&'a [u8]]) -> Result<&'a str> {
+        Self::get(entry, self.consequence)
+    }
+
+    pub(crate) fn impact<'a>(&self, entry: &[&'a [u8]]) -> Result<&'a str> {
+        Self::get(entry, self.impact)
+    }
+
+    pub(crate) fn symbol<'a>(&self, entry: &[&'a [u8]]) -> Result<&'a str> {
+        Self::get(entry, self.symbol)
+    }
+
+    pub(crate) fn gene<'a>(&self, entry: &[&'a [u8]]) -> Result<&'a str> {
+        Self::get(entry, self.gene)
+    }
+
+    pub(crate) fn feature<'a>(&self, entry: &[&'a [u8]]) -> Result<&'a str> {
+        Self::get(entry, self.feature)
+    }
+
+    pub(crate) fn hgvsp<'a>(&self, entry: &[&'a [u8]]) -> Result<&'a str> {
+        Self::get(entry, self.hgvsp)
+    }
+
+    pub(crate) fn existing_variation<'a>(&self, entry: &[&'a [u8]]) -> Result<&'a str> {
+        Self::get(entry, self.existing_variation)
+    }
+
+    pub(crate) fn clin_sig<'a>(&self, entry: &[&'a [u8]]) -> Result<&'a str> {
+        Self::get(entry, self.clin_sig)
+    }
+
+    pub(crate) fn is_canonical(&self, entry: &[&[u8]]) -> Result<bool> {
+        Ok(Self::get(entry, self.canonical)? == "YES")
+    }
+
+    pub(crate) fn has_clin_sig(&self) -> bool {
+        self.clin_sig.is_some()
+    }
+
+    /// Whether the entries name their gene, by symbol or ID.
+    pub(crate) fn has_gene(&self) -> bool {
+        self.symbol.is_some() || self.gene.is_some()
+    }
+
+    /// HGVS genomic notation of an annotation entry. Schemas without such field get it derived
+    /// from the VCF record and the ALT allele of the entry.
+    pub(crate) fn hgvsg(&self, entry: &[&[u8]], record: &RecordAlleles) -> Result<String> {
+        if self.hgvsg.is_some() {
+            return Ok(Self::get(entry, self.hgvsg)?.to_owned());
+        }
+        let allele = self.allele(entry)?;
+        let alt = match record
+            .alts
+            .iter()
+            .find(|alt| alt.as_slice() == allele.as_bytes())
+        {
+            Some(alt) => alt,
+            None if record.alts.len() == 1 => &record.alts[0],
+            None => return Ok(String::new()),
+        };
+        Ok(derive_hgvsg(record, alt))
+    }
+}
+
+/// The position and alleles of a VCF record, from which HGVSg notations are derived.
+#[derive(Debug, Clone)]
+pub(crate) struct RecordAlleles {
+    pub(crate) chrom: String,
+    /// 0-based position.
+    pub(crate) pos: i64,
+    /// 1-based inclusive end position of symbolic alleles, given by the END tag.
+    pub(crate) end: Option<i64>,
+    pub(crate) reference: Vec<u8>,
+    pub(crate) alts: Vec<Vec<u8>>,
+}
+
+/// Split the description of an annotation field into its field names, e.g. `Consequence
+/// annotations from Ensembl VEP. Format: Allele|Consequence|...` or `Functional annotations:
+/// 'Allele | Annotation | ...'`.
+pub(crate) fn parse_fields(description: &str) -> Vec<String> {
+    let description = match description.split('|').next() {
+        Some(first) => match first.rfind(": ") {
+            Some(start) => &description[start + 2..],
+            None => description,
+        },
+        None => description,
+    };
+    description
+        .split('|')
+        .map(|field| field.trim().trim_matches('\'').trim().to_owned())
+        .collect()
+}
+
+fn derive_hgvsg(record: &RecordAlleles, alt: &[u8]) -> String {
+    let reference = String::from_utf8_lossy(&record.reference);
+    let alt_str = String::from_utf8_lossy(alt);
+    let pos = record.pos + 1;
+    let end = pos + record.reference.len() as i64 - 1;
+    let change = if alt.starts_with(b"<") {
+        let end = record.end.unwrap_or(end);
+        format!(
+            "{}_{}{}",
+            pos,
+            end,
+            alt_str
+                .trim_matches(|c| c == '<' || c == '>')
+                .to_lowercase()
+        )
+    } else if reference.len() == 1 && alt.len() == 1 {
+        format!("{}{}>{}", pos, reference, alt_str)
+    } else if alt.len() == 1 && record.reference.starts_with(alt) {
+        if end == pos + 1 {
+            format!("{}del", end)
+        } else {
+            format!("{}_{}del", pos + 1, end)
+        }
+    } else if reference.len() == 1 && alt.starts_with(&record.reference) {
+        format!("{}_{}ins{}", pos, pos + 1, &alt_str[1..])
+    } else if pos == end {
+        format!("{}delins{}", pos, alt_str)
+    } else {
+        format!("{}_{}delins{}", pos, end, alt_str)
+    };
+    format!("{}:g.{}", record.chrom, change)
+}
@@ -45,7 +45,7 @@ vegaEmbed('#oncoprint', spec).then(function(result) {
         if (item.datum.sample !== undefined  && item.datum.alteration !== undefined) {
             let loc = item.datum.dna_alt;
             loc = loc.replace(/\./g, "_").replace(/>/g, "_").replace(/:/g, "_");
-            window.location.href = '../details/' + item.datum.sample + '/' + loc + '.html';
+            openPage('../details/' + item.datum.sample + '/' + loc + '.html');
         }
     });
 });
@@ -74,8 +74,17 @@ window.addEventListener('resize', function(event){
             if (item.datum.sample !== undefined  && item.datum.alteration !== undefined) {
                 let loc = item.datum.dna_alt;
                 loc = loc.replace(/\./g, "_").replace(/>/g, "_").replace(/:/g, "_");
-                window.location.href = '../details/' + item.datum.sample + '/' + loc + '.html';
+                openPage('../details/' + item.datum.sample + '/' + loc + '.html');
             }
         });
     });
 });
+
+// Pages of single-file reports are shown within the bundle, which takes care of the navigation.
+function openPage(url) {
+    if (window.rbtOpen !== undefined) {
+        rbtOpen(url);
+    } else {
+        window.location.href = url;
+    }
+}
@@ -54,12 +54,12 @@ vegaEmbed('#oncoprint', spec).then(function(result) {
         if (item.datum.gene !== undefined || item.datum.key !== undefined) {
             if (item.datum.gene !== undefined ) {
                 if (item.datum.gene.startsWith("ENST") && item.datum.sample !== undefined) {
-                    window.location.href = '../details/' + item.datum.sample + '/' + item.datum.gene + '.html';
+                    openPage('../details/' + item.datum.sample + '/' + item.datum.gene + '.html');
                 } else {
-                    window.location.href = '../genes/' + item.datum.gene + '1.html';
+                    openPage('../genes/' + item.datum.gene + '1.html');
                 }
             } else {
-                window.location.href = '../genes/' + item.datum.key + '1.html';
+                openPage('../genes/' + item.datum.key + '1.html');
             }
 
         }
@@ -90,15 +90,24 @@ window.addEventListener('resize', function(event) {
             if (item.datum.gene !== undefined || item.datum.key !== undefined) {
                 if (item.datum.gene !== undefined ) {
                     if (item.datum.gene.startsWith("ENST") && item.datum.sample !== undefined) {
-                        window.location.href = '../details/' + item.datum.sample + '/' + item.datum.gene + '.html';
+                        openPage('../details/' + item.datum.sample + '/' + item.datum.gene + '.html');
                     } else {
-                        window.location.href = '../genes/' + item.datum.gene + '1.html';
+                        openPage('../genes/' + item.datum.gene + '1.html');
                     }
                 } else {
-                    window.location.href = '../genes/' + item.datum.key + '1.html';
+                    openPage('../genes/' + item.datum.key + '1.html');
                 }
 
             }
         });
     });
 });
+
+// Pages of single-file reports are shown within the bundle, which takes care of the navigation.
+function openPage(url) {
+    if (window.rbtOpen !== undefined) {
+        rbtOpen(url);
+    } else {
+        window.location.href = url;
+    }
+}
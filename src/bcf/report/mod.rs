@@ -1,12 +1,20 @@
 use crate::bcf::report::oncoprint::WriteErr;
 use anyhow::{Context, Result};
 use itertools::Itertools;
+use lz_str::compress_to_utf16;
+use rayon::prelude::*;
+use serde_json::json;
+use std::collections::BTreeMap;
 use std::fs;
 use std::fs::File;
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use tera::{Context as TeraContext, Tera};
 
+pub mod annotation;
 pub mod oncoprint;
+pub mod selection;
 pub mod table_report;
 
 pub fn embed_js(
@@ -115,3 +123,104 @@ pub fn embed_html(output_path: &str) -> Result<()> {
     }
     Ok(())
 }
+
+/// Create the directory the report files are written to, which is a temporary directory for
+/// single-file reports that are then bundled by `embed_single_file`. The returned temporary
+/// directory is removed when dropped and has to be kept until the report is bundled.
+pub fn report_dir(single_file: bool, output_path: &str) -> Result<(Option<TempDir>, String)> {
+    if single_file {
+        let dir = tempfile::tempdir()?;
+        let report_path = dir.path().to_string_lossy().into_owned();
+        return Ok((Some(dir), report_path));
+    }
+    if !Path::new(output_path).exists() {
+        fs::create_dir_all(output_path).context(format!(
+            "Couldn't create output directory at {}.",
+            output_path
+        ))?;
+    }
+    Ok((None, output_path.to_owned()))
+}
+
+/// Bundle all files of the report at `report_path` into the single HTML file `output_path`
+/// (or `report.html` within it, if it is a directory). Each file is compressed on its own
+/// and only decompressed by the browser once a page showing it is opened, such that even
+/// very large reports open quickly.
+pub fn embed_single_file(report_path: &str, output_path: &str) -> Result<()> {
+    let root = Path::new(report_path);
+    let mut paths = Vec::new();
+    collect_files(root, &mut paths)?;
+    let files = paths
+        .par_iter()
+        .map(|path| -> Result<(String, String, bool)> {
+            let name = path
+                .strip_prefix(root)?
+                .iter()
+                .map(|component| component.to_string_lossy())
+                .join("/");
+            let (content, binary) = match String::from_utf8(fs::read(path)?) {
+                Ok(content) => (content, false),
+                // binary files like the XLSX export are stored with one character per byte
+                Err(e) => (e.into_bytes().iter().map(|b| *b as char).collect(), true),
+            };
+            Ok((name, compress_to_utf16(&content), binary))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let binary = files
+        .iter()
+        .filter(|(_, _, binary)| *binary)
+        .map(|(name, _, _)| name)
+        .collect_vec();
+    let files: BTreeMap<_, _> = files
+        .iter()
+        .map(|(name, content, _)| (name, content))
+        .collect();
+
+    let mut templates = Tera::default();
+    templates.add_raw_template(
+        "single_file.html.tera",
+        include_str!("html/single_file.html.tera"),
+    )?;
+    let mut context = TeraContext::new();
+    context.insert("lz_string", include_str!("js/lz-string.min.js"));
+    context.insert("files", &script_json(&json!(files).to_string()));
+    context.insert("binary", &script_json(&json!(binary).to_string()));
+    context.insert("start", "index.html");
+    let html = templates.render("single_file.html.tera", &context)?;
+
+    let mut out_path = PathBuf::from(output_path);
+    if out_path.is_dir() {
+        out_path.push("report.html");
+    }
+    if let Some(parent) = out_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).context(format!(
+            "Unable to create directory {} for the single-file report",
+            parent.display()
+        ))?;
+    }
+    let mut out_file = File::create(&out_path).context(format!(
+        "Unable to create single-file report at {}",
+        out_path.display()
+    ))?;
+    out_file.write_all(html.as_bytes())?;
+    Ok(())
+}
+
+fn collect_files(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, paths)?;
+        } else {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Make JSON safe to be placed within a script tag.
+fn script_json(json: &str) -> String {
+    json.replace("</", "<\\/")
+        .replace('\u{2028}', "\\u2028")
+        .replace('\u{2029}', "\\u2029")
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
+use std::fs;
 use std::io::Write;
-use std::{fs, str};
 
 use derive_new::new;
 use itertools::Itertools;
@@ -9,7 +9,8 @@ use regex::Regex;
 use serde_derive::Serialize;
 use tera::{self, Context, Tera};
 
-use crate::bcf::report::table_report::create_report_table::get_ann_description;
+use crate::bcf::report::annotation::{AnnotationLayout, AnnotationSchema, RecordAlleles};
+use crate::bcf::report::selection::RecordSelection;
 use crate::bcf::report::table_report::create_report_table::read_tag_entries;
 use anyhow::Context as AnyhowContext;
 use anyhow::Result;
@@ -29,6 +30,7 @@ lazy_static! {
     static ref HGVSP_PROTEIN_RE: Regex = Regex::new(r"ENSP[0-9]+(\.[0-9]+)?:").unwrap();
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn oncoprint(
     sample_calls: &HashMap<String, String>,
     output_path: &str,
@@ -36,6 +38,8 @@ pub fn oncoprint(
     tsv_data_path: Option<&str>,
     plot_info: Option<Vec<String>>,
     annotation_field: &str,
+    annotation_schema: Option<&AnnotationSchema>,
+    selection: &RecordSelection,
 ) -> Result<()> {
     let mut data = HashMap::new();
     let mut gene_data = HashMap::new();
@@ -65,11 +69,9 @@ pub fn oncoprint(
     for (sample, path) in sample_calls.iter().sorted() {
         let bcf_reader = bcf::Reader::from_path(path)?;
         let header_records = bcf_reader.header().header_records();
-        let ann_fields: Vec<_> = get_ann_description(header_records, annotation_field)?;
-        clin_sig_present.insert(
-            sample.to_owned(),
-            ann_fields.contains(&"CLIN_SIG".to_owned()),
-        );
+        let layout =
+            AnnotationLayout::from_header(header_records, annotation_field, annotation_schema)?;
+        clin_sig_present.insert(sample.to_owned(), layout.has_clin_sig());
     }
 
     // Check wether any of the VCF files contain CLIN_SIG at all
@@ -85,26 +87,22 @@ pub fn oncoprint(
         let mut gene_consequences = HashMap::new();
         let mut clin_sigs = HashMap::new();
         let mut gene_clin_sigs = HashMap::new();
-        let mut ann_indices = HashMap::new();
         let mut pi_data = HashMap::new();
         let mut gene_pi_data = HashMap::new();
 
-        let mut bcf_reader = bcf::Reader::from_path(path)?;
+        let bcf_reader = bcf::Reader::from_path(path)?;
         let header = bcf_reader.header().clone();
         let mut sample_names = Vec::new();
         for s in header.samples() {
             sample_names.push(String::from_utf8(s.to_owned())?);
         }
         let header_records = header.header_records();
-        let ann_fields: Vec<_> = get_ann_description(header_records, annotation_field)?;
-
-        for (i, field) in ann_fields.iter().enumerate() {
-            ann_indices.insert(field, i);
-        }
+        let layout =
+            AnnotationLayout::from_header(header_records, annotation_field, annotation_schema)?;
 
         let clin_sig_pres = clin_sig_present.get(sample).unwrap();
 
-        for res in bcf_reader.records() {
+        for res in selection.records(Path::new(path), &layout, annotation_field)? {
             let mut gene_data_per_record = HashMap::new();
             let mut record = res?;
             let pos = record.pos();
@@ -130,6 +128,19 @@ pub fn oncoprint(
                 .map(|s| s.to_vec())
                 .collect_vec();
 
+            let record_alleles = RecordAlleles {
+                chrom: String::from_utf8(header.rid2name(record.rid().unwrap())?.to_owned())?,
+                pos,
+                end: record
+                    .info(b"END")
+                    .integer()
+                    .ok()
+                    .flatten()
+                    .map(|end| end[0] as i64),
+                reference: ref_allele.clone(),
+                alts: alt_alleles.to_vec(),
+            };
+
             let ann = record.info(annotation_field.as_bytes()).string()?;
             if let Some(ann) = ann {
                 for alt_allele in alt_alleles {
@@ -157,40 +168,33 @@ pub fn oncoprint(
 
                     for entry in ann.iter() {
                         let fields: Vec<_> = entry.split(|c| *c == b'|').collect();
+                        if !selection.contains_gene(&layout, &fields)? {
+                            continue;
+                        }
 
-                        let get_field = |field: &str| {
-                            str::from_utf8(
-                                fields[*ann_indices.get(&field.to_owned()).unwrap_or_else(|| panic!("No field named {} found. Please only use VEP-annotated VCF-files.", field))],
-                            )
-                        };
-
-                        let mut impact = get_field("IMPACT")?;
+                        let mut impact = layout.impact(&fields)?;
                         let clin_sig = if *clin_sig_pres {
-                            get_field("CLIN_SIG")?
+                            layout.clin_sig(&fields)?
                         } else {
                             ""
                         };
-                        let gene = if !get_field("SYMBOL")?.is_empty() {
-                            get_field("SYMBOL")?
-                        } else if !get_field("Gene")?.is_empty() {
-                            get_field("Gene")?
-                        } else if !get_field("Feature")?.is_empty() {
-                            get_field("Feature")?
-                        } else if !get_field("HGVSg")?.is_empty() {
-                            get_field("HGVSg")?
+                        let hgvsg = layout.hgvsg(&fields, &record_alleles)?;
+                        let gene = if !layout.symbol(&fields)?.is_empty() {
+                            layout.symbol(&fields)?
+                        } else if !layout.gene(&fields)?.is_empty() {
+                            layout.gene(&fields)?
+                        } else if !layout.feature(&fields)?.is_empty() {
+                            layout.feature(&fields)?
+                        } else if !hgvsg.is_empty() {
+                            hgvsg.as_str()
                         } else {
                             continue;
                         };
-                        let dna_alteration = get_field("HGVSg")?;
-                        let canonical =
-                            if let Some(index) = ann_indices.get(&"CANONICAL".to_owned()) {
-                                str::from_utf8(fields[*index])? == "YES"
-                            } else {
-                                false
-                            };
-                        let protein_alteration = get_field("HGVSp")?;
-                        let consequence = get_field("Consequence")?;
-                        let existing_var = get_field("Existing_variation")?;
+                        let dna_alteration = hgvsg.as_str();
+                        let canonical = layout.is_canonical(&fields)?;
+                        let protein_alteration = layout.hgvsp(&fields)?;
+                        let consequence = layout.consequence(&fields)?;
+                        let existing_var = layout.existing_variation(&fields)?;
 
                         let gene_rec = unique_genes.entry(gene.to_owned()).or_insert_with(Vec::new);
                         gene_rec.push(sample.to_owned());
@@ -0,0 +1,239 @@
+//! Restriction of vcf-report to the records within given regions and annotated for given genes.
+use crate::bcf::report::annotation::AnnotationLayout;
+use crate::common::Region;
+use anyhow::{bail, Context, Result};
+use bio::io::bed;
+use itertools::Itertools;
+use log::warn;
+use rust_htslib::bcf::{self, Read};
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::path::Path;
+
+/// Regions and genes the records of a report are restricted to. Without regions or genes, all
+/// records are selected.
+#[derive(Debug, Clone, Default)]
+pub struct RecordSelection {
+    regions: Vec<Region>,
+    genes: HashSet<String>,
+}
+
+impl RecordSelection {
+    /// Build the selection from BED files (by extension `.bed`) or 1-based inclusive region
+    /// strings (`CHROM:START-END`) and from gene names or files with one gene name per line.
+    pub fn new(regions: &[String], genes: &[String]) -> Result<Self> {
+        let mut selection = RecordSelection::default();
+        for region in regions {
+            if region.ends_with(".bed") {
+                let mut reader = bed::Reader::from_file(region)
+                    .with_context(|| format!("error reading BED file {}", region))?;
+                for result in reader.records() {
+                    let record = result?;
+                    selection.regions.push(Region {
+                        target: record.chrom().to_owned(),
+                        start: record.start(),
+                        end: record.end(),
+                    });
+                }
+            } else {
+                let mut parsed: Region = region
+                    .parse()
+                    .with_context(|| format!("invalid region {}", region))?;
+                parsed.start = parsed.start.saturating_sub(1);
+                selection.regions.push(parsed);
+            }
+        }
+        for gene in genes {
+            if Path::new(gene).is_file() {
+                let content = fs::read_to_string(gene)
+                    .with_context(|| format!("error reading gene list {}", gene))?;
+                selection.genes.extend(
+                    content
+                        .lines()
+                        .map(|line| line.trim())
+                        .filter(|line| !line.is_empty())
+                        .map(|line| line.to_owned()),
+                );
+            } else {
+                selection.genes.insert(gene.to_owned());
+            }
+        }
+        Ok(selection)
+    }
+
+    /// Whether an annotation entry belongs to one of the selected genes, by its gene symbol or ID.
+    pub(crate) fn contains_gene(&self, layout: &AnnotationLayout, entry: &[&[u8]]) -> Result<bool> {
+        Ok(self.genes.is_empty()
+            || self.genes.contains(layout.symbol(entry)?)
+            || self.genes.contains(layout.gene(entry)?))
+    }
+
+    /// Records of the VCF/BCF file within the selected regions and with an annotation of one of
+    /// the selected genes. Regions are fetched via the index of the file, if there is one.
+    pub(crate) fn records(
+        &self,
+        path: &Path,
+        layout: &AnnotationLayout,
+        annotation_field: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<bcf::Record>>>> {
+        if !self.genes.is_empty() && !layout.has_gene() {
+            bail!(
+                "Genes can only be selected if the annotation field {} has a gene symbol or ID.",
+                annotation_field
+            );
+        }
+        let records: Box<dyn Iterator<Item = Result<bcf::Record>>> = if self.regions.is_empty() {
+            Box::new(read_all(bcf::Reader::from_path(path)?))
+        } else {
+            match bcf::IndexedReader::from_path(path) {
+                Ok(reader) => {
+                    let regions = merge_regions(reader.header(), &self.regions);
+                    Box::new(RegionRecords {
+                        reader,
+                        regions,
+                        current: None,
+                        previous: None,
+                    })
+                }
+                Err(_) => {
+                    warn!(
+                        "No index found for {}, reading all records to select those within the regions.",
+                        path.display()
+                    );
+                    let reader = bcf::Reader::from_path(path)?;
+                    let regions = merge_regions(reader.header(), &self.regions);
+                    Box::new(read_all(reader).filter(move |record| match record {
+                        Ok(record) => regions.iter().any(|(rid, start, end)| {
+                            record.rid() == Some(*rid)
+                                && (record.pos() as u64) < *end
+                                && (record.end() as u64) > *start
+                        }),
+                        Err(_) => true,
+                    }))
+                }
+            }
+        };
+        if self.genes.is_empty() {
+            return Ok(records);
+        }
+        let selection = self.clone();
+        let layout = layout.clone();
+        let annotation_field = annotation_field.to_owned();
+        Ok(Box::new(records.filter_map(move |record| match record {
+            Ok(record) => {
+                match selection.has_gene_annotation(&record, &layout, &annotation_field) {
+                    Ok(true) => Some(Ok(record)),
+                    Ok(false) => None,
+                    Err(e) => Some(Err(e)),
+                }
+            }
+            Err(e) => Some(Err(e)),
+        })))
+    }
+
+    fn has_gene_annotation(
+        &self,
+        record: &bcf::Record,
+        layout: &AnnotationLayout,
+        annotation_field: &str,
+    ) -> Result<bool> {
+        if let Some(ann) = record.info(annotation_field.as_bytes()).string()? {
+            for entry in ann.iter() {
+                let fields = entry.split(|c| *c == b'|').collect_vec();
+                if self.contains_gene(layout, &fields)? {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+}
+
+fn read_all<R: Read>(mut reader: R) -> impl Iterator<Item = Result<bcf::Record>> {
+    std::iter::from_fn(move || {
+        let mut record = reader.empty_record();
+        match reader.read(&mut record) {
+            Some(Ok(())) => Some(Ok(record)),
+            Some(Err(e)) => Some(Err(e.into())),
+            None => None,
+        }
+    })
+}
+
+/// Sort the regions of contigs in the header and merge overlapping ones, such that records
+/// are fetched only once.
+fn merge_regions(
+    header: &bcf::header::HeaderView,
+    regions: &[Region],
+) -> VecDeque<(u32, u64, u64)> {
+    let mut merged: VecDeque<(u32, u64, u64)> = VecDeque::new();
+    let sorted = regions
+        .iter()
+        .filter_map(|region| match header.name2rid(region.target.as_bytes()) {
+            Ok(rid) => Some((rid, region.start, region.end)),
+            Err(_) => {
+                warn!(
+                    "Skipping region {}:{}-{}: contig not found in the VCF header.",
+                    region.target,
+                    region.start + 1,
+                    region.end
+                );
+                None
+            }
+        })
+        .sorted();
+    for (rid, start, end) in sorted {
+        match merged.back_mut() {
+            Some(last) if last.0 == rid && start <= last.2 => last.2 = last.2.max(end),
+            _ => merged.push_back((rid, start, end)),
+        }
+    }
+    merged
+}
+
+/// Records of an indexed VCF/BCF file fetched region by region.
+struct RegionRecords {
+    reader: bcf::IndexedReader,
+    regions: VecDeque<(u32, u64, u64)>,
+    /// Contig and end of the region whose records are read.
+    current: Option<(u32, u64)>,
+    /// Contig and end of the region read before the current one.
+    previous: Option<(u32, u64)>,
+}
+
+impl Iterator for RegionRecords {
+    type Item = Result<bcf::Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((_, end)) = self.current {
+                let mut record = self.reader.empty_record();
+                match self.reader.read(&mut record) {
+                    Some(Ok(())) => {
+                        let pos = record.pos() as u64;
+                        // records spanning several regions were already returned for the previous one
+                        let seen = match self.previous {
+                            Some((rid, previous_end)) => {
+                                record.rid() == Some(rid) && pos < previous_end
+                            }
+                            None => false,
+                        };
+                        if !seen && pos < end {
+                            return Some(Ok(record));
+                        }
+                        continue;
+                    }
+                    Some(Err(e)) => return Some(Err(e.into())),
+                    None => {
+                        self.previous = self.current.take();
+                    }
+                }
+            }
+            let (rid, start, end) = self.regions.pop_front()?;
+            if let Err(e) = self.reader.fetch(rid, start, Some(end)) {
+                return Some(Err(e.into()));
+            }
+            self.current = Some((rid, end));
+        }
+    }
+}
@@ -3,10 +3,12 @@ extern crate rust_htslib;
 use self::rust_htslib::bam::FetchDefinition;
 use crate::bcf::report::table_report::fasta_reader::read_fasta;
 use crate::common::Region;
-use anyhow::Result;
-use rust_htslib::bam::record::CigarStringView;
+use anyhow::{Context, Result};
+use rust_htslib::bam::record::{Aux, Cigar, CigarStringView};
+use rust_htslib::bam::HeaderView;
 use rust_htslib::{bam, bam::Read};
 use serde::Serialize;
+use std::collections::HashMap;
 use std::path::Path;
 
 #[derive(Serialize, Clone, Debug, PartialEq)]
@@ -20,6 +22,8 @@ pub enum Marker {
     Insertion,
     Match,
     Pairing,
+    SoftClip,
+    Split,
 }
 
 #[derive(Clone, Debug)]
@@ -35,6 +39,107 @@ pub struct Alignment {
     tid: i32,
     mate_tid: i32,
     mapq: u8,
+    insert_size: i64,
+    /// Values of the aux tags needed for grouping or colouring reads.
+    tags: HashMap<String, String>,
+    /// Raw SA tag listing the other segments of a split read.
+    supplementary: Option<String>,
+    split_segments: Vec<SplitSegment>,
+    /// Unexpected orientation (FF, RR or RF) or mate contig of a discordant pair.
+    discordance: Option<String>,
+}
+
+/// Another alignment of a split read as listed in the SA tag, with `tid` -1 if its contig is not
+/// in the BAM header.
+#[derive(Clone, Debug)]
+struct SplitSegment {
+    tid: i32,
+    pos: i64,
+}
+
+impl Alignment {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn mapq(&self) -> u8 {
+        self.mapq
+    }
+
+    pub fn insert_size(&self) -> i64 {
+        self.insert_size
+    }
+
+    pub fn is_reverse(&self) -> bool {
+        self.flags.contains(&0x10)
+    }
+
+    pub fn tag(&self, tag: &str) -> Option<&str> {
+        self.tags.get(tag).map(|v| v.as_str())
+    }
+
+    pub fn discordance(&self) -> Option<&str> {
+        self.discordance.as_deref()
+    }
+
+    /// Start and end on the reference used to place the read into a row, covering its mate if
+    /// on the same contig, leading soft clips and the segments of a split read.
+    fn read_span(&self) -> (i64, i64) {
+        let (start, end) = if self.paired && self.tid == self.mate_tid {
+            if self.pos < self.mate_pos {
+                (self.pos, self.mate_pos + 100)
+            } else {
+                (self.mate_pos, self.pos + self.length as i64)
+            }
+        } else {
+            (self.pos, self.pos + self.length as i64)
+        };
+        let leading_clip = match self.cigar.iter().next() {
+            Some(Cigar::SoftClip(len)) => *len as i64,
+            _ => 0,
+        };
+        let (start, end) = self
+            .split_segments
+            .iter()
+            .filter(|segment| segment.tid == self.tid)
+            .fold((start - leading_clip, end), |(start, end), segment| {
+                (
+                    start.min(segment.pos),
+                    end.max(segment.pos + self.length as i64),
+                )
+            });
+        (start.max(0), end)
+    }
+
+    /// The base of the read aligned to the given 0-based reference position, or `-` if the
+    /// position is deleted in the read.
+    pub fn base_at(&self, position: i64) -> Option<char> {
+        let sequence = self.sequence.as_bytes();
+        let mut ref_pos = self.pos;
+        let mut read_offset = 0;
+        for c in self.cigar.iter() {
+            match c {
+                Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => {
+                    if position >= ref_pos && position < ref_pos + *len as i64 {
+                        return sequence
+                            .get(read_offset + (position - ref_pos) as usize)
+                            .map(|b| char::from(*b));
+                    }
+                    ref_pos += *len as i64;
+                    read_offset += *len as usize;
+                }
+                Cigar::Del(len) | Cigar::RefSkip(len) => {
+                    if position >= ref_pos && position < ref_pos + *len as i64 {
+                        return Some('-');
+                    }
+                    ref_pos += *len as i64;
+                }
+                Cigar::Ins(len) | Cigar::SoftClip(len) => read_offset += *len as usize,
+                Cigar::HardClip(_) | Cigar::Pad(_) => {}
+            }
+        }
+        None
+    }
 }
 
 #[derive(Serialize, Clone, Debug, PartialEq)]
@@ -62,6 +167,10 @@ pub struct AlignmentMatch {
     pub read_end: u32,
     pub mapq: u8,
     pub cigar: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub supplementary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discordance: Option<String>,
 }
 
 pub fn decode_flags(code: u16) -> Vec<u16> {
@@ -79,26 +188,35 @@ pub fn decode_flags(code: u16) -> Vec<u16> {
     read_map
 }
 
-pub fn read_indexed_bam<P: AsRef<Path>>(path: P, region: &Region) -> Result<Vec<Alignment>> {
-    let mut bam = bam::IndexedReader::from_path(&path)?;
+/// Read the alignments of the given region from an opened BAM file, such that the file can be
+/// reused for multiple regions. The values of the given aux tags are kept with each alignment.
+pub fn read_indexed_bam(
+    bam: &mut bam::IndexedReader,
+    region: &Region,
+    tags: &[String],
+) -> Result<Vec<Alignment>> {
     let chrom = &region.target;
     let (from, to) = (region.start, region.end);
-    let tid = bam.header().tid(chrom.as_bytes()).unwrap() as i32;
+    let tid = bam
+        .header()
+        .tid(chrom.as_bytes())
+        .with_context(|| format!("contig {} not found in BAM header", chrom))? as i32;
 
     let mut alignments: Vec<Alignment> = Vec::new();
 
+    let header = bam.header().clone();
     bam.fetch(FetchDefinition::Region(tid, from as i64, to as i64))?;
 
     for r in bam.records() {
         let rec = r?;
-        let a = make_alignment(&rec);
+        let a = make_alignment(&rec, tags, &header);
         alignments.push(a);
     }
 
     Ok(alignments)
 }
 
-fn make_alignment(record: &bam::Record) -> Alignment {
+fn make_alignment(record: &bam::Record, tags: &[String], header: &HeaderView) -> Alignment {
     let has_pair = record.is_paired();
 
     let mate_pos = record.mpos();
@@ -127,6 +245,39 @@ fn make_alignment(record: &bam::Record) -> Alignment {
     let n = record.qname().to_owned();
     let name = String::from_utf8(n).unwrap();
 
+    //Split read segments
+    let supplementary = match record.aux(b"SA") {
+        Ok(Aux::String(sa)) => Some(sa.trim_end_matches(';').to_owned()),
+        _ => None,
+    };
+    let split_segments = supplementary
+        .as_deref()
+        .map(|sa| parse_split_segments(sa, header))
+        .unwrap_or_default();
+
+    //Discordant pairs
+    let discordance = if !has_pair || record.is_unmapped() || record.is_mate_unmapped() || mtid < 0
+    {
+        None
+    } else if tid != mtid {
+        Some(format!(
+            "mate on {}",
+            String::from_utf8_lossy(header.tid2name(mtid as u32))
+        ))
+    } else {
+        let (left_reverse, right_reverse) = if pos <= mate_pos {
+            (record.is_reverse(), record.is_mate_reverse())
+        } else {
+            (record.is_mate_reverse(), record.is_reverse())
+        };
+        match (left_reverse, right_reverse) {
+            (false, true) => None,
+            (false, false) => Some("FF".to_owned()),
+            (true, true) => Some("RR".to_owned()),
+            (true, false) => Some("RF".to_owned()),
+        }
+    };
+
     Alignment {
         sequence: sequenz,
         pos,
@@ -139,6 +290,51 @@ fn make_alignment(record: &bam::Record) -> Alignment {
         tid,
         mate_tid: mtid,
         mapq: record.mapq(),
+        insert_size: record.insert_size(),
+        tags: tags
+            .iter()
+            .filter_map(|tag| {
+                record
+                    .aux(tag.as_bytes())
+                    .ok()
+                    .and_then(aux_to_string)
+                    .map(|value| (tag.to_owned(), value))
+            })
+            .collect(),
+        supplementary,
+        split_segments,
+        discordance,
+    }
+}
+
+/// Parse the segments of an SA tag (`chrom,pos,strand,CIGAR,mapQ,NM;...`).
+fn parse_split_segments(sa: &str, header: &HeaderView) -> Vec<SplitSegment> {
+    sa.split(';')
+        .filter_map(|segment| {
+            let mut fields = segment.split(',');
+            let chrom = fields.next()?;
+            let pos: i64 = fields.next()?.parse().ok()?;
+            Some(SplitSegment {
+                tid: header.tid(chrom.as_bytes()).map_or(-1, |tid| tid as i32),
+                pos: pos - 1,
+            })
+        })
+        .collect()
+}
+
+fn aux_to_string(aux: Aux) -> Option<String> {
+    match aux {
+        Aux::Char(c) => Some(char::from(c).to_string()),
+        Aux::I8(v) => Some(v.to_string()),
+        Aux::U8(v) => Some(v.to_string()),
+        Aux::I16(v) => Some(v.to_string()),
+        Aux::U16(v) => Some(v.to_string()),
+        Aux::I32(v) => Some(v.to_string()),
+        Aux::U32(v) => Some(v.to_string()),
+        Aux::Float(v) => Some(v.to_string()),
+        Aux::Double(v) => Some(v.to_string()),
+        Aux::String(v) | Aux::HexByteArray(v) => Some(v.to_owned()),
+        _ => None,
     }
 }
 
@@ -146,6 +342,7 @@ pub fn make_nucleobases<P: AsRef<Path> + std::fmt::Debug>(
     fasta_path: P,
     region: &Region,
     snippets: Vec<Alignment>,
+    soft_clip_bases: bool,
 ) -> Result<(Vec<AlignmentNucleobase>, Vec<AlignmentMatch>)> {
     let mut bases: Vec<AlignmentNucleobase> = Vec::new();
     let mut matches: Vec<AlignmentMatch> = Vec::new();
@@ -173,15 +370,39 @@ pub fn make_nucleobases<P: AsRef<Path> + std::fmt::Debug>(
                 end_position: temp_snippet.mate_pos as f64 - 0.5,
                 flags: temp_snippet.flags.clone(),
                 name: temp_snippet.name.clone(),
-                read_start: temp_snippet.pos as u32,
-                read_end: (temp_snippet.mate_pos + 100) as u32,
+                read_start: temp_snippet.read_span().0 as u32,
+                read_end: temp_snippet.read_span().1 as u32,
                 mapq: snippet.mapq,
                 cigar: snippet.cigar.to_string(),
+                supplementary: snippet.supplementary.clone(),
+                discordance: snippet.discordance.clone(),
             };
 
             matches.push(pairing);
         }
 
+        // link to the segments of a split read further right on the same contig, such that
+        // each pair of segments is only linked once
+        let end_pos = snippet.cigar.end_pos();
+        for segment in &snippet.split_segments {
+            if segment.tid == snippet.tid && segment.pos > end_pos {
+                let (read_start, read_end) = snippet.read_span();
+                matches.push(AlignmentMatch {
+                    marker_type: Marker::Split,
+                    start_position: end_pos as f64 - 0.5,
+                    end_position: segment.pos as f64 - 0.5,
+                    flags: snippet.flags.clone(),
+                    name: snippet.name.clone(),
+                    read_start: read_start as u32,
+                    read_end: read_end as u32,
+                    mapq: snippet.mapq,
+                    cigar: snippet.cigar.to_string(),
+                    supplementary: snippet.supplementary.clone(),
+                    discordance: snippet.discordance.clone(),
+                });
+            }
+        }
+
         for c in snippet.cigar.iter() {
             let mut match_count = 0;
             let mut match_start = 0;
@@ -243,9 +464,6 @@ pub fn make_nucleobases<P: AsRef<Path> + std::fmt::Debug>(
                     let snip = snippet.clone();
                     let p: f64 = snip.pos as f64 + read_offset as f64 - 0.5;
                     let m: Marker = Marker::Insertion;
-                    let rs;
-                    let re;
-
                     let mut b = String::from("");
                     for _i in 0..rust_htslib::bam::record::Cigar::Ins(*c).len() {
                         let char = char_vec[cigar_offset as usize];
@@ -254,18 +472,7 @@ pub fn make_nucleobases<P: AsRef<Path> + std::fmt::Debug>(
                         cigar_offset += 1;
                     }
 
-                    if snip.paired && snip.tid == snip.mate_tid {
-                        if snip.pos < snip.mate_pos {
-                            re = snip.mate_pos + 100;
-                            rs = snip.pos;
-                        } else {
-                            rs = snip.mate_pos;
-                            re = snip.pos + snip.length as i64;
-                        }
-                    } else {
-                        rs = snip.pos;
-                        re = snip.pos + snip.length as i64;
-                    }
+                    let (rs, re) = snip.read_span();
 
                     let base = AlignmentNucleobase {
                         marker_type: m,
@@ -295,23 +502,9 @@ pub fn make_nucleobases<P: AsRef<Path> + std::fmt::Debug>(
                         let position = snip.pos as i64 + read_offset;
                         let flags = snip.flags;
                         let name = snip.name;
-                        let read_start;
-                        let read_end;
+                        let (read_start, read_end) = snip.read_span();
                         let empty_bases = String::from("");
 
-                        if snip.paired && snip.tid == snip.mate_tid {
-                            if snip.pos < snip.mate_pos {
-                                read_end = snip.mate_pos + 100;
-                                read_start = snip.pos;
-                            } else {
-                                read_start = snip.mate_pos;
-                                read_end = snip.pos + snip.length as i64;
-                            }
-                        } else {
-                            read_start = snip.pos;
-                            read_end = snip.pos + snip.length as i64;
-                        }
-
                         let base = AlignmentNucleobase {
                             marker_type: marker,
                             bases: empty_bases,
@@ -337,54 +530,51 @@ pub fn make_nucleobases<P: AsRef<Path> + std::fmt::Debug>(
                     soft_clip_begin = false;
                 }
                 rust_htslib::bam::record::Cigar::SoftClip(c) => {
-                    for _i in 0..rust_htslib::bam::record::Cigar::SoftClip(*c).len() {
-                        let snip = snippet.clone();
-                        let b = char_vec[cigar_offset as usize];
-
-                        if snip.pos + read_offset >= from as i64
-                            && snip.pos + read_offset < to as i64
-                        {
-                            let ref_index = snip.pos + read_offset - from as i64;
-                            let ref_base = &ref_bases[ref_index as usize];
-
-                            if ref_base.get_marker_type() == b {
-                                // Create long rule while bases match
-                                if match_count == 0 {
-                                    match_start = snip.pos as i64 + read_offset;
-                                }
-                                match_count += 1;
-                                match_ending = true;
-                            } else {
-                                let (mtch, base) = make_markers(
-                                    snip.clone(),
-                                    b,
-                                    read_offset,
-                                    match_start,
-                                    match_count,
-                                );
-                                if let Some(m) = mtch {
-                                    matches.push(m)
-                                }
-                                bases.push(base);
-
-                                match_count = 0;
-                                match_start = 0;
-
-                                match_ending = false;
-                            }
-                        }
-
-                        cigar_offset += 1;
-                        if !soft_clip_begin {
-                            read_offset += 1;
-                        }
+                    let len = *c as i64;
+                    // leading clips end at the alignment start, trailing clips start after the
+                    // last aligned base
+                    let clip_start = if soft_clip_begin {
+                        snippet.pos - len
+                    } else {
+                        snippet.pos + read_offset
+                    };
+                    let (read_start, read_end) = snippet.read_span();
+
+                    if clip_start + len > from as i64 && clip_start < to as i64 {
+                        matches.push(AlignmentMatch {
+                            marker_type: Marker::SoftClip,
+                            start_position: clip_start as f64 + 0.5,
+                            end_position: (clip_start + len) as f64 + 0.5,
+                            flags: snippet.flags.clone(),
+                            name: snippet.name.clone(),
+                            read_start: read_start as u32,
+                            read_end: read_end as u32,
+                            mapq: snippet.mapq,
+                            cigar: snippet.cigar.to_string(),
+                            supplementary: snippet.supplementary.clone(),
+                            discordance: snippet.discordance.clone(),
+                        });
                     }
 
-                    if match_ending {
-                        let mtch =
-                            end_mismatch_detection(snippet.clone(), match_start, match_count);
-                        matches.push(mtch);
+                    for i in 0..len {
+                        let position = clip_start + i;
+                        let b = char_vec[(cigar_offset + i) as usize];
+                        if soft_clip_bases && position >= from as i64 && position < to as i64 {
+                            bases.push(AlignmentNucleobase {
+                                marker_type: base_marker(b),
+                                bases: b.to_string(),
+                                start_position: position as f64 + 0.5,
+                                end_position: position as f64 + 1.5,
+                                flags: snippet.flags.clone(),
+                                name: snippet.name.clone(),
+                                read_start: read_start as u32,
+                                read_end: read_end as u32,
+                                mapq: snippet.mapq,
+                                cigar: snippet.cigar.to_string(),
+                            });
+                        }
                     }
+                    cigar_offset += len;
 
                     soft_clip_begin = false;
                 }
@@ -404,37 +594,14 @@ fn make_markers(
     match_start: i64,
     match_count: i64,
 ) -> (Option<AlignmentMatch>, AlignmentNucleobase) {
-    let marker: Marker;
-
-    match base {
-        // Mismatch
-        'A' => marker = Marker::A,
-        'T' => marker = Marker::T,
-        'C' => marker = Marker::C,
-        'N' => marker = Marker::N,
-        'G' => marker = Marker::G,
-        _ => marker = Marker::Deletion,
-    }
+    // Mismatch
+    let marker = base_marker(base);
 
     let position = snip.pos as i64 + read_offset;
     let flags = snip.flags;
     let name = snip.name;
 
-    let read_start: i64;
-    let read_end: i64;
-
-    if snip.paired && snip.tid == snip.mate_tid {
-        if snip.pos < snip.mate_pos {
-            read_end = snip.mate_pos + 100;
-            read_start = snip.pos;
-        } else {
-            read_start = snip.mate_pos;
-            read_end = snip.pos + snip.length as i64;
-        }
-    } else {
-        read_start = snip.pos;
-        read_end = snip.pos + snip.length as i64;
-    }
+    let (read_start, read_end) = snip.read_span();
 
     let mut mtch = None;
 
@@ -450,6 +617,8 @@ fn make_markers(
             read_end: read_end as u32,
             mapq: snip.mapq,
             cigar: snip.cigar.to_string(),
+            supplementary: snip.supplementary.clone(),
+            discordance: snip.discordance.clone(),
         });
     }
 
@@ -468,25 +637,22 @@ fn make_markers(
     (mtch, base)
 }
 
+fn base_marker(base: char) -> Marker {
+    match base {
+        'A' => Marker::A,
+        'T' => Marker::T,
+        'C' => Marker::C,
+        'N' => Marker::N,
+        'G' => Marker::G,
+        _ => Marker::Deletion,
+    }
+}
+
 fn end_mismatch_detection(snip: Alignment, match_start: i64, match_count: i64) -> AlignmentMatch {
     let f = snip.flags;
     let n = snip.name;
 
-    let rs: i64;
-    let re: i64;
-
-    if snip.paired && snip.tid == snip.mate_tid {
-        if snip.pos < snip.mate_pos {
-            re = snip.mate_pos + 100;
-            rs = snip.pos;
-        } else {
-            rs = snip.mate_pos;
-            re = snip.pos + snip.length as i64;
-        }
-    } else {
-        rs = snip.pos;
-        re = snip.pos + snip.length as i64;
-    }
+    let (rs, re) = snip.read_span();
 
     AlignmentMatch {
         marker_type: Marker::Match,
@@ -498,5 +664,104 @@ fn end_mismatch_detection(snip: Alignment, match_start: i64, match_count: i64) -
         read_end: re as u32,
         mapq: snip.mapq,
         cigar: snip.cigar.to_string(),
+        supplementary: snip.supplementary,
+        discordance: snip.discordance,
+    }
+}
+
+/// A segment of the coverage bar of a reference position, counting the reads that show the
+/// reference base (`Match`), a mismatching base or a deletion. The segments of a position are
+/// stacked from `start` to `end`, summing up to the total `depth`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct CoverageBar {
+    marker_type: &'static str,
+    row: u8,
+    position: u64,
+    coverage_base: &'static str,
+    start: u32,
+    end: u32,
+    depth: u32,
+}
+
+const COVERAGE_BASES: [&str; 7] = ["Match", "A", "C", "G", "T", "N", "Deletion"];
+
+/// Compute the coverage of each position of the region from all given alignments, regardless of
+/// how many of them are plotted.
+pub fn compute_coverage<P: AsRef<Path> + std::fmt::Debug>(
+    fasta_path: P,
+    region: &Region,
+    alignments: &[Alignment],
+) -> Result<Vec<CoverageBar>> {
+    let ref_bases = read_fasta(fasta_path, region, false)?;
+    let (from, to) = (region.start as i64, region.end as i64);
+    // counts per position in the order of COVERAGE_BASES
+    let mut counts = vec![[0u32; 7]; ref_bases.len()];
+    let mut count = |position: i64, base: Option<char>| {
+        if position < from || position >= to {
+            return;
+        }
+        let index = (position - from) as usize;
+        let ref_base = match ref_bases.get(index) {
+            Some(b) => b.get_marker_type(),
+            None => return,
+        };
+        let i = match base.map(|b| b.to_ascii_uppercase()) {
+            None => 6,
+            Some(b) if b == ref_base => 0,
+            Some('A') => 1,
+            Some('C') => 2,
+            Some('G') => 3,
+            Some('T') => 4,
+            Some(_) => 5,
+        };
+        counts[index][i] += 1;
+    };
+
+    for alignment in alignments {
+        let sequence = alignment.sequence.as_bytes();
+        let mut position = alignment.pos;
+        let mut read_offset = 0;
+        for c in alignment.cigar.iter() {
+            match c {
+                Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => {
+                    for _ in 0..*len {
+                        let base = sequence.get(read_offset).map(|b| char::from(*b));
+                        count(position, base.or(Some('N')));
+                        position += 1;
+                        read_offset += 1;
+                    }
+                }
+                Cigar::Del(len) => {
+                    for _ in 0..*len {
+                        count(position, None);
+                        position += 1;
+                    }
+                }
+                Cigar::RefSkip(len) => position += *len as i64,
+                Cigar::Ins(len) | Cigar::SoftClip(len) => read_offset += *len as usize,
+                Cigar::HardClip(_) | Cigar::Pad(_) => {}
+            }
+        }
+    }
+
+    let mut bars = Vec::new();
+    for (i, position_counts) in counts.iter().enumerate() {
+        let depth = position_counts.iter().sum();
+        let mut start = 0;
+        for (base, n) in COVERAGE_BASES.iter().zip(position_counts) {
+            if *n > 0 {
+                bars.push(CoverageBar {
+                    marker_type: "Coverage",
+                    row: 0,
+                    position: region.start + i as u64 + 1,
+                    coverage_base: *base,
+                    start,
+                    end: start + n,
+                    depth,
+                });
+                start += n;
+            }
+        }
     }
+    Ok(bars)
 }
@@ -1,14 +1,16 @@
+use crate::bcf::report::annotation::{AnnotationLayout, AnnotationSchema, RecordAlleles};
+use crate::bcf::report::selection::RecordSelection;
 use crate::bcf::report::table_report::fasta_reader::{get_fasta_lengths, read_fasta};
-use crate::bcf::report::table_report::static_reader::{get_static_reads, Variant};
+use crate::bcf::report::table_report::feature_reader::{FeatureReader, FeatureTrack};
+use crate::bcf::report::table_report::static_reader::{get_static_reads, ReadLayout, Variant};
 use crate::common::Region;
-use anyhow::anyhow;
-use anyhow::Context as AnyhowContext;
 use anyhow::Result;
 use chrono::{DateTime, Local};
 use itertools::Itertools;
 use jsonm::packer::{PackOptions, Packer};
 use log::warn;
 use lz_str::compress_to_utf16;
+use rust_htslib::bam;
 use rust_htslib::bcf::header::{HeaderView, TagType};
 use rust_htslib::bcf::{HeaderRecord, Read, Record};
 use rustc_serialize::json::Json;
@@ -57,28 +59,31 @@ pub(crate) fn make_table_report(
     max_read_depth: u32,
     js_files: Vec<String>,
     annotation_field: &str,
+    annotation_schema: Option<&AnnotationSchema>,
+    selection: &RecordSelection,
+    gff_path: Option<&Path>,
 ) -> Result<()> {
-    // HashMap<gene: String, Vec<Report>>, Vec<ann_field_identifiers: String>
-    // let mut reports = HashMap::new();
-    let mut ann_indices = HashMap::new();
-    let mut vcf = rust_htslib::bcf::Reader::from_path(&vcf_path).unwrap();
+    let vcf = rust_htslib::bcf::Reader::from_path(&vcf_path).unwrap();
     let header = vcf.header().clone();
     let header_records = header.header_records();
-    let ann_field_description: Vec<_> = get_ann_description(header_records, annotation_field)?;
+    let layout =
+        AnnotationLayout::from_header(header_records, annotation_field, annotation_schema)?;
     let samples: Vec<_> = header
         .samples()
         .iter()
         .map(|s| std::str::from_utf8(s).map(|s| s.to_owned()))
         .collect::<Result<_, _>>()?;
 
-    for (i, field) in ann_field_description.iter().enumerate() {
-        ann_indices.insert(field, i);
-    }
-
     let reference_lengths = get_fasta_lengths(fasta_path)?;
+    // BAM files are opened once and reused for all records
+    let mut bam_readers = bam_sample_path
+        .iter()
+        .map(|(sample, path)| Ok((sample.to_owned(), bam::IndexedReader::from_path(path)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let mut feature_reader = gff_path.map(FeatureReader::from_path).transpose()?;
 
-    for v in vcf.records() {
-        let mut variant = v.unwrap();
+    for v in selection.records(vcf_path, &layout, annotation_field)? {
+        let mut variant = v?;
 
         let n = header.rid2name(variant.rid().unwrap()).unwrap().to_owned();
         let i = variant.id();
@@ -187,41 +192,35 @@ pub(crate) fn make_table_report(
         let mut alterations = Vec::new();
         let mut hgvsgs = Vec::new();
 
+        let record_alleles = RecordAlleles {
+            chrom: chrom.clone(),
+            pos,
+            end: end_pos.map(|end| (end + 0.5) as i64),
+            reference: alleles.first().cloned().unwrap_or_default(),
+            alts: alleles.iter().skip(1).cloned().collect(),
+        };
+
         if let Some(ann) = variant.info(annotation_field.as_bytes()).string()? {
             for entry in ann.iter() {
                 let fields = entry.split(|c| *c == b'|').collect_vec();
 
-                let get_field = |field: &str| {
-                    let field: String = std::str::from_utf8(
-                        fields[*ann_indices
-                            .get(&field.to_owned())
-                            .context({
-                                format!(
-                                    "No field named {} found. Please only use VEP-annotated VCF-files.",
-                                    field
-                                )
-                            })
-                            .unwrap()],
-                    ).unwrap().to_string();
-                    field
-                };
-
-                let alteration = if !get_field("HGVSp").is_empty() {
-                    get_field("HGVSp")
-                } else if !get_field("HGVSg").is_empty() {
-                    get_field("HGVSg")
+                let hgvsg = layout.hgvsg(&fields, &record_alleles)?;
+                let alteration = if !layout.hgvsp(&fields)?.is_empty() {
+                    layout.hgvsp(&fields)?.to_owned()
+                } else if !hgvsg.is_empty() {
+                    hgvsg.clone()
                 } else {
                     continue;
                 };
 
-                let allele = if !get_field("Allele").is_empty() {
-                    get_field("Allele")
+                let allele = if !layout.allele(&fields)?.is_empty() {
+                    layout.allele(&fields)?.to_owned()
                 } else {
                     continue;
                 };
 
-                if !get_field("HGVSg").is_empty() {
-                    hgvsgs.push((allele, get_field("HGVSg")));
+                if !hgvsg.is_empty() {
+                    hgvsgs.push((allele, hgvsg));
                 }
 
                 alterations.push(alteration);
@@ -239,22 +238,14 @@ pub(crate) fn make_table_report(
             let ref_vec = alleles[0].to_owned();
             let ref_allele = String::from_utf8(ref_vec)?;
 
-            let len: u8 = ref_allele.len() as u8;
-
             for allel in alleles.iter().skip(1) {
                 let alt = allel.as_slice();
-                let var_string = String::from("Variant");
-                let var_type: VariantType;
-                let alternatives: Option<String>;
-                let end_position: f64;
-                let plot_start_position;
-
                 let hgvsg: String = if alleles.len() > 2 {
                     if let Some(hgvsg) = hgvsgs
                         .iter()
                         .find(|(a, _)| *a == std::str::from_utf8(allel).unwrap())
                     {
-                        hgvsg.0.to_owned()
+                        hgvsg.1.to_owned()
                     } else {
                         warn!("Found variant {} at position {}:{} without HGVSg field for every given allele.", &id, &chrom, &pos);
                         continue;
@@ -275,117 +266,41 @@ pub(crate) fn make_table_report(
                     }
                 };
 
-                match alt {
-                    b"<DEL>" => {
-                        var_type = VariantType::Deletion;
-                        alternatives = None;
-                        end_position = end_pos.unwrap();
-                        plot_start_position = pos as f64 - 0.5;
-                    }
-                    b"<INV>" => {
-                        var_type = VariantType::Inversion;
-                        let rev: String = ref_allele.chars().rev().collect();
-                        alternatives = Some(rev.clone());
-                        end_position = end_pos.unwrap();
-                        plot_start_position = pos as f64 - 0.5;
-                    }
-                    b"<DUP>" => {
-                        var_type = VariantType::Duplicate;
-                        let dup: String = [ref_allele.clone(), ref_allele.clone()].concat();
-                        alternatives = Some(dup.clone());
-                        end_position = end_pos.unwrap();
-                        plot_start_position = pos as f64 - 0.5;
-                    }
-                    _ => {
-                        let mut alt_allele = String::from("");
-
-                        for c in alt {
-                            if *c as char != '<' && *c as char != '>' {
-                                alt_allele.push(*c as char);
-                            }
-                        }
-
-                        match alt_allele.len() {
-                            a if a < ref_allele.len() => {
-                                plot_start_position = pos as f64 + 0.5; // start position + 1 due to alignment with deletions from bam (example: ref: ACTT alt: A  -> deletion is just CTT)
-                                end_position = pos as f64 - 0.5 + len as f64;
-                                var_type = VariantType::Deletion;
-                                alternatives = Some(alt_allele.clone());
-                            }
-                            a if a > ref_allele.len() => {
-                                plot_start_position = pos as f64;
-                                end_position = pos as f64 + len as f64;
-                                var_type = VariantType::Insertion;
-                                alternatives = Some(alt_allele.clone());
-                            }
-                            _ => {
-                                plot_start_position = pos as f64 - 0.5;
-                                end_position = pos as f64 - 0.5 + len as f64;
-                                var_type = VariantType::Variant;
-                                alternatives = Some(alt_allele.clone());
-                            }
-                        }
-                    }
-                }
+                let var = make_variant(pos, &ref_allele, alt, end_pos, -1);
+                let end_position = var.end_position - 1.0;
 
-                let var = Variant {
-                    marker_type: var_string,
-                    reference: ref_allele.clone(),
-                    alternatives,
-                    start_position: plot_start_position + 1.0,
-                    end_position: end_position + 1.0,
-                    row: -1,
-                    var_type,
+                let fasta_length = *reference_lengths.get(&chrom).unwrap();
+                let (start, end) = if pos < 75 {
+                    (0, end_position as u64 + 75)
+                } else if end_position as i64 + 75 >= fasta_length as i64 {
+                    (pos as u64 - 75, fasta_length - 1)
+                } else {
+                    (pos as u64 - 75, end_position as u64 + 75)
+                };
+                let region = Region {
+                    target: chrom.clone(),
+                    start,
+                    end,
+                };
+                let features = match feature_reader.as_mut() {
+                    Some(reader) => reader.track(&region)?,
+                    None => FeatureTrack::default(),
                 };
 
                 let mut visualizations = BTreeMap::new();
 
-                for (sample, bam) in bam_sample_path {
-                    let bam_path = Path::new(bam);
-                    let fasta_length = *reference_lengths.get(&chrom).unwrap();
-                    let visualization: String;
-                    if pos < 75 {
-                        let content = create_report_data(
-                            fasta_path,
-                            Some(var.clone()),
-                            bam_path,
-                            &Region {
-                                target: chrom.clone(),
-                                start: 0,
-                                end: end_position as u64 + 75,
-                            },
-                            max_read_depth,
-                        )?;
-                        visualization = manipulate_json(content, 0, end_position as u64 + 75)?;
-                    } else if end_position as i64 + 75 >= fasta_length as i64 {
-                        let content = create_report_data(
-                            fasta_path,
-                            Some(var.clone()),
-                            bam_path,
-                            &Region {
-                                target: chrom.clone(),
-                                start: pos as u64 - 75,
-                                end: fasta_length - 1,
-                            },
-                            max_read_depth,
-                        )?;
-                        visualization =
-                            manipulate_json(content, pos as u64 - 75, fasta_length - 1)?;
-                    } else {
-                        let content = create_report_data(
-                            fasta_path,
-                            Some(var.clone()),
-                            bam_path,
-                            &Region {
-                                target: chrom.clone(),
-                                start: pos as u64 - 75,
-                                end: end_position as u64 + 75,
-                            },
-                            max_read_depth,
-                        )?;
-                        visualization =
-                            manipulate_json(content, pos as u64 - 75, end_position as u64 + 75)?;
-                    }
+                for (sample, bam) in bam_readers.iter_mut() {
+                    let content = create_report_data(
+                        fasta_path,
+                        Some(var.clone()),
+                        &[],
+                        &features,
+                        bam,
+                        &region,
+                        max_read_depth,
+                        &ReadLayout::default(),
+                    )?;
+                    let visualization = manipulate_json(content, start, end)?;
 
                     visualizations.insert(sample.to_owned(), visualization.to_string());
                 }
@@ -420,7 +335,7 @@ pub(crate) fn make_table_report(
                 context.insert("variant", &report_data);
                 context.insert("hgvsg", &hgvsg);
                 context.insert("escaped_hgvsg", &escaped_hgvsg);
-                context.insert("description", &ann_field_description);
+                context.insert("description", &layout.fields);
                 context.insert("sample", &sample);
                 context.insert("js_imports", &js_files);
                 context.insert("time", &local.format("%a %b %e %T %Y").to_string());
@@ -449,36 +364,88 @@ pub(crate) fn make_table_report(
     Ok(())
 }
 
-fn escape_hgvsg(hgvsg: &str) -> String {
-    hgvsg.replace(".", "_").replace(">", "_").replace(":", "_")
-}
+/// Build the plotted variant of an alternative allele of the record at the given 0-based
+/// position, where `end_pos` is the plot end of symbolic alleles given by the END tag.
+pub(crate) fn make_variant(
+    pos: i64,
+    ref_allele: &str,
+    alt: &[u8],
+    end_pos: Option<f64>,
+    row: i8,
+) -> Variant {
+    let len: u8 = ref_allele.len() as u8;
+    let var_type: VariantType;
+    let alternatives: Option<String>;
+    let end_position: f64;
+    let plot_start_position;
+
+    match alt {
+        b"<DEL>" => {
+            var_type = VariantType::Deletion;
+            alternatives = None;
+            end_position = end_pos.unwrap_or(pos as f64 - 0.5 + len as f64);
+            plot_start_position = pos as f64 - 0.5;
+        }
+        b"<INV>" => {
+            var_type = VariantType::Inversion;
+            let rev: String = ref_allele.chars().rev().collect();
+            alternatives = Some(rev.clone());
+            end_position = end_pos.unwrap_or(pos as f64 - 0.5 + len as f64);
+            plot_start_position = pos as f64 - 0.5;
+        }
+        b"<DUP>" => {
+            var_type = VariantType::Duplicate;
+            let dup: String = [ref_allele, ref_allele].concat();
+            alternatives = Some(dup.clone());
+            end_position = end_pos.unwrap_or(pos as f64 - 0.5 + len as f64);
+            plot_start_position = pos as f64 - 0.5;
+        }
+        _ => {
+            let mut alt_allele = String::from("");
 
-pub(crate) fn get_ann_description(
-    header_records: Vec<HeaderRecord>,
-    annotation_field: &str,
-) -> Result<Vec<String>> {
-    for rec in header_records {
-        if let rust_htslib::bcf::HeaderRecord::Info { key: _, values } = rec {
-            if values.get("ID").unwrap() == annotation_field {
-                let description = values.get("Description").unwrap();
-                let fields: Vec<_> = description.split('|').collect();
-                let mut owned_fields = Vec::new();
-                for mut entry in fields {
-                    entry = entry.trim();
-                    entry = entry.trim_start_matches(
-                        &"\"Consequence annotations from Ensembl VEP. Format: ",
-                    );
-                    entry = entry.trim_end_matches(&"\"");
-                    entry = entry.trim();
-                    owned_fields.push(entry.to_owned());
+            for c in alt {
+                if *c as char != '<' && *c as char != '>' {
+                    alt_allele.push(*c as char);
+                }
+            }
+
+            match alt_allele.len() {
+                a if a < ref_allele.len() => {
+                    plot_start_position = pos as f64 + 0.5; // start position + 1 due to alignment with deletions from bam (example: ref: ACTT alt: A  -> deletion is just CTT)
+                    end_position = pos as f64 - 0.5 + len as f64;
+                    var_type = VariantType::Deletion;
+                    alternatives = Some(alt_allele.clone());
+                }
+                a if a > ref_allele.len() => {
+                    plot_start_position = pos as f64;
+                    end_position = pos as f64 + len as f64;
+                    var_type = VariantType::Insertion;
+                    alternatives = Some(alt_allele.clone());
+                }
+                _ => {
+                    plot_start_position = pos as f64 - 0.5;
+                    end_position = pos as f64 - 0.5 + len as f64;
+                    var_type = VariantType::Variant;
+                    alternatives = Some(alt_allele.clone());
                 }
-                return Ok(owned_fields);
             }
         }
     }
-    Err(anyhow!(
-        "Could not find any annotations by VEP. Please only use VEP-annotated VCF-files."
-    ))
+
+    Variant {
+        marker_type: String::from("Variant"),
+        reference: ref_allele.to_owned(),
+        alternatives,
+        start_position: plot_start_position + 1.0,
+        end_position: end_position + 1.0,
+        row,
+        var_type,
+        genotypes: None,
+    }
+}
+
+fn escape_hgvsg(hgvsg: &str) -> String {
+    hgvsg.replace(".", "_").replace(">", "_").replace(":", "_")
 }
 
 pub(crate) fn read_tag_entries(
@@ -519,12 +486,19 @@ pub(crate) fn read_tag_entries(
     Ok(())
 }
 
+/// Collect the plot data of the reads in the given region. Only reads spanning `variant` are
+/// shown, while the variants in `overlay` are just drawn above the reads and the transcripts of
+/// `features` between the reference and the reads.
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn create_report_data<P: AsRef<Path> + std::fmt::Debug>(
     fasta_path: P,
     variant: Option<Variant>,
-    bam_path: P,
+    overlay: &[Variant],
+    features: &FeatureTrack,
+    bam: &mut bam::IndexedReader,
     region: &Region,
     max_read_depth: u32,
+    layout: &ReadLayout,
 ) -> Result<Json> {
     let mut data = Vec::new();
 
@@ -533,12 +507,14 @@ pub(crate) fn create_report_data<P: AsRef<Path> + std::fmt::Debug>(
         data.push(nucleobase);
     }
 
-    let (bases, matches) = get_static_reads(
-        bam_path,
+    let (bases, matches, coverage) = get_static_reads(
+        bam,
         fasta_path,
         region,
         max_read_depth,
         variant.as_ref(),
+        layout,
+        features.rows + 1,
     )?;
 
     for b in bases {
@@ -551,13 +527,27 @@ pub(crate) fn create_report_data<P: AsRef<Path> + std::fmt::Debug>(
         data.push(mat);
     }
 
+    for c in coverage {
+        data.push(json!(c));
+    }
+
+    for f in &features.markers {
+        data.push(json!(f));
+    }
+
     if variant.is_some() {
         data.push(json!(variant));
     }
 
+    for v in overlay {
+        data.push(json!(v));
+    }
+
     Ok(Json::from_str(&json!(data).to_string()).unwrap())
 }
 
+const COVERAGE_TRACK_HEIGHT: i64 = 60;
+
 /// Inserts the json containing the genome data into the vega specs.
 /// It also changes keys and values of the json data for the vega plot to look better and compresses the json with jsonm.
 pub(crate) fn manipulate_json(data: Json, from: u64, to: u64) -> Result<String> {
@@ -570,6 +560,7 @@ pub(crate) fn manipulate_json(data: Json, from: u64, to: u64) -> Result<String>
     let v = values["values"].as_array().unwrap().clone();
 
     let mut row = 0;
+    let mut min_row = 0;
 
     for (i, _) in v.iter().enumerate() {
         let k = v[i]["marker_type"].clone().as_str().unwrap().to_owned();
@@ -577,12 +568,17 @@ pub(crate) fn manipulate_json(data: Json, from: u64, to: u64) -> Result<String>
         if r > row {
             row = r;
         }
+        if r < min_row {
+            min_row = r;
+        }
 
         if k == "A" || k == "T" || k == "G" || k == "C" || k == "U" || k == "N" {
             values["values"][i]["base"] = values["values"][i]["marker_type"].clone();
         } else if k == "Deletion"
             || k == "Match"
             || k == "Pairing"
+            || k == "SoftClip"
+            || k == "Split"
             || k == "Duplicate"
             || k == "Inversion"
         {
@@ -594,7 +590,13 @@ pub(crate) fn manipulate_json(data: Json, from: u64, to: u64) -> Result<String>
     }
 
     vega_specs["width"] = json!(700);
-    vega_specs["height"] = json!(core::cmp::max(10 * row + 60, 203));
+    // space for the coverage track above the reads, see the range of the y scale, and for each
+    // variant track beyond the first
+    vega_specs["height"] = json!(
+        core::cmp::max(10 * row + 60, 203)
+            + COVERAGE_TRACK_HEIGHT
+            + 10 * core::cmp::max(-min_row - 1, 0)
+    );
     let domain = json!([from, to]);
 
     vega_specs["scales"][0]["domain"] = domain;
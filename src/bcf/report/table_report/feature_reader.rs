@@ -0,0 +1,376 @@
+//! Transcripts of a GFF3 or GTF file, drawn as a track of exon and CDS boxes with strand arrows
+//! under the reference sequence.
+use crate::common::Region;
+use anyhow::{Context, Result};
+use flate2::read::MultiGzDecoder;
+use itertools::Itertools;
+use log::warn;
+use rust_htslib::tbx::{self, Read};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// Maximum number of rows used by the transcripts of a region.
+const MAX_FEATURE_ROWS: u16 = 10;
+
+/// Minimum distance in bases between the strand arrows of a transcript.
+const ARROW_SPACING: u64 = 10;
+
+/// Feature types that are neither drawn nor part of a transcript.
+const IGNORED_TYPES: [&str; 10] = [
+    "start_codon",
+    "stop_codon",
+    "UTR",
+    "five_prime_UTR",
+    "three_prime_UTR",
+    "five_prime_utr",
+    "three_prime_utr",
+    "Selenocysteine",
+    "region",
+    "chromosome",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FeatureFormat {
+    Gff3,
+    Gtf,
+}
+
+/// A line of a GFF3 or GTF file, with 0-based half-open coordinates. GFF3 attributes keep all
+/// their comma-separated values, percent-decoded.
+#[derive(Debug, Clone)]
+struct Feature {
+    seqname: String,
+    feature_type: String,
+    start: u64,
+    end: u64,
+    strand: char,
+    attributes: HashMap<String, Vec<String>>,
+}
+
+impl Feature {
+    fn parse(line: &str, format: FeatureFormat) -> Option<Feature> {
+        if line.starts_with('#') {
+            return None;
+        }
+        let fields = line.trim_end().split('\t').collect_vec();
+        if fields.len() < 9 {
+            return None;
+        }
+        let attributes = fields[8]
+            .split(';')
+            .filter_map(|attribute| {
+                let attribute = attribute.trim();
+                let entry = match format {
+                    FeatureFormat::Gff3 => {
+                        let (key, value) = attribute.split_once('=')?;
+                        let values = value.split(',').map(percent_decode).collect();
+                        (percent_decode(key), values)
+                    }
+                    FeatureFormat::Gtf => {
+                        let (key, value) = attribute.split_once(' ')?;
+                        (key.to_owned(), vec![value.trim_matches('"').to_owned()])
+                    }
+                };
+                Some(entry)
+            })
+            .collect();
+        Some(Feature {
+            seqname: fields[0].to_owned(),
+            feature_type: fields[2].to_owned(),
+            start: fields[3].parse::<u64>().ok()?.saturating_sub(1),
+            end: fields[4].parse().ok()?,
+            strand: fields[6].chars().next().unwrap_or('.'),
+            attributes,
+        })
+    }
+
+    /// First value of the attribute.
+    fn attribute(&self, key: &str) -> Option<&str> {
+        self.attribute_values(key).first().map(|v| v.as_str())
+    }
+
+    fn attribute_values(&self, key: &str) -> &[String] {
+        self.attributes.get(key).map_or(&[], |v| v.as_slice())
+    }
+
+    fn is_gene(&self) -> bool {
+        self.feature_type.ends_with("gene")
+    }
+
+    fn is_part(&self) -> bool {
+        self.feature_type == "exon" || self.feature_type == "CDS"
+    }
+
+    /// Transcripts the feature belongs to (parts, possibly shared by several transcripts in
+    /// GFF3) or stands for (transcripts and genes).
+    fn transcript_keys(&self, format: FeatureFormat) -> &[String] {
+        match (format, self.is_part(), self.is_gene()) {
+            (FeatureFormat::Gff3, true, _) => self.attribute_values("Parent"),
+            (FeatureFormat::Gff3, false, _) => self.attribute_values("ID"),
+            (FeatureFormat::Gtf, _, true) => self.attribute_values("gene_id"),
+            (FeatureFormat::Gtf, _, false) => self.attribute_values("transcript_id"),
+        }
+    }
+
+    /// Gene of a transcript, such that genes with transcripts are not drawn on their own.
+    fn gene_key(&self, format: FeatureFormat) -> Option<&str> {
+        match format {
+            FeatureFormat::Gff3 => self.attribute("Parent"),
+            FeatureFormat::Gtf => self.attribute("gene_id"),
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        [
+            "Name",
+            "transcript_name",
+            "gene_name",
+            "ID",
+            "transcript_id",
+            "gene_id",
+        ]
+        .iter()
+        .find_map(|key| self.attribute(key))
+    }
+}
+
+/// Decode the `%XX` escapes of a GFF3 column 9 key or value; invalid escapes are kept as is.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A transcript (or gene without transcripts) with its exons and coding sequences.
+#[derive(Debug, Clone, Default)]
+struct Transcript {
+    name: Option<String>,
+    gene: Option<String>,
+    is_gene: bool,
+    start: u64,
+    end: u64,
+    strand: char,
+    parts: Vec<Feature>,
+}
+
+/// A drawn part of a transcript: its whole span (`Transcript`), `Exon` and `CDS` boxes, or a
+/// strand `Arrow` at a single position.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct FeatureMarker {
+    marker_type: &'static str,
+    feature_type: &'static str,
+    name: String,
+    strand: String,
+    start_position: f64,
+    end_position: f64,
+    row: u16,
+}
+
+/// The markers of all transcripts of a region and the number of rows they take.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FeatureTrack {
+    pub markers: Vec<FeatureMarker>,
+    pub rows: u16,
+}
+
+enum Source {
+    Indexed(tbx::Reader),
+    Loaded(HashMap<String, Vec<Feature>>),
+}
+
+/// Reader of a GFF3 or GTF file (by extension `.gtf`, optionally gzipped), fetching features via
+/// its tabix index if present or keeping the whole file in memory otherwise.
+pub struct FeatureReader {
+    source: Source,
+    format: FeatureFormat,
+}
+
+impl FeatureReader {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let file_name = path.to_string_lossy();
+        let format = if file_name.trim_end_matches(".gz").ends_with(".gtf") {
+            FeatureFormat::Gtf
+        } else {
+            FeatureFormat::Gff3
+        };
+        let source = match tbx::Reader::from_path(path) {
+            Ok(reader) => Source::Indexed(reader),
+            Err(_) => {
+                let file = File::open(path)
+                    .with_context(|| format!("error reading GFF/GTF file {}", path.display()))?;
+                let reader: Box<dyn BufRead> = if file_name.ends_with(".gz") {
+                    Box::new(BufReader::new(MultiGzDecoder::new(file)))
+                } else {
+                    Box::new(BufReader::new(file))
+                };
+                let mut features: HashMap<String, Vec<Feature>> = HashMap::new();
+                for line in reader.lines() {
+                    if let Some(feature) = Feature::parse(&line?, format) {
+                        features
+                            .entry(feature.seqname.clone())
+                            .or_default()
+                            .push(feature);
+                    }
+                }
+                Source::Loaded(features)
+            }
+        };
+        Ok(FeatureReader { source, format })
+    }
+
+    fn features(&mut self, region: &Region) -> Result<Vec<Feature>> {
+        let overlaps = |f: &Feature| f.start < region.end && f.end > region.start;
+        match &mut self.source {
+            Source::Indexed(reader) => {
+                let tid = match reader.tid(&region.target) {
+                    Ok(tid) => tid,
+                    // contig without features
+                    Err(_) => return Ok(Vec::new()),
+                };
+                reader.fetch(tid, region.start, region.end)?;
+                let mut features = Vec::new();
+                for line in reader.records() {
+                    let line = String::from_utf8(line?)?;
+                    if let Some(feature) = Feature::parse(&line, self.format) {
+                        if overlaps(&feature) {
+                            features.push(feature);
+                        }
+                    }
+                }
+                Ok(features)
+            }
+            Source::Loaded(features) => Ok(features
+                .get(&region.target)
+                .map(|features| features.iter().filter(|f| overlaps(f)).cloned().collect())
+                .unwrap_or_default()),
+        }
+    }
+
+    /// Collect the transcripts overlapping the region and place them into rows starting at 1,
+    /// directly under the reference.
+    pub fn track(&mut self, region: &Region) -> Result<FeatureTrack> {
+        let mut transcripts: HashMap<String, Transcript> = HashMap::new();
+        for feature in self.features(region)? {
+            if IGNORED_TYPES.contains(&feature.feature_type.as_str()) {
+                continue;
+            }
+            for key in feature.transcript_keys(self.format) {
+                let transcript = transcripts
+                    .entry(key.clone())
+                    .or_insert_with(|| Transcript {
+                        start: feature.start,
+                        end: feature.end,
+                        strand: feature.strand,
+                        ..Default::default()
+                    });
+                transcript.start = transcript.start.min(feature.start);
+                transcript.end = transcript.end.max(feature.end);
+                if feature.is_part() {
+                    if transcript.name.is_none() && self.format == FeatureFormat::Gtf {
+                        transcript.name = feature.name().map(|name| name.to_owned());
+                    }
+                    transcript.parts.push(feature.clone());
+                } else {
+                    transcript.name = feature.name().map(|name| name.to_owned());
+                    transcript.is_gene = feature.is_gene();
+                    if !transcript.is_gene {
+                        transcript.gene = feature.gene_key(self.format).map(|g| g.to_owned());
+                    }
+                }
+            }
+        }
+        // genes are only drawn if none of their transcripts are
+        let genes_with_transcripts = transcripts
+            .values()
+            .filter_map(|t| t.gene.clone())
+            .collect_vec();
+        let transcripts = transcripts
+            .into_iter()
+            .filter(|(key, t)| !(t.is_gene && genes_with_transcripts.contains(key)))
+            .sorted_by_key(|(key, t)| (t.start, t.end, key.clone()))
+            .collect_vec();
+
+        let mut track = FeatureTrack::default();
+        let mut row_ends: Vec<u64> = Vec::new();
+        let mut skipped = 0;
+        for (key, transcript) in transcripts {
+            let i = match row_ends.iter().position(|end| transcript.start > *end) {
+                Some(i) => i,
+                None if row_ends.len() < MAX_FEATURE_ROWS as usize => {
+                    row_ends.push(0);
+                    row_ends.len() - 1
+                }
+                None => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            row_ends[i] = transcript.end;
+            let row = i as u16 + 1;
+            let name = transcript.name.clone().unwrap_or(key);
+            let strand = transcript.strand.to_string();
+            let marker = |feature_type, start: u64, end: u64| FeatureMarker {
+                marker_type: "Feature",
+                feature_type,
+                name: name.clone(),
+                strand: strand.clone(),
+                start_position: start as f64 + 0.5,
+                end_position: end as f64 + 0.5,
+                row,
+            };
+
+            track
+                .markers
+                .push(marker("Transcript", transcript.start, transcript.end));
+            for part in &transcript.parts {
+                let feature_type = if part.feature_type == "CDS" {
+                    "CDS"
+                } else {
+                    "Exon"
+                };
+                track
+                    .markers
+                    .push(marker(feature_type, part.start, part.end));
+            }
+            if transcript.strand == '+' || transcript.strand == '-' {
+                let spacing = ((region.end - region.start) / 20).max(ARROW_SPACING);
+                let from = transcript.start.max(region.start);
+                let to = transcript.end.min(region.end);
+                let mut position = from + spacing / 2;
+                while position < to {
+                    track.markers.push(marker("Arrow", position, position));
+                    position += spacing;
+                }
+            }
+            track.rows = track.rows.max(row);
+        }
+        if skipped > 0 {
+            warn!(
+                "Skipped {} transcripts in {}:{}-{} exceeding {} rows.",
+                skipped, region.target, region.start, region.end, MAX_FEATURE_ROWS
+            );
+        }
+        Ok(track)
+    }
+}
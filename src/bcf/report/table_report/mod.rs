@@ -1,9 +1,12 @@
 mod alignment_reader;
 pub mod create_report_table;
-mod fasta_reader;
-mod static_reader;
+pub mod fasta_reader;
+pub mod feature_reader;
+pub mod static_reader;
 
+use crate::bcf::report::annotation::AnnotationSchema;
 use crate::bcf::report::oncoprint::WriteErr;
+use crate::bcf::report::selection::RecordSelection;
 use crate::bcf::report::table_report::create_report_table::make_table_report;
 use anyhow::{Context, Result};
 use std::fs;
@@ -21,6 +24,9 @@ pub fn table_report(
     max_read_depth: u32,
     js_files: Vec<String>,
     annotation_field: &str,
+    annotation_schema: Option<&AnnotationSchema>,
+    selection: &RecordSelection,
+    gff: Option<&str>,
 ) -> Result<()> {
     let detail_path = output_path.to_owned() + "/details/" + sample;
     fs::create_dir(Path::new(&detail_path)).context(WriteErr::CantCreateDir {
@@ -43,5 +49,8 @@ pub fn table_report(
         max_read_depth,
         js_files,
         annotation_field,
+        annotation_schema,
+        selection,
+        gff.map(Path::new),
     )
 }
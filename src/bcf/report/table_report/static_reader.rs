@@ -1,21 +1,29 @@
 use crate::bcf::report::table_report::alignment_reader::{
-    make_nucleobases, read_indexed_bam, AlignmentMatch, AlignmentNucleobase,
+    compute_coverage, make_nucleobases, read_indexed_bam, Alignment, AlignmentMatch,
+    AlignmentNucleobase, CoverageBar,
 };
 use crate::bcf::report::table_report::create_report_table::VariantType;
 use crate::common::Region;
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use itertools::Itertools;
 use rand::rngs::StdRng;
 use rand::seq::IteratorRandom;
 use rand_core::SeedableRng;
+use rust_htslib::bam;
 use serde::Serialize;
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
+use std::str::FromStr;
 
 #[derive(Serialize, Clone, Debug)]
 pub struct StaticAlignmentMatch {
     #[serde(flatten)]
     alignment: AlignmentMatch,
     row: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_color: Option<String>,
 }
 
 #[derive(Serialize, Clone)]
@@ -23,6 +31,10 @@ pub struct StaticAlignmentNucleobase {
     #[serde(flatten)]
     nucleobase: AlignmentNucleobase,
     row: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_color: Option<String>,
 }
 
 #[derive(Serialize, Clone)]
@@ -34,94 +46,262 @@ pub struct Variant {
     pub(crate) end_position: f64,
     pub(crate) row: i8,
     pub(crate) var_type: VariantType,
+    /// Genotypes of all samples (e.g. `a: 0/1, b: 1/1`), for variants drawn from a VCF file in
+    /// plot-bam.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) genotypes: Option<String>,
 }
 
-fn calc_rows(
-    reads: Vec<AlignmentNucleobase>,
-    matches: Vec<AlignmentMatch>,
-    max_read_depth: u32,
-    variant: Option<&Variant>,
-) -> (Vec<StaticAlignmentNucleobase>, Vec<StaticAlignmentMatch>) {
-    let mut row_ends = vec![0; 10000];
+/// How reads are arranged into rows and coloured in the plots.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReadLayout {
+    /// Aux tag (e.g. HP, RG, RX or SI) whose values put reads into separate blocks of rows.
+    pub group_by: Option<String>,
+    pub sort_by: Option<SortBy>,
+    pub color_by: Option<ColorBy>,
+    /// Show the bases of soft clips on top of the soft clip markers.
+    pub soft_clip_bases: bool,
+}
 
-    let mut read_names: BTreeMap<String, u16> = BTreeMap::new();
+impl ReadLayout {
+    /// Aux tags that have to be read from the BAM file.
+    fn tags(&self) -> Vec<String> {
+        let mut tags = self.group_by.iter().cloned().collect_vec();
+        if let Some(ColorBy::Tag(tag)) = &self.color_by {
+            tags.push(tag.to_owned());
+        }
+        tags
+    }
+}
 
-    let mut reads_wr: Vec<StaticAlignmentNucleobase> = Vec::new();
-    let mut matches_wr: Vec<StaticAlignmentMatch> = Vec::new();
+/// Order of reads within a group, given as `base:POSITION` (1-based) or `insert-size`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    /// The base of the reads at the given 1-based position, with deletions last.
+    Base(u64),
+    /// The absolute insert size, largest first.
+    InsertSize,
+}
 
-    let mut max_row = 0;
+impl FromStr for SortBy {
+    type Err = anyhow::Error;
 
-    for r in matches {
-        let overlaps = if let Some(variant_entry) = variant {
-            r.read_start < variant_entry.start_position as u32
-                && r.read_end > variant_entry.end_position as u32
-        } else {
-            true
-        };
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("base", position)) => Ok(SortBy::Base(position.parse().with_context(|| {
+                format!("invalid position {} for sorting reads by base", position)
+            })?)),
+            None if s == "insert-size" => Ok(SortBy::InsertSize),
+            _ => Err(anyhow!(
+                "invalid read order {}, expected base:POSITION or insert-size",
+                s
+            )),
+        }
+    }
+}
+
+/// Colouring of matching read segments, given as `strand`, `mapq` or `tag:TAG`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ColorBy {
+    Strand,
+    Mapq,
+    Tag(String),
+}
+
+impl FromStr for ColorBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("tag", tag)) if tag.len() == 2 => Ok(ColorBy::Tag(tag.to_owned())),
+            None if s == "strand" => Ok(ColorBy::Strand),
+            None if s == "mapq" => Ok(ColorBy::Mapq),
+            _ => Err(anyhow!(
+                "invalid read colouring {}, expected strand, mapq or tag:TAG",
+                s
+            )),
+        }
+    }
+}
+
+const STRAND_COLORS: [&str; 2] = ["#e8a3a3", "#a3a3e8"];
+const MAPQ_COLORS: [&str; 7] = [
+    "#910000", "#c70002", "#ff0000", "#ff7500", "#ffb200", "#ffe921", "#bbbbbb",
+];
+const TAG_COLORS: [&str; 10] = [
+    "#4e79a7", "#f28e2b", "#e15759", "#76b7b2", "#59a14f", "#edc948", "#b07aa1", "#ff9da7",
+    "#9c755f", "#bab0ac",
+];
+
+/// Colours of discordant pairs without another colouring, by orientation or mate on another
+/// contig.
+fn discordance_color(discordance: &str) -> &'static str {
+    match discordance {
+        "FF" | "RR" => "#17becf",
+        "RF" => "#2ca02c",
+        _ => "#9467bd",
+    }
+}
+
+/// Group, position in the sort order and colour of a read.
+#[derive(Debug, Clone, Default)]
+struct ReadInfo {
+    group: Option<String>,
+    sort_key: (bool, i64, char),
+    color: Option<String>,
+}
 
-        if overlaps {
-            let mut row: u16 = 0;
-
-            if read_names.contains_key(&r.name) {
-                row = *read_names.get(&r.name).unwrap();
-            } else {
-                for (i, _) in row_ends.iter().enumerate().take(10000).skip(1) {
-                    if r.read_start > row_ends[i] {
-                        if i > max_row {
-                            max_row = i;
-                        }
-                        row = i as u16;
-                        row_ends[i] = r.read_end;
-                        read_names.insert(r.name.clone(), i as u16);
-                        break;
-                    }
+/// Collect the group, sort key and colour of each read, merging both mates of a pair.
+fn read_infos(alignments: &[Alignment], layout: &ReadLayout) -> HashMap<String, ReadInfo> {
+    let tag_values: HashMap<String, &str> = match &layout.color_by {
+        Some(ColorBy::Tag(tag)) => alignments
+            .iter()
+            .filter_map(|a| a.tag(tag))
+            .sorted()
+            .dedup()
+            .enumerate()
+            .map(|(i, value)| (value.to_owned(), TAG_COLORS[i % TAG_COLORS.len()]))
+            .collect(),
+        _ => HashMap::new(),
+    };
+
+    let mut infos: HashMap<String, ReadInfo> = HashMap::new();
+    for alignment in alignments {
+        let info = infos.entry(alignment.name().to_owned()).or_insert_with(|| {
+            let color = match &layout.color_by {
+                Some(ColorBy::Strand) => {
+                    Some(STRAND_COLORS[alignment.is_reverse() as usize].to_owned())
                 }
+                Some(ColorBy::Mapq) => Some(
+                    MAPQ_COLORS[(alignment.mapq() as usize / 10).min(MAPQ_COLORS.len() - 1)]
+                        .to_owned(),
+                ),
+                Some(ColorBy::Tag(tag)) => alignment
+                    .tag(tag)
+                    .and_then(|value| tag_values.get(value))
+                    .map(|color| color.to_string()),
+                None => alignment
+                    .discordance()
+                    .map(|d| discordance_color(d).to_owned()),
+            };
+            ReadInfo {
+                color,
+                sort_key: (true, 0, ' '),
+                ..Default::default()
             }
-
-            let base = StaticAlignmentMatch {
-                alignment: r.clone(),
-                row,
+        });
+        if info.group.is_none() {
+            if let Some(tag) = &layout.group_by {
+                info.group = alignment.tag(tag).map(|value| format!("{}:{}", tag, value));
+            }
+        }
+        if info.sort_key.0 {
+            info.sort_key = match layout.sort_by {
+                Some(SortBy::Base(position)) => match alignment.base_at(position as i64 - 1) {
+                    // deletions are sorted after bases
+                    Some('-') => (false, 1, ' '),
+                    Some(base) => (false, 0, base.to_ascii_uppercase()),
+                    None => (true, 0, ' '),
+                },
+                Some(SortBy::InsertSize) => (false, -alignment.insert_size().abs(), ' '),
+                None => (false, 0, ' '),
             };
-
-            matches_wr.push(base);
         }
     }
+    infos
+}
 
-    for r in reads {
-        let overlaps = if let Some(variant_entry) = variant {
-            r.read_start < variant_entry.start_position as u32
-                && r.read_end > variant_entry.end_position as u32
+fn calc_rows(
+    reads: Vec<AlignmentNucleobase>,
+    matches: Vec<AlignmentMatch>,
+    max_read_depth: u32,
+    variant: Option<&Variant>,
+    infos: &HashMap<String, ReadInfo>,
+    first_row: usize,
+) -> (Vec<StaticAlignmentNucleobase>, Vec<StaticAlignmentMatch>) {
+    let overlaps = |read_start: u32, read_end: u32| {
+        if let Some(variant_entry) = variant {
+            read_start < variant_entry.start_position as u32
+                && read_end > variant_entry.end_position as u32
         } else {
             true
-        };
+        }
+    };
 
-        if overlaps {
-            let mut row: u16 = 0;
-
-            if read_names.contains_key(&r.name) {
-                row = *read_names.get(&r.name).unwrap();
-            } else {
-                for (i, _) in row_ends.iter().enumerate().take(10000).skip(1) {
-                    if r.read_start > row_ends[i] {
-                        if i > max_row {
-                            max_row = i;
-                        }
-                        row = i as u16;
-                        row_ends[i] = r.read_end;
-                        read_names.insert(r.name.clone(), i as u16);
-                        break;
-                    }
-                }
+    // reads in order of appearance, then by group and sort key
+    let mut seen = HashSet::new();
+    let mut spans = matches
+        .iter()
+        .map(|m| (&m.name, m.read_start, m.read_end))
+        .chain(reads.iter().map(|r| (&r.name, r.read_start, r.read_end)))
+        .filter(|(name, read_start, read_end)| {
+            overlaps(*read_start, *read_end) && seen.insert(name.to_owned())
+        })
+        .collect_vec();
+    let default_info = ReadInfo::default();
+    let info = |name: &str| infos.get(name).unwrap_or(&default_info);
+    spans.sort_by_key(|(name, _, _)| {
+        let info = info(name);
+        (info.group.is_none(), info.group.clone(), info.sort_key)
+    });
+
+    // reads are placed into the first row that is free at their start, with an empty row
+    // between groups
+    let mut read_rows: HashMap<&str, u16> = HashMap::new();
+    let mut row_ends: Vec<u32> = Vec::new();
+    let mut group_start = first_row;
+    let mut current_group = None;
+    let mut max_row = 0;
+    for (name, read_start, read_end) in spans {
+        let group = &info(name).group;
+        if group != current_group.unwrap_or(&None) {
+            if !row_ends.is_empty() {
+                group_start += row_ends.len() + 1;
             }
+            row_ends.clear();
+        }
+        current_group = Some(group);
+        let i = match row_ends.iter().position(|end| read_start > *end) {
+            Some(i) => {
+                row_ends[i] = read_end;
+                i
+            }
+            None => {
+                row_ends.push(read_end);
+                row_ends.len() - 1
+            }
+        };
+        let row = group_start + i;
+        max_row = max_row.max(row);
+        read_rows.insert(name, row as u16);
+    }
 
-            let base = StaticAlignmentNucleobase {
+    let mut matches_wr: Vec<StaticAlignmentMatch> = matches
+        .iter()
+        .filter_map(|r| {
+            let row = *read_rows.get(r.name.as_str())?;
+            let info = info(&r.name);
+            Some(StaticAlignmentMatch {
+                alignment: r.clone(),
+                row,
+                group: info.group.clone(),
+                read_color: info.color.clone(),
+            })
+        })
+        .collect();
+    let mut reads_wr: Vec<StaticAlignmentNucleobase> = reads
+        .iter()
+        .filter_map(|r| {
+            let row = *read_rows.get(r.name.as_str())?;
+            let info = info(&r.name);
+            Some(StaticAlignmentNucleobase {
                 nucleobase: r.clone(),
                 row,
-            };
-
-            reads_wr.push(base);
-        }
-    }
+                group: info.group.clone(),
+                read_color: info.color.clone(),
+            })
+        })
+        .collect();
 
     if max_row > max_read_depth as usize {
         let mut rng = StdRng::seed_from_u64(42);
@@ -142,14 +322,26 @@ fn calc_rows(
     (reads_wr, matches_wr)
 }
 
+/// Read the alignments of the region and place them into rows starting at `first_row`, below the
+/// reference and any feature track.
 pub fn get_static_reads<P: AsRef<Path> + std::fmt::Debug>(
-    path: P,
+    bam: &mut bam::IndexedReader,
     fasta_path: P,
     region: &Region,
     max_read_depth: u32,
     variant: Option<&Variant>,
-) -> Result<(Vec<StaticAlignmentNucleobase>, Vec<StaticAlignmentMatch>)> {
-    let alignments = read_indexed_bam(path, region)?;
-    let (msm, m) = make_nucleobases(fasta_path, region, alignments)?;
-    Ok(calc_rows(msm, m, max_read_depth, variant))
+    layout: &ReadLayout,
+    first_row: u16,
+) -> Result<(
+    Vec<StaticAlignmentNucleobase>,
+    Vec<StaticAlignmentMatch>,
+    Vec<CoverageBar>,
+)> {
+    let alignments = read_indexed_bam(bam, region, &layout.tags())?;
+    // coverage is computed before reads are subsampled to max_read_depth rows
+    let coverage = compute_coverage(&fasta_path, region, &alignments)?;
+    let infos = read_infos(&alignments, layout);
+    let (msm, m) = make_nucleobases(fasta_path, region, alignments, layout.soft_clip_bases)?;
+    let (bases, matches) = calc_rows(msm, m, max_read_depth, variant, &infos, first_row as usize);
+    Ok((bases, matches, coverage))
 }
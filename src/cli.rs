@@ -1,4 +1,11 @@
+use crate::bam::plot::plot_bam::PlotFormat;
+use crate::bcf::annotate_genes::FieldMapping;
+use crate::bcf::annotate_intervals::BedAnnotation;
+use crate::bcf::baf::Caller;
+use crate::bcf::report::annotation::AnnotationSchema;
+use crate::bcf::report::table_report::static_reader::{ColorBy, SortBy};
 use crate::common::Region;
+use crate::csv::config::{parse_column_type, ColumnType};
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -81,12 +88,48 @@ pub(crate) enum Command {
     },
 
     /// Convert any IUPAC codes in alleles into Ns (in order to comply with VCF 4 specs).
+    /// Optionally, REF alleles are resolved from a reference genome and ALT alleles
+    /// are expanded into the concrete alleles they represent.
     /// Reads VCF/BCF from STDIN and writes BCF to STDOUT.
     ///
     /// Example:
     /// rbt vcf-fix-iupac-alleles < test.vcf > fixed.bcf
+    /// rbt vcf-fix-iupac-alleles --reference ref.fa --expand-alts --annotate < test.vcf > fixed.bcf
     #[structopt(author = "Johannes Köster <johannes.koester@tu-dortmund.de>")]
-    VcfFixIupacAlleles {},
+    VcfFixIupacAlleles {
+        /// Reference genome (FASTA, indexed with samtools faidx) to resolve IUPAC codes in REF alleles.
+        #[structopt(long, short = "r", parse(from_os_str))]
+        reference: Option<PathBuf>,
+        /// Expand IUPAC codes in ALT alleles into the concrete alleles they represent,
+        /// resulting in multiple ALT alleles. Per-allele fields and genotypes are adjusted;
+        /// per-allele values (e.g. AD, AF, PL) and genotype alleles of expanded alleles are set to missing.
+        #[structopt(long, short = "e")]
+        expand_alts: bool,
+        /// Annotate fixed records with the INFO tag IUPAC_FIXED, listing original and fixed alleles.
+        #[structopt(long, short = "a")]
+        annotate: bool,
+    },
+
+    /// Normalize variants by left-aligning and trimming their alleles against a reference genome.
+    /// Optionally, multi-allelic records are split into biallelic ones and MNPs are decomposed into SNVs.
+    /// Reads VCF/BCF from STDIN and writes BCF to STDOUT.
+    ///
+    /// Example:
+    /// rbt vcf-normalize --split-multiallelics --decompose-mnps ref.fa < calls.vcf > normalized.bcf
+    VcfNormalize {
+        /// Reference genome (FASTA, indexed with samtools faidx).
+        #[structopt(parse(from_os_str))]
+        reference: PathBuf,
+        /// Split multi-allelic records into biallelic records, adjusting per-allele fields and genotypes.
+        #[structopt(long, short = "m")]
+        split_multiallelics: bool,
+        /// Decompose MNPs into SNVs.
+        #[structopt(long, short = "d")]
+        decompose_mnps: bool,
+        /// Window (in bases) in which records are buffered to keep the output sorted after left-alignment.
+        #[structopt(long, short = "w", default_value = "1000")]
+        site_window: u64,
+    },
 
     /// Convert VCF/BCF file from STDIN to tab-separated TXT file at STDOUT.
     /// INFO and FORMAT tags have to be selected explicitly.
@@ -116,9 +159,12 @@ pub(crate) enum Command {
     /// given second VCF/BCF. The matching is fuzzy for indels and exact for SNVs.
     /// Results are printed as BCF to STDOUT, with an additional INFO tag MATCHING.
     /// The two vcfs do not have to be sorted.
+    /// Optionally, genotypes of samples contained in both files are compared, yielding an additional
+    /// FORMAT tag GTMATCH and a per-sample concordance summary at STDERR.
     ///
     /// Example:
     /// rbt vcf-match dbsnp.vcf < calls.vcf | bcftools view
+    /// rbt vcf-match --genotypes truth.vcf < replicate.vcf | bcftools view
     #[structopt(author = "Johannes Köster <johannes.koester@tu-dortmund.de>")]
     VcfMatch {
         /// VCF/BCF file to match against.
@@ -132,26 +178,92 @@ pub(crate) enum Command {
         /// Maximum difference between lengths of two indels.
         #[structopt(long, short = "l", value_name = "INT", default_value = "10")]
         max_len_diff: u32,
+
+        /// Compare genotypes (zygosity and phase) of samples with the same name in both files.
+        #[structopt(long, short = "g")]
+        genotypes: bool,
     },
 
     /// Annotate b-allele frequency for each single nucleotide variant and sample.
     ///
+    /// Allele depths are read from the FORMAT tags of the given variant caller
+    /// (freebayes: RO/AO, gatk and deepvariant: AD, strelka: AU/CU/GU/TU and TAR/TIR).
+    /// By default, the caller is detected from the FORMAT tags in the header.
+    ///
     /// Example:
     /// rbt vcf-baf < calls.bcf > annotated.bcf
+    /// rbt vcf-baf --caller strelka --indels < calls.bcf > annotated.bcf
     #[structopt(
         author = "Johannes Köster <johannes.koester@uni-due.de>, Jan Forster <j.forster@dkfz.de>"
     )]
-    VcfBaf {},
+    VcfBaf {
+        /// Variant caller that produced the calls, determining the FORMAT tags used for allele depths.
+        #[structopt(
+            long,
+            short = "c",
+            default_value = "auto",
+            possible_values = &["auto", "freebayes", "gatk", "deepvariant", "strelka"]
+        )]
+        caller: Caller,
+        /// Also annotate b-allele frequencies of indels and other non-SNV alleles.
+        #[structopt(long, short = "i")]
+        indels: bool,
+    },
+
+    /// Segment b-allele frequencies (and depth ratios) into copy number and LOH states.
+    ///
+    /// Takes a VCF/BCF annotated with `rbt vcf-baf` from STDIN and writes segments with
+    /// their total and minor copy number as BED or VCF to STDOUT. If a matched normal
+    /// sample is given, only sites heterozygous in the normal are used and the tumor/normal
+    /// ratio of the FORMAT DP tag is segmented as well.
+    ///
+    /// Example:
+    /// rbt vcf-baf-segment --tumor tumor --normal normal < baf.bcf > segments.bed
+    VcfBafSegment {
+        /// Name of the tumor sample.
+        #[structopt(long, short = "t")]
+        tumor: String,
+        /// Name of the matched normal sample.
+        #[structopt(long, short = "n")]
+        normal: Option<String>,
+        /// Output format of the segments.
+        #[structopt(long, short = "f", default_value = "bed", possible_values = &["bed", "vcf"])]
+        output_format: String,
+        /// Probability to switch the copy number state between two consecutive sites.
+        #[structopt(long, default_value = "0.0001")]
+        switch_prob: f64,
+        /// Standard deviation of the mirrored b-allele frequency within a state.
+        #[structopt(long, default_value = "0.1")]
+        baf_sd: f64,
+        /// Standard deviation of the log2 depth ratio within a state.
+        #[structopt(long, default_value = "0.3")]
+        ratio_sd: f64,
+        /// Minimum b-allele frequency in the normal sample for a site to be considered heterozygous.
+        #[structopt(long, default_value = "0.2")]
+        min_het_baf: f64,
+    },
 
     /// Looks for interacting drugs in DGIdb and annotates them for every gene in every record.
+    /// Interactions are requested from the DGIdb API or, if given, read from a local TSV file
+    /// (e.g. the DGIdb interactions.tsv dump).
     ///
     /// Example:
     /// rbt vcf-annotate-dgidb input.vcf > output.vcf
+    /// rbt vcf-annotate-dgidb --interactions interactions.tsv -O v -o output.vcf < input.vcf
     #[structopt(author = "Felix Mölder <felix.moelder@uni-due.de>")]
     VcfAnnotateDgidb {
-        /// VCF/BCF file to be extended by dgidb drug entries
-        #[structopt()]
-        vcf: String,
+        /// VCF/BCF file to be extended by dgidb drug entries. If omitted, it is read from STDIN,
+        /// which requires a local interaction file (--interactions).
+        #[structopt(parse(from_os_str))]
+        vcf: Option<PathBuf>,
+
+        /// Output path. If omitted, the annotated records are written to STDOUT.
+        #[structopt(long, short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        /// Output type: compressed BCF (b), uncompressed BCF (u), compressed VCF (z) or uncompressed VCF (v).
+        #[structopt(long, short = "O", default_value = "u", possible_values = &["b", "u", "z", "v"])]
+        output_type: String,
 
         /// Url prefix for requesting interaction drugs by gene names.
         #[structopt(
@@ -165,6 +277,19 @@ pub(crate) enum Command {
         #[structopt(long, short = "f", default_value = "dgiDB_drugs")]
         field: String,
 
+        /// INFO field with functional annotations (e.g. ANN from SnpEff or CSQ from VEP) from which genes are taken.
+        #[structopt(long, short = "a", default_value = "ANN")]
+        annotation_field: String,
+
+        /// Subfield of the annotation field containing the gene name, as given in its header description.
+        /// If omitted, SYMBOL (VEP) or Gene_Name (SnpEff) is used.
+        #[structopt(long)]
+        gene_subfield: Option<String>,
+
+        /// Plain INFO tag containing gene names, to be used instead of the annotation field.
+        #[structopt(long, conflicts_with = "gene-subfield")]
+        gene_tag: Option<String>,
+
         /// A list of data sources included in query. If omitted all sources are considered.
         /// A list of all sources can be found at http://dgidb.org/api/v2/interaction_sources.json
         #[structopt(long, short = "s", value_name = "STR")]
@@ -174,15 +299,133 @@ pub(crate) enum Command {
         /// Too many requests could be rejected by the DGIdb server.
         #[structopt(long, short = "g", default_value = "500")]
         genes_per_request: usize,
+
+        /// Directory for caching API responses per gene. Cached genes are not requested again.
+        #[structopt(long, short = "c", parse(from_os_str))]
+        cache_dir: Option<PathBuf>,
+
+        /// Number of retries of failed API requests, with exponentially increasing delays (of at most 5 minutes).
+        #[structopt(long, default_value = "3")]
+        retries: u32,
+
+        /// Delay (in milliseconds) before retrying a failed API request for the first time.
+        #[structopt(long, default_value = "1000")]
+        retry_delay: u64,
+
+        /// Maximum number of API requests per second.
+        #[structopt(long)]
+        max_requests_per_second: Option<f64>,
+
+        /// Local TSV file with drug-gene interactions to be used instead of the DGIdb API,
+        /// e.g. the DGIdb interactions.tsv dump.
+        #[structopt(long, short = "i", parse(from_os_str))]
+        interactions: Option<PathBuf>,
+
+        /// Column of the interaction file containing gene names.
+        #[structopt(long, default_value = "gene_name")]
+        gene_column: String,
+
+        /// Column of the interaction file containing drug names.
+        #[structopt(long, default_value = "drug_name")]
+        drug_column: String,
+
+        /// Column of the interaction file containing (comma-separated) interaction types.
+        #[structopt(long, default_value = "interaction_types")]
+        interaction_type_column: String,
+
+        /// Column of the interaction file containing the data source, used for filtering by --datasources.
+        #[structopt(long, default_value = "interaction_claim_source")]
+        source_column: String,
     },
 
-    /// Creates report from a given csv file containing a table with the given data
+    /// Annotates the genes of every record with columns of a local TSV table keyed by gene
+    /// symbol or Ensembl gene ID (e.g. cancer gene census tiers or actionability levels).
+    /// Each column is mapped onto an INFO field as COLUMN=TAG[:TYPE], with TYPE being one of
+    /// String (default), Integer, Float or Flag.
+    ///
+    /// Example:
+    /// rbt vcf-annotate-genes input.vcf --table genes.tsv --fields Tier=CGC_TIER:Integer Role=CGC_ROLE > output.bcf
+    /// rbt vcf-annotate-genes --table genes.tsv --key-column gene_id --gene-subfield Gene --fields Level=LEVEL < input.vcf > output.bcf
+    VcfAnnotateGenes {
+        /// VCF/BCF file to be annotated. If omitted, it is read from STDIN.
+        #[structopt(parse(from_os_str))]
+        vcf: Option<PathBuf>,
+
+        /// TSV file with a header line, containing one or more rows per gene.
+        #[structopt(long, short = "t", parse(from_os_str))]
+        table: PathBuf,
+
+        /// Column of the table containing the gene symbol or Ensembl gene ID.
+        #[structopt(long, short = "k", default_value = "gene")]
+        key_column: String,
+
+        /// Columns to annotate, given as COLUMN=TAG[:TYPE] (e.g. Tier=CGC_TIER:Integer).
+        /// If TAG is omitted, the column name is used.
+        #[structopt(long, short = "f", value_name = "COLUMN=TAG[:TYPE]", required = true)]
+        fields: Vec<FieldMapping>,
+
+        /// Output path. If omitted, the annotated records are written to STDOUT.
+        #[structopt(long, short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        /// Output type: compressed BCF (b), uncompressed BCF (u), compressed VCF (z) or uncompressed VCF (v).
+        #[structopt(long, short = "O", default_value = "u", possible_values = &["b", "u", "z", "v"])]
+        output_type: String,
+
+        /// INFO field with functional annotations (e.g. ANN from SnpEff or CSQ from VEP) from which genes are taken.
+        #[structopt(long, short = "a", default_value = "ANN")]
+        annotation_field: String,
+
+        /// Subfield of the annotation field containing the gene, as given in its header description
+        /// (e.g. Gene for Ensembl gene IDs in VEP annotations).
+        /// If omitted, SYMBOL (VEP) or Gene_Name (SnpEff) is used.
+        #[structopt(long)]
+        gene_subfield: Option<String>,
+
+        /// Plain INFO tag containing genes, to be used instead of the annotation field.
+        #[structopt(long, conflicts_with = "gene-subfield")]
+        gene_tag: Option<String>,
+    },
+
+    /// Annotates every record with the names or scores of overlapping intervals from one or more
+    /// BED files (e.g. target regions, repeat masks, blacklist regions or exon numbers).
+    /// Each BED file is mapped onto its own INFO tag as BED=TAG[:VALUE], with VALUE being one of
+    /// name (default), score or flag. Names and scores are given per overlapping interval, in
+    /// order of their start; a score of . is annotated as missing.
+    ///
+    /// Example:
+    /// rbt vcf-annotate-intervals input.vcf --bed targets.bed=TARGET exons.bed=EXON:score > output.bcf
+    /// rbt vcf-annotate-intervals --bed blacklist.bed=BLACKLIST:flag -O v < input.vcf > output.vcf
+    VcfAnnotateIntervals {
+        /// VCF/BCF file to be annotated. If omitted, it is read from STDIN.
+        #[structopt(parse(from_os_str))]
+        vcf: Option<PathBuf>,
+
+        /// BED files to annotate, given as BED=TAG[:VALUE] (e.g. exons.bed=EXON or blacklist.bed=BLACKLIST:flag).
+        #[structopt(long, short = "b", value_name = "BED=TAG[:VALUE]", required = true)]
+        bed: Vec<BedAnnotation>,
+
+        /// Output path. If omitted, the annotated records are written to STDOUT.
+        #[structopt(long, short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        /// Output type: compressed BCF (b), uncompressed BCF (u), compressed VCF (z) or uncompressed VCF (v).
+        #[structopt(long, short = "O", default_value = "u", possible_values = &["b", "u", "z", "v"])]
+        output_type: String,
+    },
+
+    /// Creates report from a given csv file containing a table with the given data.
+    /// Multiple tables, each shown in its own tab and XLSX sheet, can be given via a YAML file
+    /// listing the tables with their paths (relative to the YAML file) and options
+    /// (name, path, separator, rows-per-page, sort-column, sort-order, formatter, pin-until, column-types and conditional-formats).
     /// Examples:
     /// With current directory as default ouput path:
     /// rbt csv-report path/to/table.csv --rows-per-page 100 --sort-column "p-value" --sort-order ascending
+    /// rbt csv-report path/to/tables.yaml path/to/report
     #[structopt(author = "Felix Wiegand <felix.wiegand@tu-dortmund.de>")]
     CsvReport {
-        /// CSV file including the data for the report.
+        /// CSV file including the data for the report, or a YAML file (.yaml or .yml) describing multiple tables.
+        /// In the latter case, the table options below are ignored.
         #[structopt()]
         csv_path: String,
 
@@ -211,6 +454,22 @@ pub(crate) enum Command {
         #[structopt(long, short = "p")]
         pin_until: Option<String>,
 
+        /// Override the detected type of a column (repeat for multiple columns), given as COLUMN=TYPE with TYPE being one of categorical, integer, float,
+        /// date (ISO 8601) or link. Numeric and date columns are shown with a histogram and summary statistics,
+        /// categorical and link columns with their most frequent values.
+        #[structopt(long = "column-type", short = "t", number_of_values = 1, parse(try_from_str = parse_column_type))]
+        column_types: Vec<(String, ColumnType)>,
+
+        /// YAML file with further table options. Cells of the XLSX output can be coloured via `conditional-formats`,
+        /// a list of rules with column, operator (<, <=, >, >=, ==, != or contains), value and color (hex code).
+        /// Column types can be given as a map `column-types` from column to type.
+        #[structopt(long, parse(from_os_str))]
+        config: Option<PathBuf>,
+
+        /// Write the report as a single self-contained HTML file at the output path (or report.html within it, if it is a directory) instead of a directory of files.
+        #[structopt(long)]
+        single_file: bool,
+
         /// Relative output path for the report files. Default value is the current directory.
         #[structopt(default_value = ".")]
         output_path: String,
@@ -218,12 +477,17 @@ pub(crate) enum Command {
 
     #[structopt(verbatim_doc_comment)]
     /// Creates a html file with a vega visualization of the given bam region that is then written to stdout.
+    /// With --format svg, a static SVG image of the same plot is written instead.
+    /// With --regions, each interval of a BED file or each record of a VCF/BCF file is plotted into
+    /// the given output directory, with an index.html linking to the plots.
     ///
     /// EXAMPLE:
     ///     rbt plot-bam -b input.bam -g 2:132424-132924 -r input.fa > plot.html
+    ///     rbt plot-bam -b input.bam -g 2:132424-132924 -r input.fa --format svg > plot.svg
+    ///     rbt plot-bam -b input.bam --regions calls.vcf -r input.fa --output-dir plots
     #[structopt(
         author = "Felix Wiegand <felix.wiegand@tu-dortmund.de>",
-        usage = "rbt plot-bam [OPTIONS] --bam-path <bam-path>... --reference <reference> --region <region> > plot.html"
+        usage = "rbt plot-bam [OPTIONS] --bam-path <bam-path>... --reference <reference> --region <region> > plot.html\n    rbt plot-bam [OPTIONS] --bam-path <bam-path>... --reference <reference> --regions <regions> --output-dir <output-dir>"
     )]
     PlotBam {
         /// BAM file to be visualized.
@@ -235,12 +499,57 @@ pub(crate) enum Command {
         reference: PathBuf,
 
         /// Chromosome and region for the visualization. Example: 2:132424-132924
-        #[structopt(long, short = "g")]
-        region: Region,
+        #[structopt(long, short = "g", required_unless = "regions")]
+        region: Option<Region>,
+
+        /// VCF/BCF file whose records are drawn as a track above the reads, with REF/ALT and the genotype of each sample. Can be given multiple times for one track each.
+        #[structopt(long = "vcf", value_name = "VCF", parse(from_os_str))]
+        vcfs: Vec<PathBuf>,
+
+        /// GFF3 or GTF file (by extension .gtf, optionally bgzipped and tabix-indexed) whose transcripts are drawn as a track under the reference.
+        #[structopt(long, parse(from_os_str))]
+        gff: Option<PathBuf>,
+
+        /// BED file (by extension .bed) or VCF/BCF file with regions to be plotted, one plot per interval or record.
+        #[structopt(
+            long,
+            conflicts_with = "region",
+            requires = "output-dir",
+            parse(from_os_str)
+        )]
+        regions: Option<PathBuf>,
+
+        /// Number of bases added on both sides of each interval or record given with --regions.
+        #[structopt(long, default_value = "75")]
+        flank: u64,
+
+        /// Output directory for the plots of --regions.
+        #[structopt(long, short = "o", parse(from_os_str))]
+        output_dir: Option<PathBuf>,
 
         /// Set the maximum rows that will be shown in the alignment plots.
         #[structopt(long, short = "d", default_value = "500")]
         max_read_depth: u32,
+
+        /// Aux tag (e.g. HP, RG, RX or SI) by which reads are grouped into separate blocks of rows.
+        #[structopt(long, value_name = "TAG")]
+        group_by: Option<String>,
+
+        /// Order of reads within a group, either by their base at a 1-based position (base:POSITION) or by descending absolute insert size (insert-size).
+        #[structopt(long, value_name = "base:POSITION|insert-size")]
+        sort_by: Option<SortBy>,
+
+        /// Colour of the aligned read segments, by strand, mapping quality or the value of an aux tag (tag:TAG).
+        #[structopt(long, value_name = "strand|mapq|tag:TAG")]
+        color_by: Option<ColorBy>,
+
+        /// Show the bases of soft-clipped read ends, which are otherwise drawn as plain markers.
+        #[structopt(long)]
+        soft_clip_bases: bool,
+
+        /// Output format, either an interactive HTML page or a static SVG image.
+        #[structopt(long, default_value = "html", possible_values = &["html", "svg"])]
+        format: PlotFormat,
     },
 
     /// Creates report from a given VCF file including a visual plot
@@ -262,11 +571,11 @@ pub(crate) enum Command {
         #[structopt()]
         fasta: String,
 
-        /// VCF files to include (multi-sample). Group is the name that will be used in the oncoprint. There needs to be one corresponding BAM file for each sample of a VCF/BCF file. Please only use VCF/BCF files annotated by VEP.
+        /// VCF files to include (multi-sample). Group is the name that will be used in the oncoprint. There needs to be one corresponding BAM file for each sample of a VCF/BCF file. Please only use VCF/BCF files annotated by VEP or SnpEff.
         #[structopt(long, short = "v", value_name = "GROUP=VCF_FILE")]
         vcfs: Vec<String>,
 
-        /// VCF files to include (multi-sample). Group is the name that will be used in the oncoprint. There needs to be one corresponding BAM file for each sample of a VCF/BCF file. Please only use VCF/BCF files annotated by VEP.
+        /// VCF files to include (multi-sample). Group is the name that will be used in the oncoprint. There needs to be one corresponding BAM file for each sample of a VCF/BCF file. Please only use VCF/BCF files annotated by VEP or SnpEff.
         #[structopt(long, short = "b", value_name = "GROUP:SAMPLE=BAM_FILE")]
         bams: Vec<String>,
 
@@ -306,10 +615,33 @@ pub(crate) enum Command {
         #[structopt(long, default_value = "0")]
         threads: usize,
 
-        /// Set the name of the annotation field generated by VEP.
+        /// Set the name of the annotation field generated by VEP or SnpEff.
         #[structopt(long, short = "a", default_value = "ANN")]
         annotation_field: String,
 
+        /// Layout of the annotation field, either vep, snpeff or a YAML file mapping the fields used by the report
+        /// (allele, consequence, impact, symbol, gene, feature, hgvsg, hgvsp, canonical, existing_variation and clin_sig)
+        /// to the field names of the annotation. By default, it is detected from the VCF header.
+        #[structopt(long, value_name = "vep|snpeff|MAPPING_FILE")]
+        annotation_schema: Option<AnnotationSchema>,
+
+        /// Only report variants within the given regions, given as BED files (by extension .bed) or as CHROM:START-END with
+        /// 1-based inclusive positions. Regions are fetched via the index of each VCF/BCF file if present.
+        #[structopt(long, value_name = "BED_FILE|CHROM:START-END")]
+        regions: Vec<String>,
+
+        /// Only report variants annotated for the given genes (by symbol or gene ID), given as names or files with one name per line.
+        #[structopt(long, value_name = "GENE|GENE_FILE")]
+        genes: Vec<String>,
+
+        /// GFF3 or GTF file (by extension .gtf, optionally bgzipped and tabix-indexed) whose transcripts are drawn as a track under the reference in the alignment plots.
+        #[structopt(long, value_name = "GFF_FILE")]
+        gff: Option<String>,
+
+        /// Write the report as a single self-contained HTML file at the output path (or report.html within it, if it is a directory) instead of a directory of files.
+        #[structopt(long)]
+        single_file: bool,
+
         /// Relative output path for the report files. Default value is the current directory.
         #[structopt(default_value = ".")]
         output_path: String,
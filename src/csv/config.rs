@@ -0,0 +1,337 @@
+//! Configuration of csv-report, given as YAML files.
+//!
+//! Options of a single table (`--config`) look like this:
+//! ```yaml
+//! column-types:
+//!   sample: categorical
+//!   date: date
+//!   website: link
+//! conditional-formats:
+//!   - column: Viruses
+//!     operator: ">="
+//!     value: 50
+//!     color: "#f4cccc"
+//!   - column: pangolin strain
+//!     operator: contains
+//!     value: B.1.1.7
+//!     color: "#fff2cc"
+//! ```
+//!
+//! A report of multiple tables, each shown in its own tab and XLSX sheet, is described by a
+//! list of tables with their paths and options:
+//! ```yaml
+//! tables:
+//!   - name: variants
+//!     path: variants.tsv
+//!     separator: "\t"
+//!     sort-column: p-value
+//!     sort-order: ascending
+//!     pin-until: gene
+//!     conditional-formats:
+//!       - column: p-value
+//!         operator: "<"
+//!         value: 0.05
+//!         color: "#d9ead3"
+//!   - name: QC
+//!     path: qc.csv
+//!     rows-per-page: 50
+//! ```
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+fn from_yaml<T: DeserializeOwned, P: AsRef<Path>>(path: P) -> Result<T> {
+    let path = path.as_ref();
+    serde_yaml::from_reader(
+        File::open(path)
+            .with_context(|| format!("unable to open config file {}", path.display()))?,
+    )
+    .with_context(|| format!("invalid config file {}", path.display()))
+}
+
+/// A report of multiple tables.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct ReportConfig {
+    pub(crate) tables: Vec<TableSpec>,
+}
+
+impl ReportConfig {
+    pub(crate) fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut config: ReportConfig = from_yaml(&path)?;
+        // table paths are relative to the config file
+        let base = path.as_ref().parent().unwrap_or_else(|| Path::new(""));
+        for table in &mut config.tables {
+            table.path = base.join(&table.path).to_string_lossy().into_owned();
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.tables.is_empty() {
+            return Err(ConfigError::NoTables.into());
+        }
+        let mut names = HashSet::new();
+        for table in &self.tables {
+            if !names.insert(&table.name) {
+                return Err(ConfigError::DuplicateTable {
+                    name: table.name.to_owned(),
+                }
+                .into());
+            }
+            // restrictions of XLSX sheet names
+            if table.name.is_empty()
+                || table.name.chars().count() > 31
+                || table
+                    .name
+                    .contains(&['[', ']', ':', '*', '?', '/', '\\'][..])
+            {
+                return Err(ConfigError::InvalidTableName {
+                    name: table.name.to_owned(),
+                }
+                .into());
+            }
+            for format in &table.conditional_formats {
+                format.rgb()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A table of a report together with its options.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct TableSpec {
+    /// Name of the tab and XLSX sheet.
+    pub(crate) name: String,
+    pub(crate) path: String,
+    #[serde(default = "default_separator")]
+    pub(crate) separator: char,
+    #[serde(default = "default_rows_per_page")]
+    pub(crate) rows_per_page: usize,
+    #[serde(default)]
+    pub(crate) sort_column: Option<String>,
+    #[serde(default)]
+    pub(crate) sort_order: SortOrder,
+    #[serde(default)]
+    pub(crate) formatter: Option<String>,
+    #[serde(default)]
+    pub(crate) pin_until: Option<String>,
+    #[serde(default)]
+    pub(crate) column_types: HashMap<String, ColumnType>,
+    #[serde(default)]
+    pub(crate) conditional_formats: Vec<ConditionalFormat>,
+}
+
+fn default_separator() -> char {
+    ','
+}
+
+fn default_rows_per_page() -> usize {
+    100
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+impl Default for SortOrder {
+    fn default() -> Self {
+        SortOrder::Descending
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ascending" => Ok(SortOrder::Ascending),
+            "descending" => Ok(SortOrder::Descending),
+            _ => Err(anyhow::anyhow!("invalid sort order {}", s)),
+        }
+    }
+}
+
+/// Type of a column, deciding how its values are written to the XLSX sheet, plotted and
+/// summarized. Without an override, columns are detected as integer, float, date or categorical.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ColumnType {
+    Categorical,
+    Integer,
+    Float,
+    /// ISO 8601 dates like `2021-03-01`, optionally with a time.
+    Date,
+    /// URLs, shown as clickable links.
+    Link,
+}
+
+impl FromStr for ColumnType {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "categorical" => Ok(ColumnType::Categorical),
+            "integer" => Ok(ColumnType::Integer),
+            "float" => Ok(ColumnType::Float),
+            "date" => Ok(ColumnType::Date),
+            "link" => Ok(ColumnType::Link),
+            _ => Err(ConfigError::InvalidColumnType {
+                column_type: s.to_owned(),
+            }),
+        }
+    }
+}
+
+/// Parse a column type override given as `COLUMN=TYPE`.
+pub(crate) fn parse_column_type(s: &str) -> Result<(String, ColumnType)> {
+    let i = s
+        .rfind('=')
+        .ok_or_else(|| ConfigError::InvalidColumnTypeMapping {
+            mapping: s.to_owned(),
+        })?;
+    if i == 0 {
+        return Err(ConfigError::InvalidColumnTypeMapping {
+            mapping: s.to_owned(),
+        }
+        .into());
+    }
+    Ok((s[..i].to_owned(), s[i + 1..].parse()?))
+}
+
+/// Options of a single table of a csv-report.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct TableConfig {
+    /// Types of columns overriding the automatic detection.
+    #[serde(default)]
+    pub(crate) column_types: HashMap<String, ColumnType>,
+    /// Rules for colouring cells of the XLSX output. For each cell, the first matching rule applies.
+    #[serde(default)]
+    pub(crate) conditional_formats: Vec<ConditionalFormat>,
+}
+
+impl TableConfig {
+    pub(crate) fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let config: TableConfig = from_yaml(path)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        for format in &self.conditional_formats {
+            format.rgb()?;
+        }
+        Ok(())
+    }
+}
+
+/// A rule colouring the cells of a column whose value fulfills the given condition.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ConditionalFormat {
+    pub(crate) column: String,
+    pub(crate) operator: Operator,
+    pub(crate) value: RuleValue,
+    /// Background colour as hex code, e.g. `#f4cccc`.
+    pub(crate) color: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operator {
+    #[serde(rename = "<")]
+    Less,
+    #[serde(rename = "<=")]
+    LessEqual,
+    #[serde(rename = ">")]
+    Greater,
+    #[serde(rename = ">=")]
+    GreaterEqual,
+    #[serde(rename = "==")]
+    Equal,
+    #[serde(rename = "!=")]
+    NotEqual,
+    #[serde(rename = "contains")]
+    Contains,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub(crate) enum RuleValue {
+    Number(f64),
+    Text(String),
+}
+
+impl ConditionalFormat {
+    /// Whether the given cell value fulfills the condition. Comparisons are numeric if both
+    /// values are numbers and lexicographic otherwise.
+    pub(crate) fn matches(&self, cell: &str) -> bool {
+        let (rule_text, rule_number) = match &self.value {
+            RuleValue::Number(n) => (n.to_string(), Some(*n)),
+            RuleValue::Text(t) => (t.to_owned(), f64::from_str(t).ok()),
+        };
+        if self.operator == Operator::Contains {
+            return cell.contains(&rule_text);
+        }
+        let ordering = match (f64::from_str(cell).ok(), rule_number) {
+            (Some(a), Some(b)) => match a.partial_cmp(&b) {
+                Some(ordering) => ordering,
+                None => return false,
+            },
+            _ => cell.cmp(rule_text.as_str()),
+        };
+        match self.operator {
+            Operator::Less => ordering.is_lt(),
+            Operator::LessEqual => ordering.is_le(),
+            Operator::Greater => ordering.is_gt(),
+            Operator::GreaterEqual => ordering.is_ge(),
+            Operator::Equal => ordering.is_eq(),
+            Operator::NotEqual => ordering.is_ne(),
+            Operator::Contains => unreachable!(),
+        }
+    }
+
+    /// The colour of this rule as RGB value.
+    pub(crate) fn rgb(&self) -> Result<u32> {
+        let hex = self.color.trim_start_matches('#');
+        if hex.len() != 6 {
+            return Err(ConfigError::InvalidColor {
+                color: self.color.to_owned(),
+            }
+            .into());
+        }
+        u32::from_str_radix(hex, 16).map_err(|_| {
+            ConfigError::InvalidColor {
+                color: self.color.to_owned(),
+            }
+            .into()
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("invalid color {color}, expected a hex code like #f4cccc")]
+    InvalidColor { color: String },
+    #[error("no tables given in report config")]
+    NoTables,
+    #[error("table name {name} is used more than once")]
+    DuplicateTable { name: String },
+    #[error("invalid table name {name}, names must have 1 to 31 characters and must not contain any of []:*?/\\")]
+    InvalidTableName { name: String },
+    #[error("invalid column type {column_type}, expected one of categorical, integer, float, date or link")]
+    InvalidColumnType { column_type: String },
+    #[error("invalid column type {mapping}, expected COLUMN=TYPE")]
+    InvalidColumnTypeMapping { mapping: String },
+}
@@ -1,2 +1,3 @@
 //! Tools that work on CSV files.
+pub mod config;
 pub mod report;
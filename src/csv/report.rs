@@ -1,179 +1,249 @@
 use crate::bcf::report::oncoprint::WriteErr;
+use crate::csv::config::{ColumnType, SortOrder, TableSpec};
 use anyhow::Context as AnyhowContext;
-use anyhow::Result;
-use chrono::{DateTime, Local};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime};
 use derive_new::new;
 use itertools::Itertools;
 use lz_str::compress_to_utf16;
 use serde_derive::Serialize;
 use serde_json::json;
-use std::collections::{HashMap, HashSet};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::TryInto;
 use std::fs;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::str::FromStr;
 use tera::{Context, Tera};
 use xlsxwriter::*;
 
-type LookupTable = HashMap<String, HashMap<String, Vec<(String, usize, usize)>>>;
-
-#[allow(clippy::too_many_arguments)]
-pub(crate) fn csv_report(
-    csv_path: &str,
-    output_path: &str,
-    rows_per_page: usize,
-    separator: char,
-    sort_column: Option<&str>,
-    ascending: Option<bool>,
-    formatter: Option<&str>,
-    pin_until: Option<&str>,
-) -> Result<()> {
-    let mut rdr = csv::ReaderBuilder::new()
-        .delimiter(separator as u8)
-        .from_path(csv_path)?;
-
-    let header = rdr.headers()?.clone();
-    let titles = header.iter().collect_vec();
-    let mut table = Vec::new();
-    let mut numeric = HashMap::new();
-    let mut non_numeric = HashMap::new();
-    let mut integer = HashMap::new();
-    for res in rdr.records() {
-        let row = res?;
-        let mut table_entry = HashMap::new();
-        for (i, tile) in titles.iter().enumerate() {
-            table_entry.insert(tile.to_string(), row[i].to_owned());
-            match f32::from_str(&row[i]) {
-                Ok(_) => {
-                    let num = numeric.entry(tile.to_owned()).or_insert_with(|| 0);
-                    *num += 1;
-                    if i32::from_str(&row[i]).is_ok() {
-                        let int = integer.entry(tile.to_owned()).or_insert_with(|| 0);
-                        *int += 1;
-                    }
-                }
-                _ => {
-                    let no_num = non_numeric.entry(tile.to_owned()).or_insert_with(|| 0);
-                    *no_num += 1;
-                }
-            }
+const BINS: usize = 20;
+const PREFIX_LEN: usize = 3;
+/// Number of most frequent values shown in the plot of a nominal column.
+const NOMINAL_PLOT_VALUES: usize = 10;
+/// Number of distinct values counted per nominal column before less frequent ones are dropped.
+const NOMINAL_CAPACITY: usize = 1000;
+/// Number of lookup pages written at once in each pass over the lookup entries of a column.
+const LOOKUP_PAGES_PER_PASS: usize = 256;
+/// Number of lookup entries buffered per page before they are written.
+const LOOKUP_CHUNK: usize = 1000;
+/// Number of rows sorted in memory before they are spilled to a temporary file.
+const SORT_CHUNK: usize = 100_000;
+
+/// Create a report of the given tables. A single table is written directly into the output
+/// directory, multiple tables into the subdirectories `tables/1`, `tables/2`, ... with a
+/// navigation between them. The XLSX file contains one sheet per table.
+pub(crate) fn csv_report(tables: &[TableSpec], output_path: &str) -> Result<()> {
+    // rows are written in order, such that they can be flushed to disk one by one
+    let wb = Workbook::new_with_options(
+        &(output_path.to_owned() + "/report.xlsx"),
+        true,
+        None,
+        false,
+    );
+    if let [table] = tables {
+        table_report(table, output_path, "../", &wb, &[], 0)?;
+    } else {
+        let names = tables.iter().map(|t| t.name.as_str()).collect_vec();
+        for (i, table) in tables.iter().enumerate() {
+            let table_path = format!("{}/tables/{}", output_path, i + 1);
+            let js_path = table_path.to_owned() + "/js/";
+            fs::create_dir_all(Path::new(&js_path)).context(WriteErr::CantCreateDir {
+                dir_path: js_path.to_owned(),
+            })?;
+            table_report(table, &table_path, "../../../", &wb, &names, i)?;
         }
-        table.push(table_entry);
+        let mut file = fs::File::create(output_path.to_owned() + "/index.html")?;
+        file.write_all(include_str!("index.html").as_bytes())?;
     }
+    wb.close()?;
+    Ok(())
+}
 
-    let mut is_numeric = HashMap::new();
-    for title in &titles {
-        let is_num = match (numeric.get(title), non_numeric.get(title)) {
-            (Some(num), Some(no_num)) => num > no_num,
-            (Some(_), None) => true,
-            _ => false,
-        };
-        is_numeric.insert(title.to_owned(), is_num);
+/// Write the report of a single table to the given directory, with `root` being the relative
+/// path from the pages of the table to the shared assets of the report.
+///
+/// The table is streamed instead of being loaded into memory: a first pass determines the type
+/// and value range of each column, a second pass collects plot data and summary statistics,
+/// spills the entries of the lookup tables to temporary files and writes the XLSX sheet, and a
+/// third pass writes the pages. Only if the table has to be sorted, its rows are kept in memory.
+fn table_report(
+    spec: &TableSpec,
+    table_path: &str,
+    root: &str,
+    wb: &Workbook,
+    tables: &[&str],
+    table_index: usize,
+) -> Result<()> {
+    let rows_per_page = spec.rows_per_page;
+    let mut rdr = reader(spec)?;
+    let titles = rdr.headers()?.iter().map(|t| t.to_owned()).collect_vec();
+    let sort_index = spec
+        .sort_column
+        .as_ref()
+        .map(|col| {
+            titles.iter().position(|t| t == col).context(
+                "Given value for --sort-column did not match any of the columns of your csv file",
+            )
+        })
+        .transpose()?;
+    let pinned_columns = if let Some(col) = &spec.pin_until {
+        titles.iter().position(|t| t == col).context(
+            "Given value for --pin-until did not match any of the columns of your csv file",
+        )? + 1
+    } else {
+        0
+    };
+    for rule in &spec.conditional_formats {
+        if !titles.contains(&rule.column) {
+            return Err(anyhow!(
+                "Column {} of conditional format did not match any of the columns of your csv file",
+                rule.column
+            ));
+        }
     }
-
-    let mut is_integer = HashMap::new();
-    for title in &titles {
-        let is_int = match (integer.get(title), non_numeric.get(title)) {
-            (Some(num), Some(no_num)) => num > no_num,
-            (Some(_), None) => true,
-            _ => false,
-        };
-        is_integer.insert(title.to_owned(), is_int);
+    for column in spec.column_types.keys() {
+        if !titles.contains(column) {
+            return Err(anyhow!(
+                "Column {} of given column types did not match any of the columns of your csv file",
+                column
+            ));
+        }
     }
 
-    let mut plot_data = HashMap::new();
-    let mut num_plot_data = HashMap::new();
-    let mut reasonable_plot = titles.iter().map(|t| (*t, true)).collect::<HashMap<_, _>>();
-
-    for title in &titles {
-        match is_numeric.get(title) {
-            Some(true) => {
-                let plot = num_plot(&table, title.to_string());
-                num_plot_data.insert(title, plot);
-            }
-            Some(false) => {
-                if let Some(plot) = nominal_plot(&table, title.to_string()) {
-                    plot_data.insert(title, plot);
-                } else {
-                    plot_data.insert(title, vec![]);
-                    reasonable_plot.insert(title, false);
-                }
-            }
-            _ => unreachable!(),
-        };
+    // first pass: column types and value ranges
+    let mut stats = vec![ColumnStats::default(); titles.len()];
+    let mut sorted_rows =
+        sort_index.map(|col| SortedRows::new(col, spec.sort_order == SortOrder::Ascending));
+    let mut row_count = 0;
+    for res in rdr.records() {
+        let record = res?;
+        for (value, column_stats) in record.iter().zip(stats.iter_mut()) {
+            column_stats.observe(value);
+        }
+        if let Some(rows) = sorted_rows.as_mut() {
+            rows.push(record.iter().map(|v| v.to_owned()).collect_vec())?;
+        }
+        row_count += 1;
     }
-
-    match (sort_column, ascending) {
-        (Some(column), Some(true)) => table.sort_by(|a, b| {
-            match (
-                f32::from_str(a.get(column).unwrap()),
-                f32::from_str(b.get(column).unwrap()),
-            ) {
-                (Ok(float_a), Ok(float_b)) => float_a.partial_cmp(&float_b).unwrap(),
-                _ => a.get(column).cmp(&b.get(column)),
-            }
-        }),
-        (Some(column), Some(false)) => table.sort_by(|a, b| {
-            match (
-                f32::from_str(a.get(column).unwrap()),
-                f32::from_str(b.get(column).unwrap()),
-            ) {
-                (Ok(float_a), Ok(float_b)) => float_b.partial_cmp(&float_a).unwrap(),
-                _ => a.get(column).cmp(&b.get(column)),
-            }
-        }),
-        (_, _) => {}
+    if let Some(rows) = sorted_rows.as_mut() {
+        rows.finish()?;
+    }
+    for (title, column_stats) in titles.iter().zip(stats.iter_mut()) {
+        column_stats.resolve_type(spec.column_types.get(title).copied());
     }
+    let is_binned = titles
+        .iter()
+        .zip(&stats)
+        .map(|(title, s)| (title.as_str(), s.is_binned()))
+        .collect::<HashMap<_, _>>();
+    let is_link = titles
+        .iter()
+        .zip(&stats)
+        .map(|(title, s)| (title.as_str(), s.column_type == ColumnType::Link))
+        .collect::<HashMap<_, _>>();
 
-    let wb = Workbook::new(&(output_path.to_owned() + "/report.xlsx"));
-    let mut sheet = wb.add_worksheet(Some("Report"))?;
+    // second pass: plot data, lookup tables and XLSX sheet
+    let mut nominal_counts = vec![NominalCounts::default(); titles.len()];
+    let mut histograms = (0..titles.len())
+        .map(|_| Histogram::default())
+        .collect_vec();
+    let bin_borders = stats.iter().map(|s| s.bin_borders()).collect_vec();
+    let mut lookups = (0..titles.len())
+        .map(|_| LookupSpill::new())
+        .collect::<Result<Vec<_>>>()?;
+
+    let header_format = wb.add_format().set_bold();
+    let rule_formats = spec
+        .conditional_formats
+        .iter()
+        .map(|rule| {
+            Ok(wb
+                .add_format()
+                .set_bg_color(FormatColor::Custom(rule.rgb()?)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut sheet = wb.add_worksheet(Some(&spec.name))?;
     for (i, title) in titles.iter().enumerate() {
-        sheet.write_string(0, i.try_into()?, title, None)?;
+        sheet.write_string(0, i.try_into()?, title, Some(&header_format))?;
     }
 
-    for (i, row) in table.iter().enumerate() {
-        for (c, title) in titles.iter().enumerate() {
-            sheet.write_string(
-                (i + 1).try_into()?,
-                c.try_into()?,
-                row.get(*title).unwrap(),
-                None,
-            )?;
-        }
-    }
+    for_each_row(spec, sorted_rows.as_ref(), |i, row| {
+        let (page, index) = (i / rows_per_page + 1, i % rows_per_page);
+        for (c, (value, column_stats)) in row.iter().zip(&stats).enumerate() {
+            let format = spec
+                .conditional_formats
+                .iter()
+                .zip(&rule_formats)
+                .find(|(rule, _)| rule.column == titles[c] && rule.matches(value))
+                .map(|(_, format)| format);
+            let (row_index, col_index) = ((i + 1).try_into()?, c.try_into()?);
+            match f64::from_str(value) {
+                Ok(number) if column_stats.is_numeric() && number.is_finite() => {
+                    sheet.write_number(row_index, col_index, number, format)?
+                }
+                _ if column_stats.column_type == ColumnType::Link && !value.is_empty() => {
+                    sheet.write_url(row_index, col_index, value, format)?
+                }
+                _ => sheet.write_string(row_index, col_index, value, format)?,
+            }
 
-    wb.close()?;
+            if column_stats.is_binned() {
+                histograms[c].observe(value, column_stats)?;
+                for bin in column_stats
+                    .bins(value)
+                    .into_iter()
+                    .map(|b| &bin_borders[c][b])
+                    .unique()
+                {
+                    lookups[c].push(bin, &column_stats.normalize(value), page, index)?;
+                }
+            } else {
+                if !value.is_empty() {
+                    nominal_counts[c].observe(value);
+                }
+                let value = value.trim();
+                if let Some(entry) = value.split_whitespace().next() {
+                    if entry.len() >= PREFIX_LEN {
+                        let prefix: String = entry.chars().take(PREFIX_LEN).collect();
+                        lookups[c].push(&prefix, value, page, index)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    })?;
 
-    let pages = if table.len() % rows_per_page == 0 && !table.is_empty() {
-        (table.len() / rows_per_page) - 1
-    } else {
-        table.len() / rows_per_page
-    };
+    sheet.freeze_panes(1, pinned_columns.try_into()?);
+    if !titles.is_empty() {
+        sheet.autofilter(0, 0, row_count.try_into()?, (titles.len() - 1).try_into()?)?;
+    }
 
-    let plot_path = output_path.to_owned() + "/plots/";
+    let plot_path = table_path.to_owned() + "/plots/";
     fs::create_dir(Path::new(&plot_path)).context(WriteErr::CantCreateDir {
         dir_path: plot_path.to_owned(),
     })?;
 
+    let mut reasonable_plot = HashMap::new();
+    let mut summaries = HashMap::new();
+    let mut templates = Tera::default();
+    templates.add_raw_template("plot.js.tera", include_str!("plot.js.tera"))?;
     for (n, title) in titles.iter().enumerate() {
-        let mut templates = Tera::default();
-        templates.add_raw_template("plot.js.tera", include_str!("plot.js.tera"))?;
         let mut context = Context::new();
-        match is_numeric.get(title) {
-            Some(true) => {
-                context.insert(
-                    "table",
-                    &json!(num_plot_data.get(title).unwrap()).to_string(),
-                );
-                context.insert("num", &true);
-            }
-            Some(false) => {
-                context.insert("table", &json!(plot_data.get(title).unwrap()).to_string());
-                context.insert("num", &false);
-            }
-            _ => unreachable!(),
+        context.insert("date", &(stats[n].column_type == ColumnType::Date));
+        if stats[n].is_binned() {
+            context.insert("table", &histograms[n].plot_data(&stats[n]).to_string());
+            context.insert("num", &true);
+            reasonable_plot.insert(title.as_str(), true);
+            summaries.insert(title.as_str(), Some(histograms[n].summary(&stats[n])?));
+        } else {
+            summaries.insert(title.as_str(), None);
+            let plot = nominal_counts[n].plot();
+            reasonable_plot.insert(title.as_str(), plot.is_some());
+            context.insert("table", &json!(plot.unwrap_or_default()).to_string());
+            context.insert("num", &false);
         }
         context.insert("title", &title);
         context.insert("index", &n.to_string());
@@ -183,125 +253,74 @@ pub(crate) fn csv_report(
         let mut file = fs::File::create(file_path)?;
         file.write_all(js.as_bytes())?;
     }
+    drop(histograms);
 
-    let index_path = output_path.to_owned() + "/indexes/";
+    let index_path = table_path.to_owned() + "/indexes/";
     fs::create_dir(Path::new(&index_path)).context(WriteErr::CantCreateDir {
         dir_path: index_path.to_owned(),
     })?;
 
-    let data_path = output_path.to_owned() + "/data/";
+    let data_path = table_path.to_owned() + "/data/";
     fs::create_dir(Path::new(&data_path)).context(WriteErr::CantCreateDir {
         dir_path: data_path.to_owned(),
     })?;
 
-    let mut prefixes = make_prefixes(
-        table
-            .clone()
-            .into_iter()
-            .map(|hm| {
-                hm.into_iter()
-                    .filter(|(k, _)| !is_numeric.get(k.as_str()).unwrap())
-                    .collect()
-            })
-            .collect(),
-        titles
-            .clone()
-            .into_iter()
-            .filter(|e| !is_numeric.get(e).unwrap())
-            .collect(),
-        rows_per_page,
-    );
-
-    let bin = make_bins(
-        table
-            .clone()
-            .into_iter()
-            .map(|hm| {
-                hm.into_iter()
-                    .filter(|(k, _)| {
-                        *is_numeric.get(k.as_str()).unwrap() && !is_integer.get(k.as_str()).unwrap()
-                    })
-                    .collect()
-            })
-            .collect(),
-        titles
-            .clone()
-            .into_iter()
-            .filter(|e| *is_numeric.get(e).unwrap() && !is_integer.get(e).unwrap())
-            .collect(),
-        rows_per_page,
-    );
-
-    let int_bin = make_bins_for_integers(
-        table
-            .clone()
-            .into_iter()
-            .map(|hm| {
-                hm.into_iter()
-                    .filter(|(k, _)| *is_integer.get(k.as_str()).unwrap())
-                    .collect()
-            })
-            .collect(),
-        titles
-            .clone()
-            .into_iter()
-            .filter(|e| *is_integer.get(e).unwrap())
-            .collect(),
-        rows_per_page,
-    );
-
-    for (k, v) in bin.into_iter().chain(int_bin) {
-        prefixes.insert(k, v);
-    }
-
-    let prefix_path = output_path.to_owned() + "/prefixes/";
+    let prefix_path = table_path.to_owned() + "/prefixes/";
     fs::create_dir(Path::new(&prefix_path)).context(WriteErr::CantCreateDir {
         dir_path: prefix_path.to_owned(),
     })?;
 
-    for (n, title) in titles.iter().enumerate() {
-        if let Some(prefix_table) = prefixes.get(title.to_owned()) {
-            let mut templates = Tera::default();
-            templates.add_raw_template(
-                "prefix_table.html.tera",
-                include_str!("prefix_table.html.tera"),
-            )?;
-            let mut context = Context::new();
-            context.insert("title", title);
-            context.insert("index", &n.to_string());
-            context.insert("table", prefix_table);
-            context.insert("numeric", is_numeric.get(title).unwrap());
-            let html = templates.render("prefix_table.html.tera", &context)?;
-
-            let file_path = output_path.to_owned() + "/prefixes/col_" + &n.to_string() + ".html";
-            let mut file = fs::File::create(file_path)?;
-            file.write_all(html.as_bytes())?;
-
-            let title_path = prefix_path.to_owned() + "/col_" + &n.to_string() + "/";
-            fs::create_dir(Path::new(&title_path)).context(WriteErr::CantCreateDir {
-                dir_path: title_path.to_owned(),
-            })?;
-
-            for (prefix, values) in prefix_table {
-                let mut templates = Tera::default();
-                templates.add_raw_template(
-                    "lookup_table.html.tera",
-                    include_str!("lookup_table.html.tera"),
-                )?;
-                let mut context = Context::new();
-                context.insert("title", title);
-                context.insert("values", values);
-                context.insert("index", &n.to_string());
-                let html = templates.render("lookup_table.html.tera", &context)?;
-
-                let file_path = title_path.to_owned() + prefix + ".html";
-                let mut file = fs::File::create(file_path)?;
-                file.write_all(html.as_bytes())?;
-            }
+    let mut templates = Tera::default();
+    templates.add_raw_template(
+        "prefix_table.html.tera",
+        include_str!("prefix_table.html.tera"),
+    )?;
+    templates.add_raw_template(
+        "lookup_table_start.html.tera",
+        include_str!("lookup_table_start.html.tera"),
+    )?;
+    templates.add_raw_template(
+        "lookup_rows.html.tera",
+        include_str!("lookup_rows.html.tera"),
+    )?;
+    templates.add_raw_template(
+        "lookup_table_end.html.tera",
+        include_str!("lookup_table_end.html.tera"),
+    )?;
+    for (n, (title, lookup)) in titles.iter().zip(lookups).enumerate() {
+        // binned columns list all bins, including empty ones
+        let keys = if stats[n].is_binned() {
+            bin_borders[n].clone()
+        } else {
+            lookup.keys.iter().cloned().collect_vec()
+        };
+        if keys.is_empty() {
+            continue;
         }
+        let mut context = Context::new();
+        context.insert("title", title);
+        context.insert("index", &n.to_string());
+        context.insert("table", &keys);
+        context.insert("numeric", &stats[n].is_binned());
+        context.insert("root", root);
+        let html = templates.render("prefix_table.html.tera", &context)?;
+
+        let file_path = table_path.to_owned() + "/prefixes/col_" + &n.to_string() + ".html";
+        let mut file = fs::File::create(file_path)?;
+        file.write_all(html.as_bytes())?;
+
+        let title_path = prefix_path.to_owned() + "/col_" + &n.to_string() + "/";
+        fs::create_dir(Path::new(&title_path)).context(WriteErr::CantCreateDir {
+            dir_path: title_path.to_owned(),
+        })?;
+        let mut context = Context::new();
+        context.insert("title", title);
+        context.insert("index", &n.to_string());
+        context.insert("root", root);
+        lookup.write_pages(&keys, &title_path, &templates, &context)?;
     }
 
-    let formatter_object = if let Some(f) = formatter {
+    let formatter_object = if let Some(f) = &spec.formatter {
         let mut file_string = "".to_string();
         let mut custom_file =
             File::open(f).context("Unable to open given file for formatting colums")?;
@@ -314,344 +333,884 @@ pub(crate) fn csv_report(
         None
     };
 
-    let pinned_columns = if let Some(col) = pin_until {
-        titles.iter().position(|&r| r == col).context(
-            "Given value for --pin-until did not match any of the columns of your csv file",
-        )? + 1
-    } else {
-        0
-    };
-
     let mut templates = Tera::default();
     templates.add_raw_template("csv_report.js.tera", include_str!("csv_report.js.tera"))?;
     let mut context = Context::new();
     context.insert("titles", &titles);
-    context.insert("num", &is_numeric);
+    context.insert("num", &is_binned);
+    context.insert("links", &is_link);
     context.insert("formatter", &formatter_object);
     context.insert("pinned_columns", &pinned_columns);
-    context.insert("pin", &pin_until.is_some());
+    context.insert("pin", &spec.pin_until.is_some());
 
     let js = templates.render("csv_report.js.tera", &context)?;
 
-    let file_path = output_path.to_owned() + "/js/csv_report.js";
+    let file_path = table_path.to_owned() + "/js/csv_report.js";
     let mut file = fs::File::create(file_path)?;
     file.write_all(js.as_bytes())?;
 
-    if table.is_empty() {
-        let mut templates = Tera::default();
-        templates.add_raw_template("csv_report.html.tera", include_str!("csv_report.html.tera"))?;
-        templates.add_raw_template("data.js.tera", include_str!("data.js.tera"))?;
-        let mut context = Context::new();
-        context.insert("table", &table);
-        context.insert("titles", &titles);
-        context.insert("current_page", &1);
-        context.insert("pages", &1);
-        let local: DateTime<Local> = Local::now();
-        context.insert("time", &local.format("%a %b %e %T %Y").to_string());
-        context.insert("version", &env!("CARGO_PKG_VERSION"));
-        context.insert("is_reasonable", &reasonable_plot);
-
-        let data: Vec<Vec<&str>> = Vec::new();
-
-        context.insert(
-            "data",
-            &json!(compress_to_utf16(&json!(data).to_string())).to_string(),
-        );
-
-        let js = templates.render("data.js.tera", &context)?;
-        let js_file_path = output_path.to_owned() + "/data/index1.js";
-        let mut js_file = fs::File::create(js_file_path)?;
-        js_file.write_all(js.as_bytes())?;
-
-        let html = templates.render("csv_report.html.tera", &context)?;
-        let file_path = output_path.to_owned() + "/indexes/index1.html";
-        let mut file = fs::File::create(file_path)?;
-        file.write_all(html.as_bytes())?;
+    // third pass: pages
+    let pages = if row_count == 0 {
+        1
     } else {
-        for (i, current_table) in table.chunks(rows_per_page).enumerate() {
-            let page = i + 1;
-
-            let mut templates = Tera::default();
-            templates
-                .add_raw_template("csv_report.html.tera", include_str!("csv_report.html.tera"))?;
-            templates.add_raw_template("data.js.tera", include_str!("data.js.tera"))?;
-            let mut context = Context::new();
-            context.insert("table", &current_table);
-            context.insert("titles", &titles);
-            context.insert("current_page", &page);
-            context.insert("pages", &(pages + 1));
-            let local: DateTime<Local> = Local::now();
-            context.insert("time", &local.format("%a %b %e %T %Y").to_string());
-            context.insert("version", &env!("CARGO_PKG_VERSION"));
-            context.insert("is_reasonable", &reasonable_plot);
-
-            let mut data = Vec::new();
-            for row in current_table {
-                let mut r = Vec::new();
-                for title in &titles {
-                    r.push(row.get(*title).unwrap())
-                }
-                data.push(r);
+        (row_count + rows_per_page - 1) / rows_per_page
+    };
+    let mut templates = Tera::default();
+    templates.add_raw_template("csv_report.html.tera", include_str!("csv_report.html.tera"))?;
+    templates.add_raw_template("data.js.tera", include_str!("data.js.tera"))?;
+    let mut context = Context::new();
+    context.insert("titles", &titles);
+    context.insert("pages", &pages);
+    let local: DateTime<Local> = Local::now();
+    context.insert("time", &local.format("%a %b %e %T %Y").to_string());
+    context.insert("version", &env!("CARGO_PKG_VERSION"));
+    context.insert("is_reasonable", &reasonable_plot);
+    context.insert("summaries", &summaries);
+    context.insert("root", root);
+    context.insert("tables", tables);
+    context.insert("table_index", &table_index);
+
+    let mut page_rows = Vec::with_capacity(rows_per_page.min(row_count));
+    for_each_row(spec, sorted_rows.as_ref(), |i, row| {
+        page_rows.push(row.to_vec());
+        if page_rows.len() == rows_per_page || i + 1 == row_count {
+            write_page(
+                table_path,
+                i / rows_per_page + 1,
+                &page_rows,
+                &templates,
+                &context,
+            )?;
+            page_rows.clear();
+        }
+        Ok(())
+    })?;
+    if row_count == 0 {
+        write_page(table_path, 1, &[], &templates, &context)?;
+    }
+    Ok(())
+}
+
+fn reader(spec: &TableSpec) -> Result<csv::Reader<File>> {
+    Ok(csv::ReaderBuilder::new()
+        .delimiter(spec.separator as u8)
+        .from_path(&spec.path)?)
+}
+
+/// Call the given function with the index and values of each row, taken from the given sorted
+/// rows or by reading the table again.
+fn for_each_row<F>(spec: &TableSpec, sorted_rows: Option<&SortedRows>, mut f: F) -> Result<()>
+where
+    F: FnMut(usize, &[String]) -> Result<()>,
+{
+    match sorted_rows {
+        Some(rows) => rows.for_each(f)?,
+        None => {
+            for (i, res) in reader(spec)?.records().enumerate() {
+                let row = res?.iter().map(|v| v.to_owned()).collect_vec();
+                f(i, &row)?;
             }
+        }
+    }
+    Ok(())
+}
 
-            context.insert(
-                "data",
-                &json!(compress_to_utf16(&json!(data).to_string())).to_string(),
-            );
+/// Compare two values of the sort column. Numbers (including NaN) come before all other values
+/// in both sort orders and are compared numerically, other values lexicographically.
+fn compare_values(a: &str, b: &str, ascending: bool) -> Ordering {
+    let ordering = match (f32::from_str(a), f32::from_str(b)) {
+        (Ok(float_a), Ok(float_b)) => float_a.total_cmp(&float_b),
+        (Ok(_), Err(_)) => return Ordering::Less,
+        (Err(_), Ok(_)) => return Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    };
+    if ascending {
+        ordering
+    } else {
+        ordering.reverse()
+    }
+}
 
-            let html = templates.render("csv_report.html.tera", &context)?;
-            let js = templates.render("data.js.tera", &context)?;
+/// Rows of a table sorted by one of its columns. Rows are sorted in chunks of [`SORT_CHUNK`],
+/// which are spilled to temporary files and merged while iterating, unless all rows fit into a
+/// single chunk.
+struct SortedRows {
+    column: usize,
+    ascending: bool,
+    chunk: Vec<Vec<String>>,
+    runs: Vec<File>,
+}
 
-            let file_path = output_path.to_owned() + "/indexes/index" + &page.to_string() + ".html";
-            let mut file = fs::File::create(file_path)?;
-            file.write_all(html.as_bytes())?;
+impl SortedRows {
+    fn new(column: usize, ascending: bool) -> Self {
+        SortedRows {
+            column,
+            ascending,
+            chunk: Vec::new(),
+            runs: Vec::new(),
+        }
+    }
 
-            let js_file_path = output_path.to_owned() + "/data/index" + &page.to_string() + ".js";
-            let mut js_file = fs::File::create(js_file_path)?;
-            js_file.write_all(js.as_bytes())?;
+    fn push(&mut self, row: Vec<String>) -> Result<()> {
+        self.chunk.push(row);
+        if self.chunk.len() == SORT_CHUNK {
+            self.spill()?;
         }
+        Ok(())
     }
+
+    fn compare(&self, a: &[String], b: &[String]) -> Ordering {
+        let (a, b) = (a.get(self.column), b.get(self.column));
+        compare_values(
+            a.map_or("", |v| v.as_str()),
+            b.map_or("", |v| v.as_str()),
+            self.ascending,
+        )
+    }
+
+    fn sort_chunk(&mut self) {
+        let mut chunk = std::mem::take(&mut self.chunk);
+        chunk.sort_by(|a, b| self.compare(a, b));
+        self.chunk = chunk;
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        self.sort_chunk();
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_writer(tempfile::tempfile()?);
+        for row in self.chunk.drain(..) {
+            writer.write_record(&row)?;
+        }
+        let run = writer
+            .into_inner()
+            .map_err(|e| anyhow!("error writing sorted rows: {}", e))?;
+        self.runs.push(run);
+        Ok(())
+    }
+
+    /// Sort the remaining rows, which are kept in memory if nothing was spilled before.
+    fn finish(&mut self) -> Result<()> {
+        if self.runs.is_empty() {
+            self.sort_chunk();
+        } else if !self.chunk.is_empty() {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Call `f` with the index and values of each row in sorted order. Rows with equal values
+    /// keep their original order.
+    fn for_each<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(usize, &[String]) -> Result<()>,
+    {
+        if self.runs.is_empty() {
+            for (i, row) in self.chunk.iter().enumerate() {
+                f(i, row)?;
+            }
+            return Ok(());
+        }
+        let mut runs = self
+            .runs
+            .iter()
+            .map(|run| {
+                let mut run = run.try_clone()?;
+                run.seek(SeekFrom::Start(0))?;
+                Ok(csv::ReaderBuilder::new()
+                    .has_headers(false)
+                    .flexible(true)
+                    .from_reader(BufReader::new(run))
+                    .into_records())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let next_row = |run: &mut csv::StringRecordsIntoIter<BufReader<File>>| {
+            run.next()
+                .transpose()
+                .map(|row| row.map(|row| row.iter().map(|v| v.to_owned()).collect_vec()))
+        };
+        let mut heads = runs
+            .iter_mut()
+            .map(next_row)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut i = 0;
+        // the first of equal rows is taken, such that the merge is stable
+        while let Some(r) = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(r, head)| head.as_ref().map(|row| (r, row)))
+            .min_by(|(_, a), (_, b)| self.compare(a, b))
+            .map(|(r, _)| r)
+        {
+            if let Some(row) = heads[r].take() {
+                f(i, &row)?;
+                i += 1;
+            }
+            heads[r] = next_row(&mut runs[r])?;
+        }
+        Ok(())
+    }
+}
+
+fn write_page(
+    table_path: &str,
+    page: usize,
+    rows: &[Vec<String>],
+    templates: &Tera,
+    context: &Context,
+) -> Result<()> {
+    let mut context = context.clone();
+    context.insert("current_page", &page);
+    context.insert(
+        "data",
+        &json!(compress_to_utf16(&json!(rows).to_string())).to_string(),
+    );
+
+    let html = templates.render("csv_report.html.tera", &context)?;
+    let js = templates.render("data.js.tera", &context)?;
+
+    let file_path = table_path.to_owned() + "/indexes/index" + &page.to_string() + ".html";
+    let mut file = fs::File::create(file_path)?;
+    file.write_all(html.as_bytes())?;
+
+    let js_file_path = table_path.to_owned() + "/data/index" + &page.to_string() + ".js";
+    let mut js_file = fs::File::create(js_file_path)?;
+    js_file.write_all(js.as_bytes())?;
     Ok(())
 }
 
-fn num_plot(table: &[HashMap<String, String>], column: String) -> Vec<BinnedPlotRecord> {
-    let mut values = Vec::new();
-    let mut nan = 0;
-    for row in table {
-        match f32::from_str(row.get(&column).unwrap()) {
-            Ok(val) => values.push(val.to_owned()),
-            _ => nan += 1,
-        }
-    }
-    let min = values.iter().fold(f32::INFINITY, |a, &b| a.min(b));
-    let max = values.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
-    let bins = 20;
-    let step = (max - min) / bins as f32;
-    let mut binned_data = HashMap::new();
-    let mut bin_borders = HashMap::new();
-    for val in values {
-        for i in 0..bins {
-            let lower_bound = min + i as f32 * step;
-            let upper_bound = lower_bound + step;
-            let bin_name = String::from("bin") + &i.to_string();
-            bin_borders.insert(bin_name.to_owned(), (lower_bound, upper_bound));
-            let entry = binned_data.entry(bin_name.to_owned()).or_insert_with(|| 0);
-            if ((i < (bins - 1) && val < upper_bound) || (i < bins && val <= upper_bound))
-                && val >= lower_bound
-            {
-                *entry += 1;
+/// Type and value ranges of a column, determined in a first pass over the table.
+#[derive(Debug, Clone)]
+struct ColumnStats {
+    column_type: ColumnType,
+    numeric: usize,
+    integer: usize,
+    dates: usize,
+    non_numeric: usize,
+    min: f32,
+    max: f32,
+    int_min: i64,
+    int_max: i64,
+    date_min: i64,
+    date_max: i64,
+}
+
+impl Default for ColumnStats {
+    fn default() -> Self {
+        ColumnStats {
+            column_type: ColumnType::Categorical,
+            numeric: 0,
+            integer: 0,
+            dates: 0,
+            non_numeric: 0,
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
+            int_min: i64::MAX,
+            int_max: i64::MIN,
+            date_min: i64::MAX,
+            date_max: i64::MIN,
+        }
+    }
+}
+
+impl ColumnStats {
+    fn observe(&mut self, value: &str) {
+        match f32::from_str(value) {
+            Ok(val) => {
+                self.numeric += 1;
+                self.min = self.min.min(val);
+                self.max = self.max.max(val);
+                if let Ok(int) = i32::from_str(value) {
+                    self.integer += 1;
+                    self.int_min = self.int_min.min(int as i64);
+                    self.int_max = self.int_max.max(int as i64);
+                }
+            }
+            _ => {
+                self.non_numeric += 1;
+                if let Some(date) = parse_date(value) {
+                    self.dates += 1;
+                    self.date_min = self.date_min.min(date);
+                    self.date_max = self.date_max.max(date);
+                }
             }
         }
     }
-    if nan > 0 {
-        bin_borders.insert(
-            String::from("bin") + &bins.to_string(),
-            (f32::NAN, f32::NAN),
-        );
-        binned_data.insert(String::from("bin") + &bins.to_string(), nan);
-    }
-    let mut plot_data = Vec::new();
-    for (name, v) in binned_data {
-        let (lower_bound, upper_bound) = bin_borders.get(&name).unwrap();
-        let plot_record = BinnedPlotRecord {
-            bin_start: *lower_bound,
-            value: v,
-            bin_end: *upper_bound,
-        };
-        plot_data.push(plot_record);
+
+    /// Set the type of the column to the given one or, if there is none, to the type of the
+    /// majority of its values. Links are never detected automatically.
+    fn resolve_type(&mut self, column_type: Option<ColumnType>) {
+        self.column_type = column_type.unwrap_or(if self.integer > self.non_numeric {
+            ColumnType::Integer
+        } else if self.numeric > self.non_numeric {
+            ColumnType::Float
+        } else if 2 * self.dates > self.numeric + self.non_numeric {
+            ColumnType::Date
+        } else {
+            ColumnType::Categorical
+        });
     }
-    plot_data
-}
 
-fn nominal_plot(table: &[HashMap<String, String>], column: String) -> Option<Vec<PlotRecord>> {
-    let values = table
-        .iter()
-        .map(|row| row.get(&column).unwrap().to_owned())
-        .filter(|s| !s.is_empty())
-        .collect_vec();
+    /// Whether values are written as numbers to the XLSX sheet.
+    fn is_numeric(&self) -> bool {
+        matches!(self.column_type, ColumnType::Integer | ColumnType::Float)
+    }
 
-    let mut count_values = HashMap::new();
-    for v in values {
-        let entry = count_values.entry(v.to_owned()).or_insert_with(|| 0);
-        *entry += 1;
+    /// Whether the column is shown as histogram with summary statistics instead of its most
+    /// frequent values.
+    fn is_binned(&self) -> bool {
+        self.is_numeric() || self.column_type == ColumnType::Date
     }
 
-    let mut plot_data = count_values
-        .iter()
-        .map(|(k, v)| PlotRecord {
-            key: k.to_owned(),
-            value: *v,
-        })
-        .collect_vec();
+    fn step(&self) -> f32 {
+        (self.max - self.min) / BINS as f32
+    }
 
-    if plot_data.len() > 10 {
-        let unique_values: HashSet<_> = count_values.iter().map(|(_, v)| v).collect();
-        if unique_values.len() <= 1 {
-            return None;
-        };
-        plot_data.sort_by(|a, b| b.value.cmp(&a.value));
-        plot_data = plot_data.into_iter().take(10).collect();
+    /// Value range of an integer or date column (in days), if it has any valid values.
+    fn discrete_range(&self) -> Option<(i64, i64)> {
+        match self.column_type {
+            ColumnType::Integer if self.integer > 0 => Some((self.int_min, self.int_max)),
+            ColumnType::Date if self.dates > 0 => Some((self.date_min, self.date_max)),
+            _ => None,
+        }
+    }
+
+    /// Width of the bins of an integer or date column, rounded up such that at most `BINS`
+    /// bins cover the whole range.
+    fn discrete_step(min: i64, max: i64) -> i64 {
+        ((max - min + BINS as i64 - 1) / BINS as i64).max(1)
+    }
+
+    /// Value of an integer or date column (in days).
+    fn discrete_value(&self, value: &str) -> Option<i64> {
+        match self.column_type {
+            ColumnType::Integer => i32::from_str(value).ok().map(i64::from),
+            ColumnType::Date => parse_date(value),
+            _ => None,
+        }
+    }
+
+    fn format_discrete(&self, value: i64) -> String {
+        if self.column_type == ColumnType::Date {
+            format_date(value)
+        } else {
+            value.to_string()
+        }
     }
 
-    Some(plot_data)
+    /// Lower and upper borders of the bins of an integer or date column, with the last bin
+    /// ending at the maximum.
+    fn discrete_bins(&self) -> Vec<(i64, i64)> {
+        match self.discrete_range() {
+            Some((min, max)) => {
+                let step = Self::discrete_step(min, max);
+                let bins = ((max - min + step - 1) / step).max(1);
+                (0..bins)
+                    .map(|i| (min + i * step, (min + (i + 1) * step).min(max)))
+                    .collect()
+            }
+            None => vec![],
+        }
+    }
+
+    /// Names of the bins of a binned column, given by their borders.
+    fn bin_borders(&self) -> Vec<String> {
+        match self.column_type {
+            ColumnType::Float if self.numeric > 0 => (0..BINS)
+                .map(|i| {
+                    let lower = self.min + i as f32 * self.step();
+                    lower.to_string() + "-" + &(lower + self.step()).to_string()
+                })
+                .collect(),
+            ColumnType::Integer => self
+                .discrete_bins()
+                .into_iter()
+                .map(|(lower, upper)| lower.to_string() + "-" + &upper.to_string())
+                .collect(),
+            ColumnType::Date => self
+                .discrete_bins()
+                .into_iter()
+                .map(|(lower, upper)| format_date(lower) + " to " + &format_date(upper))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Indices of the bins containing the given value. Values on the border between two bins
+    /// are contained in both.
+    fn bins(&self, value: &str) -> Vec<usize> {
+        match self.column_type {
+            ColumnType::Integer | ColumnType::Date => match self.discrete_value(value) {
+                Some(val) => self
+                    .discrete_bins()
+                    .into_iter()
+                    .positions(|(lower, upper)| val >= lower && val <= upper)
+                    .collect(),
+                None => vec![],
+            },
+            ColumnType::Float if self.numeric > 0 => match f32::from_str(value) {
+                Ok(val) => (0..BINS)
+                    .filter(|i| {
+                        let lower = self.min + *i as f32 * self.step();
+                        val >= lower && val <= lower + self.step()
+                    })
+                    .collect(),
+                _ => vec![],
+            },
+            _ => vec![],
+        }
+    }
+
+    /// Normalized representation of a value of a binned column, as shown in lookup tables.
+    fn normalize(&self, value: &str) -> String {
+        match self.column_type {
+            ColumnType::Integer | ColumnType::Date => self
+                .discrete_value(value)
+                .map_or_else(|| value.to_owned(), |v| self.format_discrete(v)),
+            _ => f32::from_str(value).map_or_else(|_| value.to_owned(), |v| v.to_string()),
+        }
+    }
+
+    /// Numeric value used for summary statistics, with dates given in days.
+    fn summary_value(&self, value: &str) -> Option<f64> {
+        if self.column_type == ColumnType::Date {
+            parse_date(value).map(|d| d as f64)
+        } else {
+            f64::from_str(value).ok().filter(|v| v.is_finite())
+        }
+    }
+
+    fn format_summary_value(&self, value: f64) -> String {
+        if self.column_type == ColumnType::Date {
+            format_date(value.round() as i64)
+        } else if value.fract() == 0.0 && value.abs() < 1e15 {
+            (value as i64).to_string()
+        } else {
+            let formatted = format!("{:.4}", value);
+            formatted
+                .trim_end_matches('0')
+                .trim_end_matches('.')
+                .to_owned()
+        }
+    }
 }
 
-fn make_prefixes(
-    table: Vec<HashMap<String, String>>,
-    titles: Vec<&str>,
-    rows_per_page: usize,
-) -> LookupTable {
-    let mut title_map = HashMap::new();
-    for (i, partial_table) in table.chunks(rows_per_page).enumerate() {
-        let page = i + 1;
-        let prefix_len = 3;
-        for (index, row) in partial_table.iter().enumerate() {
-            for key in &titles {
-                let value = &row[key.to_owned()].trim().to_owned();
-                if !value.is_empty() {
-                    let entry = value.split_whitespace().take(1).collect_vec()[0];
-                    if entry.len() >= prefix_len {
-                        let prefix = entry.chars().take(prefix_len).collect::<String>();
-                        let prefix_map = title_map
-                            .entry(key.to_string())
-                            .or_insert_with(HashMap::new);
-                        let values = prefix_map.entry(prefix).or_insert_with(Vec::new);
-                        values.push((value.to_owned(), page, index));
-                    }
+/// Parse an ISO 8601 date, optionally with a time, into days since the common era.
+fn parse_date(value: &str) -> Option<i64> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S").map(|d| d.date()))
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").map(|d| d.date()))
+        .ok()
+        .map(|date| date.num_days_from_ce() as i64)
+}
+
+fn format_date(days: i64) -> String {
+    NaiveDate::from_num_days_from_ce_opt(days as i32).map_or_else(
+        || days.to_string(),
+        |date| date.format("%Y-%m-%d").to_string(),
+    )
+}
+
+/// Histogram of a binned column with values outside of any bin counted as NaN. The mean and
+/// range of the values are updated with each value, and the values are spilled to a temporary
+/// file for determining exact quantiles.
+#[derive(Debug, Default)]
+struct Histogram {
+    counts: Vec<u32>,
+    nan: u32,
+    n: u64,
+    mean: f64,
+    min: f64,
+    max: f64,
+    values: Option<BufWriter<File>>,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: &str, stats: &ColumnStats) -> Result<()> {
+        match stats.summary_value(value) {
+            Some(val) => {
+                if self.n == 0 {
+                    self.min = val;
+                    self.max = val;
+                } else {
+                    self.min = self.min.min(val);
+                    self.max = self.max.max(val);
+                }
+                self.n += 1;
+                self.mean += (val - self.mean) / self.n as f64;
+                if self.values.is_none() {
+                    self.values = Some(BufWriter::new(tempfile::tempfile()?));
+                }
+                if let Some(values) = self.values.as_mut() {
+                    values.write_all(&val.to_le_bytes())?;
                 }
             }
+            None => self.nan += 1,
+        }
+        // the same bins as in lookup tables and filters
+        let bins = stats.bins(value);
+        if !bins.is_empty() && self.counts.is_empty() {
+            self.counts = vec![0; stats.bin_borders().len()];
+        }
+        for bin in bins {
+            self.counts[bin] += 1;
+        }
+        Ok(())
+    }
+
+    fn plot_data(&self, stats: &ColumnStats) -> serde_json::Value {
+        let mut plot_data = if stats.column_type == ColumnType::Date {
+            stats
+                .discrete_bins()
+                .into_iter()
+                .zip(&self.counts)
+                .map(|((lower, upper), count)| {
+                    json!({
+                        "bin_start": format_date(lower),
+                        "bin_end": format_date(upper),
+                        "value": count
+                    })
+                })
+                .collect_vec()
+        } else if stats.column_type == ColumnType::Integer {
+            stats
+                .discrete_bins()
+                .into_iter()
+                .zip(&self.counts)
+                .map(|((lower, upper), count)| {
+                    json!(BinnedPlotRecord::new(lower as f32, upper as f32, *count))
+                })
+                .collect_vec()
+        } else {
+            let step = stats.step();
+            self.counts
+                .iter()
+                .enumerate()
+                .map(|(i, count)| {
+                    let lower_bound = stats.min + i as f32 * step;
+                    json!(BinnedPlotRecord::new(
+                        lower_bound,
+                        lower_bound + step,
+                        *count
+                    ))
+                })
+                .collect_vec()
+        };
+        if self.nan > 0 {
+            plot_data.push(json!({"bin_start": null, "bin_end": null, "value": self.nan}));
         }
-        // write stuff to output map with page like so: HashMap<column_title, HashMap<prefix, Vec<(value, page, index)>>>
+        json!(plot_data)
+    }
+
+    /// Summary statistics of the column, with quantiles interpolated linearly between values.
+    fn summary(&mut self, stats: &ColumnStats) -> Result<Summary> {
+        let mut summary = Summary {
+            missing: self.nan,
+            ..Default::default()
+        };
+        let mut values = match self.values.take() {
+            Some(values) => values.into_inner()?,
+            None => return Ok(summary),
+        };
+        let positions = [0.25, 0.5, 0.75]
+            .iter()
+            .map(|q| q * (self.n - 1) as f64)
+            .collect_vec();
+        let ranks = positions
+            .iter()
+            .flat_map(|pos| vec![pos.floor() as u64, pos.ceil() as u64])
+            .collect_vec();
+        let ordered = order_statistics(&mut values, self.min, self.max, &ranks)?;
+        let quantile = |i: usize| {
+            let (lower, upper) = (ordered[2 * i], ordered[2 * i + 1]);
+            stats.format_summary_value(lower + (upper - lower) * positions[i].fract())
+        };
+        summary.min = Some(stats.format_summary_value(self.min));
+        summary.q1 = Some(quantile(0));
+        summary.median = Some(quantile(1));
+        summary.q3 = Some(quantile(2));
+        summary.max = Some(stats.format_summary_value(self.max));
+        summary.mean = Some(stats.format_summary_value(self.mean));
+        Ok(summary)
+    }
+}
+
+/// Number of buckets the value range of a rank is divided into in each pass of
+/// [`order_statistics`].
+const SELECTION_BUCKETS: usize = 4096;
+/// Number of values that are sorted in memory to find a rank among them.
+const SELECTION_LIMIT: u64 = 100_000;
+
+/// Range of values containing a rank, with the number of smaller values.
+struct RankRange {
+    rank: u64,
+    below: u64,
+    min: f64,
+    max: f64,
+    /// Number, minimum and maximum of the values in each bucket of the range.
+    buckets: Vec<(u64, f64, f64)>,
+    /// The values of the range, once there are few enough of them.
+    values: Option<Vec<f64>>,
+    result: Option<f64>,
+}
+
+impl RankRange {
+    fn bucket(&self, value: f64) -> usize {
+        // halved to avoid overflows of the difference of large values
+        let relative = (value / 2.0 - self.min / 2.0) / (self.max / 2.0 - self.min / 2.0);
+        ((relative * SELECTION_BUCKETS as f64) as usize).min(SELECTION_BUCKETS - 1)
     }
-    title_map
 }
 
-fn make_bins(
-    table: Vec<HashMap<String, String>>,
-    titles: Vec<&str>,
-    rows_per_page: usize,
-) -> LookupTable {
-    let mut title_map = HashMap::new();
-    for title in titles {
-        let mut values = Vec::new();
-        for row in &table {
-            if let Ok(val) = f32::from_str(row.get(title).unwrap()) {
-                values.push(val.to_owned())
+/// The values of the given (0-based) ranks among the values in the given file, which lie between
+/// `min` and `max`. The range of each rank is narrowed down by counting values in buckets in
+/// passes over the file, until it contains a single value or few enough values to sort them.
+fn order_statistics(file: &mut File, min: f64, max: f64, ranks: &[u64]) -> Result<Vec<f64>> {
+    let mut ranges = ranks
+        .iter()
+        .map(|rank| RankRange {
+            rank: *rank,
+            below: 0,
+            min,
+            max,
+            buckets: Vec::new(),
+            values: None,
+            result: if min == max { Some(min) } else { None },
+        })
+        .collect_vec();
+    while ranges.iter().any(|range| range.result.is_none()) {
+        for range in ranges.iter_mut().filter(|range| range.result.is_none()) {
+            if range.values.is_none() {
+                range.buckets = vec![(0, f64::INFINITY, f64::NEG_INFINITY); SELECTION_BUCKETS];
             }
         }
-        let min = values.iter().fold(f32::INFINITY, |a, &b| a.min(b));
-        let max = values.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
-        let bins = 20;
-        let step = (max - min) / bins as f32;
-        let mut bin_data = HashMap::new();
-        for val in values {
-            for i in 0..bins {
-                let lower_bound = min + i as f32 * step;
-                let upper_bound = lower_bound + step;
-                let bin_name = lower_bound.to_string() + "-" + &upper_bound.to_string();
-                let entry = bin_data
-                    .entry(bin_name.to_owned())
-                    .or_insert_with(HashSet::new);
-                if ((i < (bins - 1) && val < upper_bound) || (i < bins && val <= upper_bound))
-                    && val >= lower_bound
-                {
-                    entry.insert(val.to_string());
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(&mut *file);
+        let mut buffer = [0; 8];
+        loop {
+            match reader.read_exact(&mut buffer) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            let value = f64::from_le_bytes(buffer);
+            for range in ranges.iter_mut().filter(|range| range.result.is_none()) {
+                if value < range.min || value > range.max {
+                    continue;
+                }
+                if let Some(values) = range.values.as_mut() {
+                    values.push(value);
+                } else {
+                    let bucket = range.bucket(value);
+                    let (count, min, max) = &mut range.buckets[bucket];
+                    *count += 1;
+                    *min = min.min(value);
+                    *max = max.max(value);
                 }
             }
         }
-
-        let mut value_on_page = HashMap::new();
-        for (i, partial_table) in table.chunks(rows_per_page).enumerate() {
-            let page = i + 1;
-            for (index, row) in partial_table.iter().enumerate() {
-                if let Ok(val) = f32::from_str(row.get(title).unwrap()) {
-                    let entry = value_on_page
-                        .entry(val.to_string())
-                        .or_insert_with(HashSet::new);
-                    entry.insert((page, index));
-                }
+        for range in ranges.iter_mut().filter(|range| range.result.is_none()) {
+            if let Some(mut values) = range.values.take() {
+                values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                range.result = Some(values[(range.rank - range.below) as usize]);
+                continue;
             }
-            // write stuff to output map with page like so: HashMap<column_title, HashMap<bin, Vec<(value, page, index)>>>
-        }
-        let mut bin_map = HashMap::new();
-        for (bin, values) in bin_data {
-            for v in values {
-                let entry = bin_map.entry(bin.to_string()).or_insert_with(Vec::new);
-                for (page, index) in value_on_page.get(&v).unwrap() {
-                    entry.push((v.to_string(), *page, *index));
+            // buckets hold contiguous value ranges, such that the range of the rank can be
+            // narrowed down to the bucket containing it
+            let mut below = range.below;
+            for (count, min, max) in range.buckets.drain(..) {
+                if below + count > range.rank {
+                    // ranges that cannot be narrowed down any further are sorted regardless of
+                    // their size
+                    let narrowed = min > range.min || max < range.max;
+                    range.below = below;
+                    range.min = min;
+                    range.max = max;
+                    if min == max {
+                        range.result = Some(min);
+                    } else if count <= SELECTION_LIMIT || !narrowed {
+                        range.values = Some(Vec::with_capacity(count as usize));
+                    }
+                    break;
                 }
+                below += count;
             }
         }
-        title_map.insert(title.to_string(), bin_map);
     }
+    Ok(ranges
+        .into_iter()
+        .map(|range| range.result.unwrap())
+        .collect())
+}
 
-    title_map
+/// Summary statistics of a binned column, shown next to its histogram.
+#[derive(Serialize, Debug, Clone, Default)]
+struct Summary {
+    missing: u32,
+    min: Option<String>,
+    q1: Option<String>,
+    median: Option<String>,
+    q3: Option<String>,
+    max: Option<String>,
+    mean: Option<String>,
 }
 
-fn make_bins_for_integers(
-    table: Vec<HashMap<String, String>>,
-    titles: Vec<&str>,
-    rows_per_page: usize,
-) -> LookupTable {
-    let mut title_map = HashMap::new();
-    for title in titles {
-        let mut values = Vec::new();
-        for row in &table {
-            if let Ok(val) = i32::from_str(row.get(title).unwrap()) {
-                values.push(val.to_owned())
-            }
+/// Counts of the values of a nominal column. Once there are many distinct values, only the most
+/// frequent ones are kept and the others are merely part of the total.
+#[derive(Debug, Clone, Default)]
+struct NominalCounts {
+    counts: HashMap<String, u32>,
+    total: u32,
+    pruned: bool,
+}
+
+impl NominalCounts {
+    fn observe(&mut self, value: &str) {
+        self.total += 1;
+        *self.counts.entry(value.to_owned()).or_insert(0) += 1;
+        if self.counts.len() >= 2 * NOMINAL_CAPACITY {
+            let kept: HashMap<_, _> = self
+                .counts
+                .drain()
+                .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)))
+                .take(NOMINAL_CAPACITY)
+                .collect();
+            self.counts = kept;
+            self.pruned = true;
         }
-        let min = *values.iter().min().unwrap();
-        let max = *values.iter().max().unwrap();
-        let bins = 20;
-        let step = if max - min <= 20 {
-            1
-        } else {
-            (max - min) / bins
-        };
-        let mut bin_data = HashMap::new();
-        for val in values {
-            for i in 0..bins {
-                let lower_bound = min + i * step;
-                let upper_bound = if i == bins { max } else { lower_bound + step };
-                let bin_name = lower_bound.to_string() + "-" + &upper_bound.to_string();
-                let entry = bin_data
-                    .entry(bin_name.to_owned())
-                    .or_insert_with(HashSet::new);
-                if ((i < (bins - 1) && val < upper_bound) || (i < bins && val <= upper_bound))
-                    && val >= lower_bound
-                {
-                    entry.insert(val.to_string());
-                }
+    }
+
+    /// The most frequent values, with all others summarized as `other`. Columns of more distinct
+    /// values than shown that all occur equally often are not plotted.
+    fn plot(&self) -> Option<Vec<PlotRecord>> {
+        let mut plot_data = self
+            .counts
+            .iter()
+            .map(|(k, v)| PlotRecord {
+                key: k.to_owned(),
+                value: *v,
+            })
+            .collect_vec();
+
+        if plot_data.len() > NOMINAL_PLOT_VALUES || self.pruned {
+            let unique_values: HashSet<_> = self.counts.values().collect();
+            if unique_values.len() <= 1 {
+                return None;
+            };
+            plot_data.sort_by(|a, b| b.value.cmp(&a.value).then_with(|| a.key.cmp(&b.key)));
+            plot_data.truncate(NOMINAL_PLOT_VALUES);
+            let shown: u32 = plot_data.iter().map(|record| record.value).sum();
+            if self.total > shown {
+                plot_data.push(PlotRecord {
+                    key: "other".to_owned(),
+                    value: self.total - shown,
+                });
             }
         }
 
-        let mut value_on_page = HashMap::new();
-        for (i, partial_table) in table.chunks(rows_per_page).enumerate() {
-            let page = i + 1;
-            for (index, row) in partial_table.iter().enumerate() {
-                if let Ok(val) = i32::from_str(row.get(title).unwrap()) {
-                    let entry = value_on_page
-                        .entry(val.to_string())
-                        .or_insert_with(HashSet::new);
-                    entry.insert((page, index));
-                }
+        Some(plot_data)
+    }
+}
+
+/// Entries of the lookup tables of a column (value, page and index of its row), spilled to a
+/// temporary file while the table is streamed.
+struct LookupSpill {
+    writer: csv::Writer<File>,
+    /// Prefixes or bins of the entries.
+    keys: BTreeSet<String>,
+}
+
+impl LookupSpill {
+    fn new() -> Result<Self> {
+        Ok(LookupSpill {
+            writer: csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(tempfile::tempfile()?),
+            keys: BTreeSet::new(),
+        })
+    }
+
+    /// Add an entry to the lookup table of the given prefix or bin.
+    fn push(&mut self, key: &str, value: &str, page: usize, index: usize) -> Result<()> {
+        if !self.keys.contains(key) {
+            self.keys.insert(key.to_owned());
+        }
+        self.writer.serialize((key, value, page, index))?;
+        Ok(())
+    }
+
+    /// Write one lookup page per given prefix or bin. The spilled entries are read once per
+    /// [`LOOKUP_PAGES_PER_PASS`] pages and written in chunks, such that only few of them are in
+    /// memory at a time.
+    fn write_pages(
+        self,
+        keys: &[String],
+        dir: &str,
+        templates: &Tera,
+        context: &Context,
+    ) -> Result<()> {
+        let mut spill = self
+            .writer
+            .into_inner()
+            .map_err(|e| anyhow!("error writing lookup table entries: {}", e))?;
+        for batch in keys.chunks(LOOKUP_PAGES_PER_PASS) {
+            let mut pages = HashMap::new();
+            for key in batch {
+                let mut file = BufWriter::new(fs::File::create(dir.to_owned() + key + ".html")?);
+                file.write_all(
+                    templates
+                        .render("lookup_table_start.html.tera", context)?
+                        .as_bytes(),
+                )?;
+                pages.insert(key.as_str(), (file, Vec::new()));
             }
-            // write stuff to output map with page like so: HashMap<column_title, HashMap<bin, Vec<(value, page, index)>>>
-        }
-        let mut bin_map = HashMap::new();
-        for (bin, values) in bin_data {
-            for v in values {
-                let entry = bin_map.entry(bin.to_string()).or_insert_with(Vec::new);
-                for (page, index) in value_on_page.get(&v).unwrap() {
-                    entry.push((v.to_string(), *page, *index));
+            spill.seek(SeekFrom::Start(0))?;
+            let mut reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .from_reader(&mut spill);
+            for result in reader.deserialize() {
+                let (key, value, page, index): (String, String, usize, usize) = result?;
+                if let Some((file, values)) = pages.get_mut(key.as_str()) {
+                    values.push((value, page, index));
+                    if values.len() == LOOKUP_CHUNK {
+                        write_lookup_rows(file, values, templates, context)?;
+                    }
                 }
             }
+            for (file, values) in pages.values_mut() {
+                write_lookup_rows(file, values, templates, context)?;
+                file.write_all(
+                    templates
+                        .render("lookup_table_end.html.tera", context)?
+                        .as_bytes(),
+                )?;
+                file.flush()?;
+            }
         }
-        title_map.insert(title.to_string(), bin_map);
+        Ok(())
     }
+}
 
-    title_map
+/// Write the given rows of a lookup page and clear them.
+fn write_lookup_rows<W: Write>(
+    file: &mut W,
+    values: &mut Vec<(String, usize, usize)>,
+    templates: &Tera,
+    context: &Context,
+) -> Result<()> {
+    if values.is_empty() {
+        return Ok(());
+    }
+    let mut context = context.clone();
+    context.insert("values", &*values);
+    file.write_all(
+        templates
+            .render("lookup_rows.html.tera", &context)?
+            .as_bytes(),
+    )?;
+    values.clear();
+    Ok(())
 }
 
 #[derive(new, Serialize, Debug, Clone)]
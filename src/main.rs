@@ -50,6 +50,14 @@ fn main() -> Result<()> {
             exclude_flags,
             min_mapq,
         )?,
+        VcfNormalize {
+            reference,
+            split_multiallelics,
+            decompose_mnps,
+            site_window,
+        } => {
+            bcf::normalize::normalize(reference, split_multiallelics, decompose_mnps, site_window)?
+        }
         VcfToTxt {
             info,
             format,
@@ -63,22 +71,126 @@ fn main() -> Result<()> {
             vcf,
             max_dist,
             max_len_diff,
-        } => bcf::match_variants::match_variants(vcf, max_dist, max_len_diff)?,
-        VcfBaf {} => bcf::baf::calculate_baf()?,
-        VcfFixIupacAlleles {} => bcf::fix_iupac_alleles::fix_iupac_alleles()?,
+            genotypes,
+        } => bcf::match_variants::match_variants(vcf, max_dist, max_len_diff, genotypes)?,
+        VcfBaf { caller, indels } => bcf::baf::calculate_baf(caller, indels)?,
+        VcfBafSegment {
+            tumor,
+            normal,
+            output_format,
+            switch_prob,
+            baf_sd,
+            ratio_sd,
+            min_het_baf,
+        } => bcf::baf_segment::segment(
+            &tumor,
+            normal.as_deref(),
+            &output_format,
+            bcf::baf_segment::SegmentationParams {
+                switch_prob,
+                baf_sd,
+                ratio_sd,
+                min_het_baf,
+            },
+        )?,
+        VcfFixIupacAlleles {
+            reference,
+            expand_alts,
+            annotate,
+        } => bcf::fix_iupac_alleles::fix_iupac_alleles(reference, expand_alts, annotate)?,
         VcfAnnotateDgidb {
             vcf,
+            output,
+            output_type,
             api_path,
             field,
+            annotation_field,
+            gene_subfield,
+            gene_tag,
             datasources,
             genes_per_request,
-        } => bcf::annotate_dgidb::annotate_dgidb(
+            cache_dir,
+            retries,
+            retry_delay,
+            max_requests_per_second,
+            interactions,
+            gene_column,
+            drug_column,
+            interaction_type_column,
+            source_column,
+        } => {
+            let interaction_source = match interactions {
+                Some(path) => bcf::annotate_dgidb::InteractionSource::Local {
+                    path,
+                    columns: bcf::annotate_dgidb::InteractionColumns {
+                        gene: gene_column,
+                        drug: drug_column,
+                        interaction_types: interaction_type_column,
+                        source: source_column,
+                    },
+                },
+                None => {
+                    bcf::annotate_dgidb::InteractionSource::Api(bcf::annotate_dgidb::ApiOptions {
+                        api_path,
+                        genes_per_request,
+                        cache_dir,
+                        retries,
+                        retry_delay: std::time::Duration::from_millis(retry_delay),
+                        max_requests_per_second,
+                    })
+                }
+            };
+            let gene_field = match gene_tag {
+                Some(tag) => bcf::genes::GeneField::Tag(tag),
+                None => bcf::genes::GeneField::Annotation {
+                    field: annotation_field,
+                    subfield: gene_subfield,
+                },
+            };
+            bcf::annotate_dgidb::annotate_dgidb(
+                vcf,
+                interaction_source,
+                &gene_field,
+                &*field,
+                datasources.as_deref(),
+                output,
+                &output_type,
+            )?
+        }
+        VcfAnnotateGenes {
             vcf,
-            api_path,
-            &*field,
-            datasources.as_deref(),
-            genes_per_request,
-        )?,
+            table,
+            key_column,
+            fields,
+            output,
+            output_type,
+            annotation_field,
+            gene_subfield,
+            gene_tag,
+        } => {
+            let gene_field = match gene_tag {
+                Some(tag) => bcf::genes::GeneField::Tag(tag),
+                None => bcf::genes::GeneField::Annotation {
+                    field: annotation_field,
+                    subfield: gene_subfield,
+                },
+            };
+            bcf::annotate_genes::annotate_genes(
+                vcf,
+                table,
+                &key_column,
+                &fields,
+                &gene_field,
+                output,
+                &output_type,
+            )?
+        }
+        VcfAnnotateIntervals {
+            vcf,
+            bed,
+            output,
+            output_type,
+        } => bcf::annotate_intervals::annotate_intervals(vcf, &bed, output, &output_type)?,
         CsvReport {
             csv_path,
             rows_per_page,
@@ -87,38 +199,95 @@ fn main() -> Result<()> {
             separator,
             formatter,
             pin_until,
+            column_types,
+            config,
+            single_file,
             output_path,
         } => {
-            if !Path::new(&output_path).exists() {
-                fs::create_dir_all(Path::new(&output_path))?;
-            }
-            bcf::report::embed_js(&output_path, false, None, vec![])?;
-            bcf::report::embed_css(&output_path, false)?;
-            bcf::report::embed_html(&output_path)?;
-
-            let order = match sort_order.as_str() {
-                "ascending" => Some(true),
-                "descending" => Some(false),
-                _ => None,
+            let tables = if csv_path.ends_with(".yaml") || csv_path.ends_with(".yml") {
+                csv::config::ReportConfig::from_path(&csv_path)?.tables
+            } else {
+                let config = match config {
+                    Some(path) => csv::config::TableConfig::from_path(path)?,
+                    None => csv::config::TableConfig::default(),
+                };
+                vec![csv::config::TableSpec {
+                    name: "Report".to_owned(),
+                    path: csv_path,
+                    separator,
+                    rows_per_page: rows_per_page as usize,
+                    sort_column,
+                    sort_order: sort_order.parse()?,
+                    formatter,
+                    pin_until,
+                    // types given on the command line take precedence over the config file
+                    column_types: config
+                        .column_types
+                        .into_iter()
+                        .chain(column_types)
+                        .collect(),
+                    conditional_formats: config.conditional_formats,
+                }]
             };
 
-            csv::report::csv_report(
-                &csv_path,
-                &output_path,
-                rows_per_page as usize,
-                separator,
-                sort_column.as_deref(),
-                order,
-                formatter.as_deref(),
-                pin_until.as_deref(),
-            )?
+            let (_single_file_dir, report_path) =
+                bcf::report::report_dir(single_file, &output_path)?;
+            bcf::report::embed_js(&report_path, false, None, vec![])?;
+            bcf::report::embed_css(&report_path, false)?;
+            bcf::report::embed_html(&report_path)?;
+
+            csv::report::csv_report(&tables, &report_path)?;
+            if single_file {
+                bcf::report::embed_single_file(&report_path, &output_path)?;
+            }
         }
         PlotBam {
             bam_path,
             reference,
             region,
+            vcfs,
+            gff,
+            regions,
+            flank,
+            output_dir,
             max_read_depth,
-        } => bam::plot::plot_bam::plot_bam(&bam_path, reference, &region, max_read_depth)?,
+            group_by,
+            sort_by,
+            color_by,
+            soft_clip_bases,
+            format,
+        } => {
+            let layout = bcf::report::table_report::static_reader::ReadLayout {
+                group_by,
+                sort_by,
+                color_by,
+                soft_clip_bases,
+            };
+            match (regions, output_dir) {
+                (Some(regions), Some(output_dir)) => bam::plot::plot_bam::plot_bam_regions(
+                    &bam_path,
+                    reference,
+                    &vcfs,
+                    gff.as_deref(),
+                    &regions,
+                    flank,
+                    max_read_depth,
+                    &layout,
+                    format,
+                    &output_dir,
+                )?,
+                _ => bam::plot::plot_bam::plot_bam(
+                    &bam_path,
+                    reference,
+                    &vcfs,
+                    gff.as_deref(),
+                    &region.context("please specify either --region or --regions")?,
+                    max_read_depth,
+                    &layout,
+                    format,
+                )?,
+            }
+        }
         VcfReport {
             fasta,
             vcfs,
@@ -133,16 +302,17 @@ fn main() -> Result<()> {
             tsv,
             threads,
             annotation_field,
+            annotation_schema,
+            regions,
+            genes,
+            gff,
+            single_file,
             output_path,
         } => {
             let mut sample_calls = HashMap::new();
             let mut bam_paths = HashMap::new();
-            if !Path::new(&output_path).exists() {
-                fs::create_dir(Path::new(&output_path)).context(format!(
-                    "Couldn't create output directory at {}. Please make sure the path exists.",
-                    output_path
-                ))?;
-            }
+            let (_single_file_dir, report_path) =
+                bcf::report::report_dir(single_file, &output_path)?;
             let js_files_vec = custom_js_files
                 .clone()
                 .map_or_else(Vec::new, |values| values.into_iter().collect());
@@ -163,14 +333,14 @@ fn main() -> Result<()> {
                 vec![]
             };
             bcf::report::embed_js(
-                &output_path,
+                &report_path,
                 true,
                 custom_js_template.as_deref(),
                 js_files_vec,
             )?;
-            bcf::report::embed_css(&output_path, true)?;
-            bcf::report::embed_html(&output_path)?;
-            let detail_path = output_path.to_owned() + "/details/";
+            bcf::report::embed_css(&report_path, true)?;
+            bcf::report::embed_html(&report_path)?;
+            let detail_path = report_path.to_owned() + "/details/";
             fs::create_dir(Path::new(&detail_path))?;
             for vcf in vcfs {
                 let v: Vec<_> = vcf.split('=').collect();
@@ -186,6 +356,8 @@ fn main() -> Result<()> {
                 rec.push((c[1].to_owned(), b[1].to_owned()))
             }
 
+            let selection = bcf::report::selection::RecordSelection::new(&regions, &genes)?;
+
             rayon::ThreadPoolBuilder::new()
                 .num_threads(threads)
                 .build_global()?;
@@ -197,13 +369,16 @@ fn main() -> Result<()> {
                     bam_paths
                         .get(sample)
                         .unwrap_or_else(|| panic!("No bam provided for sample {}", sample)),
-                    &output_path,
+                    &report_path,
                     sample,
                     infos.clone(),
                     formats.clone(),
                     max_read_depth,
                     js_file_names.clone(),
                     &annotation_field,
+                    annotation_schema.as_ref(),
+                    &selection,
+                    gff.as_deref(),
                 )
                 .unwrap_or_else(|e| {
                     panic!("Failed building table report for sample {}. {}", sample, e)
@@ -212,12 +387,17 @@ fn main() -> Result<()> {
 
             bcf::report::oncoprint::oncoprint(
                 &sample_calls,
-                &output_path,
+                &report_path,
                 cells,
                 tsv.as_deref(),
                 plot_info,
                 &annotation_field,
-            )?
+                annotation_schema.as_ref(),
+                &selection,
+            )?;
+            if single_file {
+                bcf::report::embed_single_file(&report_path, &output_path)?;
+            }
         }
         VcfSplit { input, output } => bcf::split::split(input, output.as_ref())?,
         CollapseReadsToFragments { cmd } => match cmd {
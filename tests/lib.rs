@@ -1,8 +1,13 @@
 use bio::io::fastq;
+use calamine::{open_workbook, Data, Reader, Xlsx};
 use rust_htslib::bam;
 use rust_htslib::bam::Read;
 use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::Path;
 use std::process::Command;
+use std::thread;
 
 /// Compare an output file to the expected output and delete the output file.
 fn test_output(result: &str, expected: &str) {
@@ -128,6 +133,15 @@ fn vcf_match_same() {
     );
 }
 
+#[test]
+fn vcf_match_genotypes() {
+    assert!(Command::new("bash")
+            .arg("-c")
+            .arg("target/debug/rbt vcf-match --genotypes tests/test-gt-truth.vcf < tests/test-gt-query.vcf | bcftools query -f '[%GTMATCH,]\\n' > tests/gt-matching.txt")
+            .spawn().unwrap().wait().unwrap().success());
+    test_output("tests/gt-matching.txt", "tests/expected/gt-matching.txt");
+}
+
 #[test]
 fn vcf_fix_iupac_alleles() {
     assert!(Command::new("bash")
@@ -143,6 +157,35 @@ fn vcf_fix_iupac_alleles() {
     test_output("tests/iupac-fixed.bcf", "tests/expected/iupac-fixed.bcf");
 }
 
+#[test]
+fn vcf_fix_iupac_alleles_resolve() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt vcf-fix-iupac-alleles --reference tests/ref.fa --expand-alts --annotate < tests/test-iupac-resolve.vcf | bcftools query -f '%POS\\t%REF\\t%ALT\\t%INFO/IUPAC_FIXED\\t%INFO/AF[\\t%GT:%AD:%PL]\\n' > tests/iupac-resolved.txt")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    test_output(
+        "tests/iupac-resolved.txt",
+        "tests/expected/iupac-resolved.txt",
+    );
+}
+
+#[test]
+fn vcf_normalize() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt vcf-normalize --split-multiallelics --decompose-mnps tests/ref.fa < tests/test-normalize.vcf | bcftools query -f '%POS\\t%REF\\t%ALT\\t%INFO/AF[\\t%GT:%AD]\\n' > tests/normalized.txt")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    test_output("tests/normalized.txt", "tests/expected/normalized.txt");
+}
+
 #[test]
 fn vcf_baf() {
     assert!(Command::new("bash")
@@ -156,6 +199,65 @@ fn vcf_baf() {
     test_output("tests/baf.bcf", "tests/expected/baf.bcf");
 }
 
+#[test]
+fn vcf_baf_allele_depths() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt vcf-baf --indels < tests/test-gatk-baf.vcf | bcftools query -f '[%BAF;]\\n' > tests/baf-gatk.txt")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    test_output("tests/baf-gatk.txt", "tests/expected/baf-gatk.txt");
+}
+
+#[test]
+fn vcf_baf_strelka_indels() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt vcf-baf --caller strelka --indels < tests/test-strelka-indels-baf.vcf | bcftools query -f '[%BAF;]\\n' > tests/baf-strelka-indels.txt")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    test_output(
+        "tests/baf-strelka-indels.txt",
+        "tests/expected/baf-strelka-indels.txt",
+    );
+}
+
+#[test]
+fn vcf_baf_segment() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt vcf-baf-segment --tumor tumor --normal normal < tests/test-baf-segment.vcf > tests/baf-segments.bed")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    test_output("tests/baf-segments.bed", "tests/expected/baf-segments.bed");
+}
+
+#[test]
+fn vcf_baf_segment_zero_depth() {
+    // the BAF of a site without coverage is NaN and must not affect the segmentation
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt vcf-baf-segment --tumor tumor --normal normal < tests/test-baf-segment-zero-depth.vcf > tests/baf-segments-zero-depth.bed")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    test_output(
+        "tests/baf-segments-zero-depth.bed",
+        "tests/expected/baf-segments.bed",
+    );
+}
+
 #[test]
 fn test_vcf_report() {
     assert!(
@@ -219,6 +321,323 @@ fn test_vcf_report() {
     fs::remove_dir_all("tests/test-vcf-report").unwrap();
 }
 
+#[test]
+fn test_vcf_report_snpeff() {
+    assert!(
+        Command::new("bash")
+            .arg("-c")
+            .arg("target/debug/rbt vcf-report tests/ref.fa -v a=tests/report-test-snpeff.vcf -b a:tumor=tests/test-report.bam --annotation-schema snpeff -- tests/test-vcf-report-snpeff")
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap()
+            .success()
+    );
+    // SnpEff has no HGVSg field, so it is derived from the record
+    let details =
+        fs::read_to_string("tests/test-vcf-report-snpeff/details/a/chr1_g_15C_T.html").unwrap();
+    assert!(details.contains("p.Gly12Asp"));
+    assert!(Path::new("tests/test-vcf-report-snpeff/genes/KRAS1.html").exists());
+    fs::remove_dir_all("tests/test-vcf-report-snpeff").unwrap();
+}
+
+#[test]
+fn test_vcf_report_annotation_schema_file() {
+    assert!(
+        Command::new("bash")
+            .arg("-c")
+            .arg("target/debug/rbt vcf-report tests/ref.fa -v a=tests/report-test-snpeff.vcf -b a:tumor=tests/test-report.bam --annotation-schema tests/annotation-schema-snpeff.yaml -- tests/test-vcf-report-schema-file")
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap()
+            .success()
+    );
+    // the YAML keys are snake_case; optional fields missing from ANN are left empty
+    let details =
+        fs::read_to_string("tests/test-vcf-report-schema-file/details/a/chr1_g_15C_T.html")
+            .unwrap();
+    assert!(details.contains("p.Gly12Asp"));
+    fs::remove_dir_all("tests/test-vcf-report-schema-file").unwrap();
+}
+
+#[test]
+fn test_vcf_report_regions() {
+    assert!(
+        Command::new("bash")
+            .arg("-c")
+            .arg("target/debug/rbt vcf-report tests/ref.fa -v a=tests/report-test-genes.vcf -b a:tumor=tests/test-report.bam --regions chr1:1-30 -- tests/test-vcf-report-regions")
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap()
+            .success()
+    );
+    // the NRAS variant at chr1:60 is outside of the region
+    assert!(Path::new("tests/test-vcf-report-regions/genes/KRAS1.html").exists());
+    assert!(!Path::new("tests/test-vcf-report-regions/genes/NRAS1.html").exists());
+    fs::remove_dir_all("tests/test-vcf-report-regions").unwrap();
+}
+
+#[test]
+fn test_vcf_report_indexed_regions() {
+    assert!(
+        Command::new("bash")
+            .arg("-c")
+            .arg("target/debug/rbt vcf-report tests/ref.fa -v a=tests/report-test-regions.vcf.gz -b a:tumor=tests/test-report.bam --regions chr1:18-22 chr1:40-50 chr1:45-55 chr2:1-10 -- tests/test-vcf-report-indexed-regions")
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap()
+            .success()
+    );
+    assert!(!Path::new("tests/test-vcf-report-indexed-regions/genes/KRAS1.html").exists());
+    assert!(!Path::new("tests/test-vcf-report-indexed-regions/genes/NRAS1.html").exists());
+    // the HRAS deletion at chr1:20-45 spans two of the merged regions but is reported once
+    let plots =
+        fs::read_to_string("tests/test-vcf-report-indexed-regions/genes/plots/HRAS1.js").unwrap();
+    let spec = plots
+        .lines()
+        .find_map(|line| line.strip_prefix("var spec = "))
+        .unwrap()
+        .trim_end_matches(';');
+    let compressed: String = serde_json::from_str(spec).unwrap();
+    let codes: Vec<u32> = compressed.chars().map(|c| u32::from(c) - 32).collect();
+    let packed: serde_json::Value =
+        serde_json::from_str(&lz_str::decompress(&codes, 15).unwrap()).unwrap();
+    let spec: serde_json::Value = jsonm::unpacker::Unpacker::new().unpack(&packed).unwrap();
+    assert_eq!(spec["datasets"]["main"].as_array().unwrap().len(), 1);
+    fs::remove_dir_all("tests/test-vcf-report-indexed-regions").unwrap();
+}
+
+#[test]
+fn test_vcf_report_genes() {
+    assert!(
+        Command::new("bash")
+            .arg("-c")
+            .arg("target/debug/rbt vcf-report tests/ref.fa -v a=tests/report-test-genes.vcf -b a:tumor=tests/test-report.bam --genes KRAS -- tests/test-vcf-report-genes")
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap()
+            .success()
+    );
+    assert!(Path::new("tests/test-vcf-report-genes/genes/KRAS1.html").exists());
+    assert!(!Path::new("tests/test-vcf-report-genes/genes/NRAS1.html").exists());
+    fs::remove_dir_all("tests/test-vcf-report-genes").unwrap();
+}
+
+#[test]
+fn test_plot_bam_svg() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt plot-bam -b tests/test-report.bam -r tests/ref.fa -g chr1:1-102 --format svg > tests/test-plot-bam.svg")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+
+    let svg = fs::read_to_string("tests/test-plot-bam.svg").unwrap();
+    assert!(svg.contains("<svg"));
+    assert!(svg.trim_end().ends_with("</svg>"));
+    assert!(svg.contains("test-report.bam"));
+    // coverage track computed from the single read, which is aligned from position 5 on and
+    // has a T instead of the reference G at position 101
+    assert!(svg.contains("<title>11 Match: 1 of 1</title>"));
+    assert!(svg.contains("<title>101 T: 1 of 1</title>"));
+    assert!(!svg.contains("<title>101 Match:"));
+    assert!(!svg.contains("<title>3 Match:"));
+    fs::remove_file("tests/test-plot-bam.svg").unwrap();
+}
+
+/// Vertical position and colour of the first aligned segment of a read in a plot-bam SVG.
+fn read_row_style<'a>(svg: &'a str, read: &str) -> (&'a str, &'a str) {
+    let line = svg
+        .lines()
+        .find(|line| line.contains(&format!("<title>Match in {} (", read)))
+        .unwrap();
+    let attribute = |name: &str| {
+        let start = line.find(&format!(" {}=\"", name)).unwrap() + name.len() + 3;
+        &line[start..start + line[start..].find('"').unwrap()]
+    };
+    (attribute("y1"), attribute("stroke"))
+}
+
+#[test]
+fn test_plot_bam_read_layout() {
+    // reads a1 and a2 with HP 1, b1 and b2 with HP 2 and n1 without HP, each in its own row
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt plot-bam -b tests/test-plot-bam-layout.bam -r tests/ref.fa -g chr1:1-60 --group-by HP --sort-by insert-size --color-by tag:RX --format svg > tests/test-plot-bam-layout.svg")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+
+    let svg = fs::read_to_string("tests/test-plot-bam-layout.svg").unwrap();
+    // descending absolute insert size within each group, reads without HP last
+    assert_eq!(read_row_style(&svg, "a2"), ("5.00", "#f28e2b"));
+    assert_eq!(read_row_style(&svg, "a1"), ("15.00", "#4e79a7"));
+    assert_eq!(read_row_style(&svg, "b1"), ("25.00", "#4e79a7"));
+    assert_eq!(read_row_style(&svg, "b2"), ("35.00", "#f28e2b"));
+    assert_eq!(read_row_style(&svg, "n1"), ("45.00", "#f28e2b"));
+    // group labels at the first row of each group
+    assert!(svg.contains(r##"<text x="12.00" y="9.00" font-size="9" fill="#444444">HP:1</text>"##));
+    assert!(svg.contains(r##"<text x="12.00" y="29.00" font-size="9" fill="#444444">HP:2</text>"##));
+    fs::remove_file("tests/test-plot-bam-layout.svg").unwrap();
+
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt plot-bam -b tests/test-plot-bam-layout.bam -r tests/ref.fa -g chr1:1-60 --color-by mapq --format svg > tests/test-plot-bam-mapq.svg")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+
+    let svg = fs::read_to_string("tests/test-plot-bam-mapq.svg").unwrap();
+    assert_eq!(read_row_style(&svg, "a2").1, "#910000");
+    assert_eq!(read_row_style(&svg, "b1").1, "#ff7500");
+    assert_eq!(read_row_style(&svg, "a1").1, "#bbbbbb");
+    fs::remove_file("tests/test-plot-bam-mapq.svg").unwrap();
+
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt plot-bam -b tests/test-report.bam -r tests/ref.fa -g chr1:1-100 --soft-clip-bases --format svg > tests/test-plot-bam-clips.svg")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+
+    let svg = fs::read_to_string("tests/test-plot-bam-clips.svg").unwrap();
+    assert!(svg.contains(">SoftClip</text>"));
+    fs::remove_file("tests/test-plot-bam-clips.svg").unwrap();
+
+    // a split read with its supplementary alignment and pairs in FF, RR and RF orientation or
+    // with the mate on another contig
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt plot-bam -b tests/test-plot-bam-sv.bam -r tests/ref.fa -g chr1:0-123 --format svg > tests/test-plot-bam-sv.svg")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+
+    let svg = fs::read_to_string("tests/test-plot-bam-sv.svg").unwrap();
+    assert!(svg.contains("<title>Split read split1: chr1,71,+,20M,60,0</title>"));
+    assert!(
+        svg.contains("Match in split1 (MAPQ 60, CIGAR 20M), split segments: chr1,71,+,20M,60,0")
+    );
+    assert!(svg.contains("Match in ff1 (MAPQ 60, CIGAR 20M), discordant pair: FF"));
+    assert!(svg.contains("Match in rr1 (MAPQ 60, CIGAR 20M), discordant pair: RR"));
+    assert!(svg.contains("Match in rf1 (MAPQ 60, CIGAR 20M), discordant pair: RF"));
+    assert!(svg.contains("Match in tr1 (MAPQ 60, CIGAR 20M), discordant pair: mate on chr2"));
+    fs::remove_file("tests/test-plot-bam-sv.svg").unwrap();
+
+    assert!(!Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt plot-bam -b tests/test-report.bam -r tests/ref.fa -g chr1:1-100 --color-by quality > /dev/null 2>&1")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+}
+
+#[test]
+fn test_plot_bam_vcf() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt plot-bam -b tests/test-report.bam -r tests/ref.fa -g chr1:1-100 --vcf tests/report-test.vcf --format svg > tests/test-plot-bam-vcf.svg")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+
+    let svg = fs::read_to_string("tests/test-plot-bam-vcf.svg").unwrap();
+    assert!(svg.contains("<title>Variant C&gt;T</title>"));
+    fs::remove_file("tests/test-plot-bam-vcf.svg").unwrap();
+}
+
+#[test]
+fn test_plot_bam_gff() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt plot-bam -b tests/test-report.bam -r tests/ref.fa -g chr1:1-100 --gff tests/plot-bam-features.gff3 --format svg > tests/test-plot-bam-gff.svg")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+
+    let svg = fs::read_to_string("tests/test-plot-bam-gff.svg").unwrap();
+    assert!(svg.contains("<title>Exon GENE1-201 (-)</title>"));
+    assert!(svg.contains("<title>CDS GENE1-201 (-)</title>"));
+    // exons shared by several transcripts and percent-encoded names
+    assert!(svg.contains("<title>Exon GENE1,202 (-)</title>"));
+    // the gene is drawn by its transcript only
+    assert!(!svg.contains("<title>Transcript GENE1 (-)</title>"));
+    fs::remove_file("tests/test-plot-bam-gff.svg").unwrap();
+}
+
+#[test]
+fn test_plot_bam_regions() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt plot-bam -b tests/test-report.bam -r tests/ref.fa --regions tests/report-test.vcf --format svg --output-dir tests/test-plot-bam-vcf")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt plot-bam -b tests/test-report.bam -r tests/ref.fa --regions tests/plot-bam-regions.bed --flank 10 --output-dir tests/test-plot-bam-bed")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+
+    let index = fs::read_to_string("tests/test-plot-bam-vcf/index.html").unwrap();
+    assert!(index.contains("regions/1.svg"));
+    assert!(Path::new("tests/test-plot-bam-vcf/regions/1.svg").exists());
+    let index = fs::read_to_string("tests/test-plot-bam-bed/index.html").unwrap();
+    assert!(index.contains("first"));
+    assert!(index.contains("chr1:0-50"));
+    assert!(Path::new("tests/test-plot-bam-bed/regions/2.html").exists());
+
+    fs::remove_dir_all("tests/test-plot-bam-vcf").unwrap();
+    fs::remove_dir_all("tests/test-plot-bam-bed").unwrap();
+}
+
+#[test]
+fn test_plot_bam_regions_missing_contig() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt plot-bam -b tests/test-report.bam -r tests/ref.fa --regions tests/plot-bam-regions-missing.bed --output-dir tests/test-plot-bam-missing")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+
+    // the region on chr2, which is not part of the reference, is skipped instead of failing the batch
+    let index = fs::read_to_string("tests/test-plot-bam-missing/index.html").unwrap();
+    assert!(index.contains("regions/1.html"));
+    assert!(index.contains("Skipped regions"));
+    assert!(index.contains("contig chr2 not found in reference"));
+    assert!(Path::new("tests/test-plot-bam-missing/regions/1.html").exists());
+    assert!(!Path::new("tests/test-plot-bam-missing/regions/2.html").exists());
+
+    fs::remove_dir_all("tests/test-plot-bam-missing").unwrap();
+}
+
 #[test]
 fn test_csv_report() {
     assert!(Command::new("bash")
@@ -237,6 +656,151 @@ fn test_csv_report() {
     fs::remove_dir_all("tests/test-csv-report").unwrap();
 }
 
+#[test]
+fn test_csv_report_xlsx_config() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt csv-report tests/test_report.csv --pin-until sample --config tests/csv-report-config.yaml -- tests/test-csv-report-config")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+
+    assert!(
+        fs::metadata("tests/test-csv-report-config/report.xlsx")
+            .unwrap()
+            .len()
+            > 0
+    );
+    let result = "tests/test-csv-report-config/data/index1.js";
+    let expected = "tests/expected/csv-report/data/index1.js";
+    test_output(&result, &expected);
+
+    let mut workbook: Xlsx<_> = open_workbook("tests/test-csv-report-config/report.xlsx").unwrap();
+    let sheet = workbook.worksheet_range_at(0).unwrap().unwrap();
+    assert_eq!(
+        sheet.get_value((0, 2)),
+        Some(&Data::String("Viruses".to_owned()))
+    );
+    // Viruses is configured as float and written as numbers
+    assert_eq!(sheet.get_value((1, 2)), Some(&Data::Float(86.35)));
+    assert_eq!(sheet.get_value((2, 2)), Some(&Data::Float(9.61)));
+    assert_eq!(
+        sheet.get_value((1, 8)),
+        Some(&Data::String("B.1.1.7 17/17".to_owned()))
+    );
+    fs::remove_dir_all("tests/test-csv-report-config").unwrap();
+
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt csv-report tests/test-csv-report-links.csv --column-type website=link -- tests/test-csv-report-links")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    let mut workbook: Xlsx<_> = open_workbook("tests/test-csv-report-links/report.xlsx").unwrap();
+    let sheet = workbook.worksheet_range_at(0).unwrap().unwrap();
+    assert_eq!(sheet.get_value((1, 1)), Some(&Data::Float(86.35)));
+    assert_eq!(
+        sheet.get_value((1, 2)),
+        Some(&Data::String("https://example.org/samples/1".to_owned()))
+    );
+    assert_eq!(
+        sheet.get_value((2, 2)),
+        Some(&Data::String("https://example.org/samples/2".to_owned()))
+    );
+    fs::remove_dir_all("tests/test-csv-report-links").unwrap();
+}
+
+#[test]
+fn test_csv_report_single_file() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt csv-report tests/test_report.csv --single-file -- tests/test-csv-report.html")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+
+    let html = fs::read_to_string("tests/test-csv-report.html").unwrap();
+    // pages, scripts and the XLSX export are bundled into the file
+    assert!(html.contains("\"indexes/index1.html\":"));
+    assert!(html.contains("\"js/vega.min.js\":"));
+    assert!(html.contains("\"report.xlsx\":"));
+    assert!(html.contains("var LZString"));
+    fs::remove_file("tests/test-csv-report.html").unwrap();
+
+    // missing parent directories of the output file are created
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt csv-report tests/test_report.csv --single-file -- tests/test-csv-report-single/nested/report.html")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+
+    let html = fs::read_to_string("tests/test-csv-report-single/nested/report.html").unwrap();
+    assert!(html.contains("\"indexes/index1.html\":"));
+    fs::remove_dir_all("tests/test-csv-report-single").unwrap();
+}
+
+#[test]
+fn test_csv_report_column_types() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt csv-report tests/test_report.csv --column-type sample=categorical -- tests/test-csv-report-types")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+
+    // the sample column is integer but shown with its values instead of a histogram
+    let sample_plot = fs::read_to_string("tests/test-csv-report-types/plots/plot_0.js").unwrap();
+    assert!(sample_plot.contains("\"field\": \"key\""));
+    let index = fs::read_to_string("tests/test-csv-report-types/indexes/index1.html").unwrap();
+    assert!(index.contains("median"));
+    let result = "tests/test-csv-report-types/data/index1.js";
+    let expected = "tests/expected/csv-report/data/index1.js";
+    test_output(&result, &expected);
+
+    fs::remove_dir_all("tests/test-csv-report-types").unwrap();
+}
+
+#[test]
+fn test_csv_report_multiple_tables() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg(
+            "target/debug/rbt csv-report tests/csv-report-tables.yaml tests/test-csv-report-tables"
+        )
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+
+    assert!(Path::new("tests/test-csv-report-tables/report.xlsx").exists());
+    assert!(Path::new("tests/test-csv-report-tables/tables/2/indexes/index3.html").exists());
+    let result = "tests/test-csv-report-tables/tables/1/data/index1.js";
+    let expected = "tests/expected/csv-report/data/index1.js";
+    test_output(&result, &expected);
+
+    // numbers (including NaN) come first in descending order, followed by text in descending order
+    let mut workbook: Xlsx<_> = open_workbook("tests/test-csv-report-tables/report.xlsx").unwrap();
+    let sheet = workbook.worksheet_range_at(2).unwrap().unwrap();
+    let ids = (1..=6)
+        .map(|row| sheet.get_value((row, 0)).unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec!["r2", "r4", "r1", "r6", "r5", "r3"]);
+
+    fs::remove_dir_all("tests/test-csv-report-tables").unwrap();
+}
+
 #[test]
 fn test_collapse_reads_to_fragments_two_cluster() {
     assert!(
@@ -337,6 +901,187 @@ fn test_vcf_annotate_dgidb() {
     assert_eq!(String::from_utf8(exec_test.stdout).unwrap().trim(), "65");
 }
 
+#[test]
+fn test_vcf_annotate_dgidb_local() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt vcf-annotate-dgidb --interactions tests/dgidb-interactions.tsv tests/annotate_dgidb_test.vcf | bcftools query -f '%INFO/dgiDB_drugs\\n' > tests/dgidb-local.txt")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    test_output("tests/dgidb-local.txt", "tests/expected/dgidb-local.txt");
+}
+
+#[test]
+fn test_vcf_annotate_dgidb_local_datasources() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt vcf-annotate-dgidb tests/annotate_dgidb_test.vcf --interactions tests/dgidb-interactions.tsv --datasources DrugBank | bcftools query -f '%INFO/dgiDB_drugs\\n' > tests/dgidb-local-drugbank.txt")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    test_output(
+        "tests/dgidb-local-drugbank.txt",
+        "tests/expected/dgidb-local-drugbank.txt",
+    );
+}
+
+#[test]
+fn test_vcf_annotate_dgidb_vep() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt vcf-annotate-dgidb --annotation-field CSQ --interactions tests/dgidb-interactions.tsv tests/annotate_dgidb_vep.vcf | bcftools query -f '%POS\\t%INFO/dgiDB_drugs\\n' > tests/dgidb-vep.txt")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    test_output("tests/dgidb-vep.txt", "tests/expected/dgidb-vep.txt");
+}
+
+#[test]
+fn test_vcf_annotate_dgidb_gene_tag() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt vcf-annotate-dgidb --gene-tag GENE --interactions tests/dgidb-interactions.tsv tests/annotate_dgidb_vep.vcf | bcftools query -f '%POS\\t%INFO/dgiDB_drugs\\n' > tests/dgidb-gene-tag.txt")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    test_output(
+        "tests/dgidb-gene-tag.txt",
+        "tests/expected/dgidb-gene-tag.txt",
+    );
+}
+
+#[test]
+fn test_vcf_annotate_genes_tag() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt vcf-annotate-genes tests/annotate_dgidb_vep.vcf --gene-tag GENE --table tests/gene-table.tsv --fields tier=CGC_TIER:Integer role=CGC_ROLE hallmark=CGC_HALLMARK:Flag | bcftools query -f '%POS\\t%INFO/CGC_TIER\\t%INFO/CGC_ROLE\\t%INFO/CGC_HALLMARK\\n' > tests/genes-tag.txt")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    test_output("tests/genes-tag.txt", "tests/expected/genes-tag.txt");
+}
+
+#[test]
+fn test_vcf_annotate_genes_ensembl() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt vcf-annotate-genes --annotation-field CSQ --gene-subfield Gene --table tests/gene-table.tsv --key-column gene_id --fields tier=TIER:Integer role < tests/annotate_dgidb_vep.vcf | bcftools query -f '%POS\\t%INFO/TIER\\t%INFO/role\\n' > tests/genes-ensembl.txt")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    test_output(
+        "tests/genes-ensembl.txt",
+        "tests/expected/genes-ensembl.txt",
+    );
+}
+
+#[test]
+fn test_vcf_annotate_intervals() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt vcf-annotate-intervals tests/test-intervals.vcf --bed tests/intervals-targets.bed=TARGET tests/intervals-exons.bed=EXON:score tests/intervals-blacklist.bed=BLACKLIST:flag | bcftools query -f '%CHROM\\t%POS\\t%INFO/TARGET\\t%INFO/EXON\\t%INFO/BLACKLIST\\n' > tests/intervals.txt")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    test_output("tests/intervals.txt", "tests/expected/intervals.txt");
+}
+
+#[test]
+fn test_vcf_annotate_dgidb_stdin() {
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("target/debug/rbt vcf-annotate-dgidb --annotation-field CSQ --interactions tests/dgidb-interactions.tsv --output-type v --output tests/dgidb-stdin.vcf < tests/annotate_dgidb_vep.vcf")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    assert!(fs::read_to_string("tests/dgidb-stdin.vcf")
+        .unwrap()
+        .starts_with("##fileformat=VCF"));
+    assert!(Command::new("bash")
+        .arg("-c")
+        .arg("bcftools query -f '%POS\\t%INFO/dgiDB_drugs\\n' tests/dgidb-stdin.vcf > tests/dgidb-stdin.txt")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .success());
+    fs::remove_file("tests/dgidb-stdin.vcf").unwrap();
+    test_output("tests/dgidb-stdin.txt", "tests/expected/dgidb-vep.txt");
+}
+
+#[test]
+fn test_vcf_annotate_dgidb_api_cache() {
+    // mock DGIdb server failing the first request and answering the retry
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        for (status, body) in &[
+            ("500 Internal Server Error", ""),
+            (
+                "200 OK",
+                r#"{"matchedTerms":[{"geneName":"CHRNA2","interactions":[{"drugName":"VARENICLINE","interactionTypes":["agonist"]}]}]}"#,
+            ),
+        ] {
+            let (mut stream, _) = listener.accept().unwrap();
+            // skip the request up to the empty line ending its header
+            let mut line = String::new();
+            let mut request = BufReader::new(&stream);
+            while request.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+        }
+    });
+    let cache_dir = tempfile::tempdir().unwrap();
+    let annotate = |retries: u32, output: &str| {
+        Command::new("bash")
+            .arg("-c")
+            .arg(format!(
+                "target/debug/rbt vcf-annotate-dgidb tests/annotate_dgidb_test.vcf --api-path 'http://127.0.0.1:{}/interactions.json?genes=' --cache-dir {} --retries {} --retry-delay 10 | bcftools query -f '%INFO/dgiDB_drugs\\n' > {}",
+                port,
+                cache_dir.path().display(),
+                retries,
+                output
+            ))
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap()
+            .success()
+    };
+    assert!(annotate(1, "tests/dgidb-api.txt"));
+    server.join().unwrap();
+    test_output("tests/dgidb-api.txt", "tests/expected/dgidb-api.txt");
+
+    // the server is gone, hence the rerun has to be answered from the cache
+    assert!(annotate(0, "tests/dgidb-api-cached.txt"));
+    test_output("tests/dgidb-api-cached.txt", "tests/expected/dgidb-api.txt");
+}
+
 #[test]
 fn test_stats_fasta_file() {
     assert!(Command::new("bash")